@@ -0,0 +1,521 @@
+// secko-admin: looks inside a node's snapshot and commit log without starting a server. nothing is written to unless the command
+// is one that repairs (truncate-log, rebuild), so the rest are fine to run against a node that's up
+use std::{
+    collections::{HashMap, BTreeSet},
+    fs::metadata,
+    io,
+    net::SocketAddrV4,
+    path::Path,
+    process::exit,
+    sync::Arc,
+};
+
+use clap::{arg, Arg, ArgMatches, Command, command, value_parser};
+
+use secko_messages::{Key, ReplicaId, Crdt, name_key};
+use secko_server::{Entry, Named, content_matches, u64_to_socketaddr, socketaddr_to_u64, map::LockFreeMap, commit_log::{self, LogRecord}, snapshot::{self, SnapshotManifest, SnapshotKind}, storage::{Storage, MemoryStorage}, replicas::{ReplicaMap, Listed, list_tails, restore}};
+
+// what the server names them when it isn't told otherwise, for finding them in a data directory
+const SNAPSHOT_NAME: &str = "secko_snapshot";
+const COMMIT_LOG_NAME: &str = "commit_log.txt";
+
+fn main() {
+    if let Err(e) = run(&cli().get_matches()) {
+        eprintln!("{}", e);
+        exit(1);
+    }
+}
+
+fn cli() -> Command {
+    command!()
+        .subcommand_required(true)
+        .subcommand(node_args(Command::new("stats").about("Counts what a node holds")))
+        .subcommand(node_args(Command::new("list").about("Lists the entries and named keys, only the ones matching PATTERN if it's given"))
+            .arg(Arg::new("pattern"))) // in the key, name or value
+        .subcommand(node_args(Command::new("verify").about("Checks every value against its content key")))
+        .subcommand(Command::new("check-log").about("Reads every record of a commit log, without changing it")
+            .arg(Arg::new("log").required(true)))
+        .subcommand(Command::new("truncate-log").about("Cuts a torn record off the end of a commit log, as the server would on startup")
+            .arg(Arg::new("log").required(true))
+            .arg(arg!(node: -n <NODEADDR>).value_parser(value_parser!(String)))) // whose log, for converting one from an older version
+        .subcommand(Command::new("rebuild").about("Writes a full snapshot of everything in a commit log, for a node whose snapshots are lost")
+            .arg(Arg::new("log").required(true))
+            .arg(Arg::new("snapshot").required(true))
+            .arg(arg!(node: -n <NODEADDR>).value_parser(value_parser!(String))))
+        .subcommand(Command::new("diff").about("Compares what two nodes' data directories hold")
+            .arg(Arg::new("a").required(true))
+            .arg(Arg::new("b").required(true))
+            .arg(arg!(snapshot: -s <SNAPSHOTNAME>).value_parser(value_parser!(String))) // file names within each, as given to the servers
+            .arg(arg!(commit: -c <COMMITLOGNAME>).value_parser(value_parser!(String))))
+}
+
+fn run(matches: &ArgMatches) -> Result<(), String> {
+    match matches.subcommand() {
+        Some(("stats", m)) => stats(m),
+        Some(("list", m)) => list(m),
+        Some(("verify", m)) => verify(m),
+        Some(("check-log", m)) => check_log(m),
+        Some(("truncate-log", m)) => truncate_log(m),
+        Some(("rebuild", m)) => rebuild(m),
+        Some(("diff", m)) => diff(m),
+        _ => unreachable!(), // a subcommand is required
+    }
+}
+
+// the files that make up a node, as given to its server
+fn node_args(command: Command) -> Command {
+    command
+        .arg(arg!(snapshot: -s <SNAPSHOTFILE>).value_parser(value_parser!(String)))
+        .arg(arg!(commit: -c <COMMITLOGFILE>).value_parser(value_parser!(String))) // without, only what's in the snapshot
+        .arg(arg!(node: -n <NODEADDR>).value_parser(value_parser!(String))) // its antientropy address, which tells our list from the others
+}
+
+// the replica id of the -n address, 0 without one. it's only what the log is replayed as
+fn node_id(matches: &ArgMatches) -> Result<ReplicaId, String> {
+    match matches.get_one::<String>("node") {
+        Some(c) => match c.trim().parse::<SocketAddrV4>() {
+            Ok(addr) => Ok(socketaddr_to_u64(&addr)),
+            Err(_) => Err(format!("{} isn't an address, give -n as <ip>:<port>.", c)),
+        },
+        None => Ok(0),
+    }
+}
+
+// a node's data as its server would start from it: the snapshot, and the log replayed on top
+struct Node {
+    map: MemoryStorage,
+    named: LockFreeMap<Key, Named>,
+    replica_map: Option<ReplicaMap>, // None for a snapshot from before the manifest, which didn't have the lists
+    manifest: Option<SnapshotManifest>, // None for a snapshot from before the manifest, or none at all
+    position: u64, // of the first record replayed
+    replayed: usize,
+    notes: Vec<String>, // anything the server would have said about it on the way
+}
+
+fn open_node(snapshot_path: &str, log_path: Option<&str>, local_replica_id: ReplicaId) -> Result<Node, String> {
+    let map = MemoryStorage::new();
+    let mut notes: Vec<String> = Vec::new();
+
+    let (named, lists, manifest, position) = match snapshot::load(snapshot_path, &map)? {
+        Some(loaded) => {
+            if loaded.deltas > 0 {
+                notes.push(format!("Layered {} snapshot deltas on the last full snapshot.", loaded.deltas));
+            }
+            for reason in loaded.skipped {
+                notes.push(format!("Fell back to an older snapshot: {}", reason));
+            }
+            (loaded.named, Some(loaded.lists), Some(loaded.manifest), loaded.position)
+        },
+        None if metadata(snapshot_path).is_ok() => {
+            snapshot::load_unmanaged(snapshot_path, &map, local_replica_id)?;
+            notes.push("The snapshot was written by an older version.".to_string());
+            (LockFreeMap::new(), None, None, 0)
+        },
+        None => {
+            notes.push(format!("There's no snapshot at {}.", snapshot_path));
+            (LockFreeMap::new(), Some(HashMap::new()), None, 0)
+        },
+    };
+
+    let records: Vec<LogRecord> = match log_path {
+        Some(log_path) => {
+            let checked = commit_log::check(log_path)?;
+            let base = checked.segments[0].base;
+            if position < base {
+                return Err(format!("The commit log starts at record {}, but replay has to start at {}.", base, position));
+            }
+            checked.records.into_iter().skip((position - base) as usize).collect()
+        },
+        None => Vec::new(),
+    };
+
+    let replica_map = lists.map(|lists| restore(lists, local_replica_id, &records));
+    let replayed = records.len();
+    for skipped in commit_log::replay(records, &map, &named, local_replica_id)? {
+        notes.push(format!("Skipped logged state of {}", skipped));
+    }
+
+    Ok(Node { map, named, replica_map, manifest, position, replayed, notes })
+}
+
+// everything an iterator over the store gives, or the first thing it couldn't read
+fn entries(iter: impl Iterator<Item = io::Result<(Key, Arc<Entry>)>>) -> Result<Vec<(Key, Arc<Entry>)>, String> {
+    iter.collect::<io::Result<Vec<_>>>().map_err(|e| format!("Couldn't read the store: {}", e))
+}
+
+fn open_node_args(matches: &ArgMatches) -> Result<Node, String> {
+    let snapshot_path = match matches.get_one::<String>("snapshot") {
+        Some(s) => s.trim(),
+        None => return Err("Which snapshot? Give it with -s.".to_string()),
+    };
+    let node = open_node(snapshot_path, matches.get_one::<String>("commit").map(|c| c.trim()), node_id(matches)?)?;
+    for note in node.notes.iter() {
+        println!("{}", note);
+    }
+    Ok(node)
+}
+
+// in the same words the server uses for an entry
+fn state(entry: &Entry) -> &'static str {
+    if entry.is_deleted() {
+        "deleted"
+    } else if entry.is_expired() {
+        "expired"
+    } else {
+        "live"
+    }
+}
+
+fn stats(matches: &ArgMatches) -> Result<(), String> {
+    let node = open_node_args(matches)?;
+
+    if let Some(manifest) = node.manifest.as_ref() {
+        println!("manifest: {} snapshots, newest first", manifest.snapshots.len());
+        for info in manifest.snapshots.iter() {
+            let kind = match info.kind {
+                SnapshotKind::Full => "full",
+                SnapshotKind::Delta => "delta",
+            };
+            println!("  {} {} version {}, {} bytes, crc {:08x}, covers {} records", info.file, kind, info.version, info.len, info.crc, info.position);
+        }
+    }
+    println!("replayed {} commit log records from {}", node.replayed, node.position);
+
+    let mut counts: HashMap<&'static str, usize> = HashMap::new();
+    let mut bytes: u64 = 0;
+    let mut largest: Option<(Key, usize)> = None;
+    for (key, entry) in entries(node.map.iter())? {
+        *counts.entry(state(&entry)).or_default() += 1;
+        bytes += entry.value.len() as u64;
+        if largest.is_none_or(|(_, len)| entry.value.len() > len) {
+            largest = Some((key, entry.value.len()));
+        }
+    }
+    println!("entries: {} ({} live, {} deleted, {} expired), {} bytes of values", node.map.len(), counts.get("live").unwrap_or(&0), counts.get("deleted").unwrap_or(&0), counts.get("expired").unwrap_or(&0), bytes);
+    if let Some((key, len)) = largest {
+        println!("largest value: {} at {} bytes", key, len);
+    }
+
+    let mut kinds: HashMap<&'static str, usize> = HashMap::new();
+    for entry in node.named.iter() {
+        *kinds.entry(entry.val().state.lock().unwrap().kind()).or_default() += 1;
+    }
+    println!("named keys: {}", node.named.iter().count());
+    for (kind, count) in kinds {
+        println!("  {}: {}", kind, count);
+    }
+
+    match node.replica_map.as_ref() {
+        Some(replica_map) => {
+            println!("replica lists:");
+            for replica in replica_map.iter() {
+                println!("  {}: {} keys", u64_to_socketaddr(*replica.key()), replica.val().lock().unwrap().len());
+            }
+        },
+        None => println!("replica lists: not in a snapshot this old"),
+    }
+    Ok(())
+}
+
+fn list(matches: &ArgMatches) -> Result<(), String> {
+    let node = open_node_args(matches)?;
+    let pattern = matches.get_one::<String>("pattern");
+
+    // the pattern is looked for in the bytes of the value, which are usually text
+    let matching = |text: &str, value: &[u8]| match pattern {
+        Some(p) => text.contains(p.as_str()) || value.windows(p.len().max(1)).any(|w| w == p.as_bytes()),
+        None => true,
+    };
+
+    let mut found: usize = 0;
+    for (key, entry) in entries(node.map.iter_arrived())? {
+        if matching(&key.to_string(), &entry.value) {
+            println!("{} {} {} bytes {}", key, state(&entry), entry.value.len(), entry.meta);
+            found += 1;
+        }
+    }
+    for entry in node.named.iter() {
+        let crdt = entry.val().state.lock().unwrap();
+        if matching(&entry.val().name, &[]) {
+            println!("{} {} {}", entry.val().name, crdt.kind(), crdt.value());
+            found += 1;
+        }
+    }
+    println!("{} found", found);
+    Ok(())
+}
+
+fn verify(matches: &ArgMatches) -> Result<(), String> {
+    let node = open_node_args(matches)?;
+
+    let mut checked: usize = 0;
+    let mut bad: usize = 0;
+    for (key, entry) in entries(node.map.iter())? {
+        // a tombstone has no value, an expired one may be anything at all once it's collected
+        if let Some(value) = entry.live() {
+            checked += 1;
+            if !content_matches(&node.map, &key, value).map_err(|e| e.to_string())? {
+                println!("{}: the value doesn't match its key", key);
+                bad += 1;
+            }
+        }
+    }
+    for entry in node.named.iter() {
+        checked += 1;
+        if *entry.key() != name_key(&entry.val().name) {
+            println!("{}: held under {}, not its name's key", entry.val().name, entry.key());
+            bad += 1;
+        }
+    }
+
+    match bad {
+        0 => {
+            println!("{} checked, all match", checked);
+            Ok(())
+        },
+        _ => Err(format!("{} checked, {} don't match.", checked, bad)),
+    }
+}
+
+fn check_log(matches: &ArgMatches) -> Result<(), String> {
+    let log_path = matches.get_one::<String>("log").unwrap().trim();
+    let checked = commit_log::check(log_path)?;
+
+    let mut problems: usize = 0;
+    let last = checked.segments.len() - 1;
+    for (i, segment) in checked.segments.iter().enumerate() {
+        println!("{}: records {} to {}", segment.path, segment.base, segment.base + segment.records);
+        if segment.torn > 0 {
+            // a server that crashed mid write leaves this, and cuts it off when it starts again. anywhere else it's damage
+            match i == last && !segment.damaged {
+                true => println!("  {} bytes of torn record at the end, truncate-log cuts them off", segment.torn),
+                false if segment.damaged => {
+                    println!("  damaged part way through, whole records follow the {} bytes after the last one read", segment.torn);
+                    problems += 1;
+                },
+                false => {
+                    println!("  damaged part way through, {} bytes after the last whole record", segment.torn);
+                    problems += 1;
+                },
+            }
+        }
+        if let Some(next) = checked.segments.get(i + 1) {
+            if next.base != segment.base + segment.records {
+                println!("  the next segment starts at record {}, not {}", next.base, segment.base + segment.records);
+                problems += 1;
+            }
+        }
+    }
+
+    let (mut puts, mut deletes, mut named) = (0, 0, 0);
+    for record in checked.records.iter() {
+        match record {
+            LogRecord::Put { .. } => puts += 1,
+            LogRecord::Delete { .. } => deletes += 1,
+            LogRecord::Named { .. } => named += 1,
+        }
+    }
+    println!("{} records: {} puts, {} deletes, {} named", checked.records.len(), puts, deletes, named);
+
+    match problems {
+        0 => Ok(()),
+        _ => Err(format!("The commit log has {} problems.", problems)),
+    }
+}
+
+fn truncate_log(matches: &ArgMatches) -> Result<(), String> {
+    let log_path = matches.get_one::<String>("log").unwrap().trim();
+
+    // from the first segment there is, so recover doesn't need to know where a snapshot leaves off. a log from an older version
+    // has no segments yet, recover works it out from the log itself
+    let first = match commit_log::segments(log_path) {
+        Ok(segments) => segments.first().map(|(base, _)| *base),
+        Err(e) => return Err(format!("Couldn't list the commit log's segments: {}", e)),
+    };
+    if first.is_none() && metadata(log_path).is_err() {
+        return Err(format!("There's no commit log at {}.", log_path));
+    }
+
+    let recovered = commit_log::recover(log_path, node_id(matches)?, first)?;
+    if recovered.converted {
+        println!("Converted a commit log written by an older version.");
+    }
+    match recovered.truncated {
+        0 => println!("Nothing to cut, the log ends on a whole record."),
+        n => println!("Cut a torn record ({} bytes) off the end of the commit log.", n),
+    }
+    println!("The log holds records {} to {}.", recovered.start, recovered.end);
+    Ok(())
+}
+
+fn rebuild(matches: &ArgMatches) -> Result<(), String> {
+    let log_path = matches.get_one::<String>("log").unwrap().trim();
+    let snapshot_path = matches.get_one::<String>("snapshot").unwrap().trim();
+    let local_replica_id = node_id(matches)?;
+
+    // written over, the snapshot files it names would be left behind and the log they let go of couldn't be replaced
+    if metadata(snapshot::manifest_path(snapshot_path)).is_ok() {
+        return Err(format!("{} already has a manifest, move it and its snapshots out of the way first.", snapshot_path));
+    }
+
+    let checked = commit_log::check(log_path)?;
+    if checked.segments[0].base != 0 {
+        return Err(format!("The commit log starts at record {}, the ones before it went with compaction.", checked.segments[0].base));
+    }
+    if checked.segments.iter().any(|s| s.torn > 0) {
+        println!("Only whole records go in, the torn ones are left out.");
+    }
+
+    // every record put a key on the end of our list, from nothing. the others' lists come back through antientropy
+    let map = MemoryStorage::new();
+    let named: LockFreeMap<Key, Named> = LockFreeMap::new();
+    let position = checked.records.len() as u64;
+    let replica_map = restore(HashMap::new(), local_replica_id, &checked.records);
+    for skipped in commit_log::replay(checked.records, &map, &named, local_replica_id)? {
+        println!("Skipped logged state of {}", skipped);
+    }
+
+    let listed = Listed { position, len: position as usize };
+    let (tails, _) = list_tails(&replica_map, local_replica_id, listed, position, None);
+    let mut manifest = SnapshotManifest::default();
+    if let Err(e) = snapshot::write_full(snapshot_path, &mut manifest, position, &map, &named, &tails) {
+        return Err(format!("Couldn't write the snapshot: {}", e));
+    }
+    println!("Wrote a full snapshot of {} entries and {} named keys, covering {} records.", map.len(), named.iter().count(), position);
+    Ok(())
+}
+
+fn diff(matches: &ArgMatches) -> Result<(), String> {
+    let snapshot_name = matches.get_one::<String>("snapshot").map_or(SNAPSHOT_NAME, |s| s.trim());
+    let log_name = matches.get_one::<String>("commit").map_or(COMMIT_LOG_NAME, |c| c.trim());
+
+    let open = |dir: &str| -> Result<Node, String> {
+        let dir = Path::new(dir.trim());
+        let log_path = dir.join(log_name).to_string_lossy().to_string();
+        // a node that's never written anything has no log yet
+        let has_log = commit_log::segments(&log_path).is_ok_and(|s| !s.is_empty()) || metadata(&log_path).is_ok();
+        open_node(&dir.join(snapshot_name).to_string_lossy(), if has_log { Some(&log_path) } else { None }, 0)
+    };
+    let a = open(matches.get_one::<String>("a").unwrap())?;
+    let b = open(matches.get_one::<String>("b").unwrap())?;
+
+    // expired values are as good as gone, every replica collects them at the same time
+    let held = |node: &Node| -> Result<HashMap<Key, Arc<Entry>>, String> {
+        Ok(entries(node.map.iter())?.into_iter().filter(|(_, entry)| entry.is_deleted() || !entry.is_expired()).collect())
+    };
+    let (held_a, held_b) = (held(&a)?, held(&b)?);
+    let keys: BTreeSet<Key> = held_a.keys().chain(held_b.keys()).copied().collect();
+
+    let (mut only_a, mut only_b, mut differ) = (0, 0, 0);
+    for key in keys {
+        match (held_a.get(&key), held_b.get(&key)) {
+            (Some(entry), None) => {
+                println!("< {} {}", key, state(entry));
+                only_a += 1;
+            },
+            (None, Some(entry)) => {
+                println!("> {} {}", key, state(entry));
+                only_b += 1;
+            },
+            (Some(in_a), Some(in_b)) if in_a != in_b => {
+                println!("! {} {} in a, {} in b", key, state(in_a), state(in_b));
+                differ += 1;
+            },
+            _ => (),
+        }
+    }
+
+    let names = |node: &Node| -> HashMap<String, Crdt> {
+        node.named.iter().map(|e| (e.val().name.clone(), e.val().state.lock().unwrap().clone())).collect()
+    };
+    let (names_a, names_b) = (names(&a), names(&b));
+    let all: BTreeSet<&String> = names_a.keys().chain(names_b.keys()).collect();
+    for name in all {
+        match (names_a.get(name), names_b.get(name)) {
+            (Some(_), None) => {
+                println!("< {}", name);
+                only_a += 1;
+            },
+            (None, Some(_)) => {
+                println!("> {}", name);
+                only_b += 1;
+            },
+            (Some(in_a), Some(in_b)) if in_a != in_b => {
+                println!("! {} {} in a, {} in b", name, in_a.value(), in_b.value());
+                differ += 1;
+            },
+            _ => (),
+        }
+    }
+
+    // like diff(1), holding the same is the only success
+    match only_a + only_b + differ {
+        0 => {
+            println!("Both hold the same.");
+            Ok(())
+        },
+        _ => Err(format!("{} only in a, {} only in b, {} differ.", only_a, only_b, differ)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::OpenOptions;
+    use std::io::Write;
+    use secko_messages::{EntryMeta, hash_value};
+    use secko_server::test_util::TempDir;
+
+    fn admin(args: &[&str]) -> Result<(), String> {
+        run(&cli().try_get_matches_from([&["secko-admin"], args].concat()).unwrap())
+    }
+
+    fn put(value: &[u8]) -> LogRecord {
+        LogRecord::Put { key: hash_value(value), value: value.to_vec(), meta: EntryMeta::new(1) }
+    }
+
+    // appends to the log at path, starting it if there isn't one yet
+    fn write_log(path: &str, end: u64, records: &[LogRecord]) {
+        if end == 0 {
+            commit_log::create(path, 0).unwrap();
+        }
+        let mut appender = commit_log::Appender::open(path, end, commit_log::SEGMENT_BYTES).unwrap();
+        let bytes: Vec<u8> = records.iter().flat_map(commit_log::encode).collect();
+        appender.append(&bytes, records.len() as u64, true).unwrap();
+    }
+
+    // what a server that crashed with a node's log half written leaves, put back together and compared with another node
+    #[test]
+    fn torn_log_is_truncated_rebuilt_and_diffed() {
+        let (a, b) = (TempDir::new("admin-a"), TempDir::new("admin-b"));
+        let (log_a, log_b) = (a.path(COMMIT_LOG_NAME), b.path(COMMIT_LOG_NAME));
+        let records = vec![put(b"one"), put(b"two"), LogRecord::Delete { key: hash_value(b"three"), meta: EntryMeta::new(1) }];
+        write_log(&log_a, 0, &records);
+        write_log(&log_b, 0, &records);
+
+        let torn = commit_log::encode(&put(b"four"));
+        let mut segment = OpenOptions::new().append(true).open(commit_log::segment_path(&log_a, 0)).unwrap();
+        segment.write_all(&torn[..torn.len() - 2]).unwrap();
+        drop(segment);
+
+        // a torn record at the end is what a crash leaves, not damage
+        admin(&["check-log", &log_a]).unwrap();
+        assert_eq!(commit_log::check(&log_a).unwrap().segments[0].torn, torn.len() as u64 - 2);
+
+        admin(&["truncate-log", &log_a]).unwrap();
+        let checked = commit_log::check(&log_a).unwrap();
+        assert_eq!(checked.segments[0].torn, 0);
+        assert_eq!(checked.records, records);
+
+        admin(&["rebuild", &log_a, &a.path(SNAPSHOT_NAME)]).unwrap();
+        admin(&["rebuild", &log_b, &b.path(SNAPSHOT_NAME)]).unwrap();
+        assert!(admin(&["rebuild", &log_a, &a.path(SNAPSHOT_NAME)]).is_err());
+        admin(&["verify", "-s", &a.path(SNAPSHOT_NAME), "-c", &log_a]).unwrap();
+        assert!(admin(&["verify", "-s", &a.path(SNAPSHOT_NAME), "-n", "not an address"]).is_err());
+        admin(&["diff", &a.0, &b.0]).unwrap();
+
+        // one more in b's log, on top of its snapshot
+        write_log(&log_b, records.len() as u64, &[put(b"four")]);
+        assert_eq!(admin(&["diff", &a.0, &b.0]), Err("0 only in a, 1 only in b, 0 differ.".to_string()));
+    }
+}
@@ -1,9 +1,9 @@
 extern crate ocaml;
 use std::net::TcpStream;
-use std::collections::hash_map::DefaultHasher;
-use std::hash::{Hash, Hasher};
 
-use secko_messages::{FoundValue, Message, KVPair, send_message, receive_message};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use secko_messages::{FoundValue, Message, KVPair, ScanItem, PushResult, ErrorCode, Key, EntryMeta, CrdtOp, CrdtValue, Manifest, chunk, hash_value, send_message, receive_message, connect};
 use crate::ocaml::{ToValue, FromValue};
 
 // https://zshipko.github.io/ocaml-rs/03_writing_ocaml_functions_in_rust.html#opaque-types
@@ -15,20 +15,72 @@ ocaml::custom!(Db);
 
 pub struct KeyVal(KVPair);
 
+// how many chunks of a large value to fetch per MultiRetrieveReq
+const CHUNKS_PER_REQUEST: usize = 8;
+
+// values are ocaml bytes. a plain Vec<u8> would cross over as an int array
+pub struct Bytes(Vec<u8>);
+
+unsafe impl ocaml::ToValue for Bytes {
+    fn to_value(&self, _gc: &ocaml::Runtime) -> ocaml::Value {
+        unsafe { ocaml::Value::bytes(&self.0) }
+    }
+}
+
+unsafe impl ocaml::FromValue for Bytes {
+    fn from_value(value: ocaml::Value) -> Bytes {
+        unsafe { Bytes(value.bytes_val().to_vec()) }
+    }
+}
+
 #[derive(ToValue, FromValue)]
-#[ocaml::sig("Present of string | NotPresent")]
+#[ocaml::sig("Present of bytes | NotPresent")]
 pub enum Lookup {
-    Present(String),
+    Present(Bytes),
     NotPresent,
 }
 
+#[derive(ToValue, FromValue)]
+#[ocaml::sig("Accepted | Duplicate | HashMismatch | Collision | Deleted")]
+pub enum PushOutcome {
+    Accepted,
+    Duplicate,
+    HashMismatch,
+    Collision,
+    Deleted,
+}
+
+// EntryMeta for ocaml. times are seconds since the epoch, like Unix.gettimeofday
+#[derive(ToValue, FromValue)]
+#[ocaml::sig("{origin: string; written_at: float; received_at: float; hops: int; expires_at: float option}")]
+pub struct Stat {
+    origin: String,
+    written_at: f64,
+    received_at: f64,
+    hops: ocaml::Int,
+    expires_at: Option<f64>,
+}
+
+impl From<EntryMeta> for Stat {
+    fn from(meta: EntryMeta) -> Stat {
+        let seconds = |t: SystemTime| t.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs_f64();
+        Stat {
+            origin: meta.origin.to_string(), // strings again, an int can't hold every u64
+            written_at: seconds(meta.written_at),
+            received_at: seconds(meta.received_at),
+            hops: meta.hops as ocaml::Int,
+            expires_at: meta.expires_at.map(seconds),
+        }
+    }
+}
+
 unsafe impl ocaml::ToValue for KeyVal {
     fn to_value(&self, gc: &ocaml::Runtime) -> ocaml::Value {
         unsafe { 
             let mut tup = ocaml::Value::alloc_tuple(2);
             
             tup.store_field(gc, 0, ocaml::Value::string::<&str>(&self.0.key.to_string()));
-            tup.store_field(gc, 1, ocaml::Value::string::<&str>(&self.0.value)); // i spent several hours trying to get this to work with int64. unfortunately there is no documentation on how to unpack it in ocaml correctly. strings are the only thing that work so i use that
+            tup.store_field(gc, 1, ocaml::Value::bytes(&self.0.value)); // i spent several hours trying to get this to work with int64. unfortunately there is no documentation on how to unpack it in ocaml correctly. strings are the only thing that work so i use that
             
             tup
         }
@@ -39,8 +91,8 @@ unsafe impl ocaml::FromValue for KeyVal {
     fn from_value(value: ocaml::Value) -> KeyVal {
         unsafe { 
             KeyVal(KVPair {
-                key: value.field(0).string_val().to_string().parse().unwrap(), // will always be a u64 as we are using this only in dump
-                value: value.field(1).string_val().to_string() 
+                key: value.field(0).string_val().parse().unwrap(), // will always be a valid key as we are using this only in dump
+                value: value.field(1).bytes_val().to_vec()
             }) 
         }
     }
@@ -50,39 +102,255 @@ unsafe impl ocaml::FromValue for KeyVal {
 #[ocaml::func]
 #[ocaml::sig("string -> int -> db")]
 pub unsafe fn init(address: &str, port: ocaml::Int) -> Result<ocaml::Pointer<Db>, ocaml::Error> {
-    let stream = TcpStream::connect(address.to_owned() + ":" + &port.to_string())?;
+    let stream = match connect(address.to_owned() + ":" + &port.to_string()) {
+        Ok(s) => s,
+        Err(e) => return Err(connection_error(e.to_string())),
+    };
     Ok(Db { con: stream }.into())
 }
 
 #[ocaml::func]
-#[ocaml::sig("db -> string -> unit")]
-pub unsafe fn push(db: &mut Db, value: String) -> Result<(), ocaml::Error> {
+#[ocaml::sig("db -> bytes -> unit")]
+pub unsafe fn push(db: &mut Db, value: &[u8]) -> Result<(), ocaml::Error> {
 
-    // make key for it
-    let mut hash = DefaultHasher::new();
-    value.to_string().hash(&mut hash);
-    let hashed: u64 = hash.finish(); 
+    // large values go as chunks first, then the manifest under the value's key
+    let pair = match chunk::split(value) {
+        Some((chunks, manifest)) => {
+            push_chunks(db, chunks, None)?;
+            manifest
+        },
+        None => KVPair {key: hash_value(value), value: value.to_vec()}, // make key for it
+    };
 
     // send the message
-    let data = Message::PushReq ( KVPair {key: hashed, value: value} );
+    let data = Message::PushReq ( pair, None );
     match send_message(&mut db.con, data) {
         Ok(()) => (),
-        Err(e) => return Err(ocaml::Error::Message(string_to_static_str(e))),
+        Err(e) => return Err(connection_error(e.to_string())),
     };
 
     // get the response
     let result: Message = match receive_message(&mut db.con) {
         Ok(msg) => msg,
-        Err(e) => return Err(ocaml::Error::Message(string_to_static_str(e))),
+        Err(e) => return Err(connection_error(e.to_string())),
     };
 
     // check message type
     match result {
-        Message::ConnectionClosed => Err(ocaml::Error::Message("Remote closed unexpectedly.")),
-        Message::Error(e) => Err(ocaml::Error::Message(string_to_static_str(e))),
+        Message::ConnectionClosed => Err(connection_error("Remote closed unexpectedly.".to_string())),
+        Message::Error{ code, detail } => Err(remote_error(code, detail)),
         Message::PushResp{success: true} => Ok(()),
         Message::PushResp{success: false} => Err(ocaml::Error::Message("Failed to push keys successfully")),
-        other => Err(ocaml::Error::Message(string_to_static_str(format!("Unexpected response received from remote: {}", other)))),
+        other => Err(protocol_error(format!("Unexpected response received from remote: {}", other))),
+    }
+
+}
+
+#[ocaml::func]
+#[ocaml::sig("db -> bytes -> string")]
+pub unsafe fn put(db: &mut Db, value: &[u8]) -> Result<String, ocaml::Error> {
+    put_with_ttl(db, value, None)
+}
+
+// the same, but the value expires ttl seconds after it's written
+#[ocaml::func]
+#[ocaml::sig("db -> bytes -> float -> string")]
+pub unsafe fn put_expiring(db: &mut Db, value: &[u8], ttl: f64) -> Result<String, ocaml::Error> {
+    let ttl = match Duration::try_from_secs_f64(ttl) {
+        Ok(v) => v,
+        Err(_) => return Err(ocaml::Error::Message("Please pass a non-negative ttl.")),
+    };
+    put_with_ttl(db, value, Some(ttl))
+}
+
+fn put_with_ttl(db: &mut Db, value: &[u8], ttl: Option<Duration>) -> Result<String, ocaml::Error> {
+    // no hashing here, the server hands back the key it stored the value under. unless it's large, then the chunks expire along
+    // with the manifest
+    let data = match chunk::split(value) {
+        Some((chunks, manifest)) => {
+            push_chunks(db, chunks, ttl)?;
+            Message::PushReq(manifest, ttl)
+        },
+        None => Message::PutReq { value: value.to_vec(), ttl },
+    };
+
+    // send the message
+    match send_message(&mut db.con, data) {
+        Ok(()) => (),
+        Err(e) => return Err(connection_error(e.to_string())),
+    };
+
+    // get the response
+    let result: Message = match receive_message(&mut db.con) {
+        Ok(msg) => msg,
+        Err(e) => return Err(connection_error(e.to_string())),
+    };
+
+    // check message type
+    match result {
+        Message::ConnectionClosed => Err(connection_error("Remote closed unexpectedly.".to_string())),
+        Message::Error{ code, detail } => Err(remote_error(code, detail)),
+        Message::PutResp{ key } => Ok(key.to_string()),
+        Message::PushResp{ success: true } => Ok(hash_value(value).to_string()),
+        Message::PushResp{ success: false } => Err(ocaml::Error::Message("Failed to push keys successfully")),
+        other => Err(protocol_error(format!("Unexpected response received from remote: {}", other))),
+    }
+
+}
+
+// one at a time, so no frame is much bigger than a chunk
+fn push_chunks(db: &mut Db, chunks: Vec<KVPair>, ttl: Option<Duration>) -> Result<(), ocaml::Error> {
+    for pair in chunks {
+        // send the message
+        match send_message(&mut db.con, Message::PushReq(pair, ttl)) {
+            Ok(()) => (),
+            Err(e) => return Err(connection_error(e.to_string())),
+        };
+
+        // get the response
+        let result: Message = match receive_message(&mut db.con) {
+            Ok(msg) => msg,
+            Err(e) => return Err(connection_error(e.to_string())),
+        };
+
+        // check message type
+        match result {
+            Message::ConnectionClosed => return Err(connection_error("Remote closed unexpectedly.".to_string())),
+            Message::Error{ code, detail } => return Err(remote_error(code, detail)),
+            Message::PushResp{ success: true } => (),
+            Message::PushResp{ success: false } => return Err(ocaml::Error::Message("Failed to push keys successfully")),
+            other => return Err(protocol_error(format!("Unexpected response received from remote: {}", other))),
+        }
+    }
+    Ok(())
+}
+
+// the value stored under key, put back together if what's stored is a manifest. None if a chunk hasn't reached this replica yet
+fn resolve(db: &mut Db, key: &Key, value: Vec<u8>) -> Result<Option<Vec<u8>>, ocaml::Error> {
+    let manifest = match Manifest::stored_as(key, &value) {
+        Some(m) => m,
+        None => return Ok(Some(value)),
+    };
+
+    let mut chunks: Vec<Vec<u8>> = Vec::with_capacity(manifest.chunks.len());
+    for keys in manifest.chunks.chunks(CHUNKS_PER_REQUEST) {
+        // send the message
+        match send_message(&mut db.con, Message::MultiRetrieveReq(keys.to_vec())) {
+            Ok(()) => (),
+            Err(e) => return Err(connection_error(e.to_string())),
+        };
+
+        // get the response
+        let result: Message = match receive_message(&mut db.con) {
+            Ok(msg) => msg,
+            Err(e) => return Err(connection_error(e.to_string())),
+        };
+
+        // check message type
+        match result {
+            Message::ConnectionClosed => return Err(connection_error("Remote closed unexpectedly.".to_string())),
+            Message::Error{ code, detail } => return Err(remote_error(code, detail)),
+            Message::MultiRetrieveResp(results) => for r in results {
+                match r {
+                    FoundValue::Success { value } => chunks.push(value),
+                    FoundValue::Failure => return Ok(None),
+                }
+            },
+            other => return Err(protocol_error(format!("Unexpected response received from remote: {}", other))),
+        }
+    }
+
+    match manifest.reassemble(key, chunks) {
+        Ok(value) => Ok(Some(value)),
+        Err(e) => Err(remote_error(ErrorCode::HashMismatch, Some(e))),
+    }
+}
+
+#[ocaml::func]
+#[ocaml::sig("db -> bytes array -> push_outcome array")]
+pub unsafe fn push_batch(db: &mut Db, values: Vec<Bytes>) -> Result<Vec<PushOutcome>, ocaml::Error> {
+
+    // make keys for them. large ones have their chunks pushed now, and their manifest goes in the batch instead
+    let mut pairs: Vec<KVPair> = Vec::with_capacity(values.len());
+    for Bytes(value) in values {
+        match chunk::split(&value) {
+            Some((chunks, manifest)) => {
+                push_chunks(db, chunks, None)?;
+                pairs.push(manifest);
+            },
+            None => pairs.push(KVPair {key: hash_value(&value), value: value}),
+        }
+    }
+
+    // send the message
+    let data = Message::PushBatchReq(pairs, None);
+    match send_message(&mut db.con, data) {
+        Ok(()) => (),
+        Err(e) => return Err(connection_error(e.to_string())),
+    };
+
+    // get the response
+    let result: Message = match receive_message(&mut db.con) {
+        Ok(msg) => msg,
+        Err(e) => return Err(connection_error(e.to_string())),
+    };
+
+    // check message type
+    match result {
+        Message::ConnectionClosed => Err(connection_error("Remote closed unexpectedly.".to_string())),
+        Message::Error{ code, detail } => Err(remote_error(code, detail)),
+        Message::PushBatchResp(results) => Ok(results.into_iter().map(|r| match r {
+            PushResult::Accepted => PushOutcome::Accepted,
+            PushResult::Duplicate => PushOutcome::Duplicate,
+            PushResult::HashMismatch => PushOutcome::HashMismatch,
+            PushResult::Collision => PushOutcome::Collision,
+            PushResult::Deleted => PushOutcome::Deleted,
+        }).collect()),
+        other => Err(protocol_error(format!("Unexpected response received from remote: {}", other))),
+    }
+
+}
+
+#[ocaml::func]
+#[ocaml::sig("db -> string array -> lookup array")]
+pub unsafe fn get_many(db: &mut Db, keys: Vec<String>) -> Result<Vec<Lookup>, ocaml::Error> {
+    // convert the strings to keys ("sha256:<hex>"). if any is not a key, throw error
+    let keys: Vec<Key> = match keys.iter().map(|k| k.parse::<Key>()).collect() {
+        Ok(v) => v,
+        Err(_) => return Err(ocaml::Error::Message("Please pass valid keys as strings.")),
+    };
+
+    // send the message
+    let data = Message::MultiRetrieveReq(keys.clone());
+    match send_message(&mut db.con, data) {
+        Ok(()) => (),
+        Err(e) => return Err(connection_error(e.to_string())),
+    };
+
+    // get the response
+    let result: Message = match receive_message(&mut db.con) {
+        Ok(msg) => msg,
+        Err(e) => return Err(connection_error(e.to_string())),
+    };
+
+    // check message type
+    match result {
+        Message::ConnectionClosed => Err(connection_error("Remote closed unexpectedly.".to_string())),
+        Message::Error{ code, detail } => Err(remote_error(code, detail)),
+        Message::MultiRetrieveResp(results) => {
+            let mut lookups: Vec<Lookup> = Vec::with_capacity(results.len());
+            for (key, r) in keys.iter().zip(results) {
+                lookups.push(match r {
+                    FoundValue::Success { value } => match resolve(db, key, value)? {
+                        Some(value) => Lookup::Present(Bytes(value)),
+                        None => Lookup::NotPresent,
+                    },
+                    FoundValue::Failure => Lookup::NotPresent,
+                });
+            }
+            Ok(lookups)
+        },
+        other => Err(protocol_error(format!("Unexpected response received from remote: {}", other))),
     }
 
 }
@@ -90,72 +358,364 @@ pub unsafe fn push(db: &mut Db, value: String) -> Result<(), ocaml::Error> {
 #[ocaml::func]
 #[ocaml::sig("db -> string -> lookup")]
 pub unsafe fn get(db: &mut Db, key: String) -> Result<ocaml::Pointer<Lookup>, ocaml::Error> {
-    // convert the string to a u64. if not a u64, throw error
-    let key: u64 = match key.parse() {
+    // convert the string to a key ("sha256:<hex>"). if not a key, throw error
+    let key: Key = match key.parse() {
         Ok(v) => v,
-        Err(_) => return Err(ocaml::Error::Message("Please pass a valid u64 as a string.")),
+        Err(_) => return Err(ocaml::Error::Message("Please pass a valid key as a string.")),
     };
 
     // send the message
     let data = Message::RetrieveReq { key: key };
     match send_message(&mut db.con, data) {
         Ok(()) => (),
-        Err(e) => return Err(ocaml::Error::Message(string_to_static_str(e))),
+        Err(e) => return Err(connection_error(e.to_string())),
     };
 
     // get the response
     let result: Message = match receive_message(&mut db.con) {
         Ok(msg) => msg,
-        Err(e) => return Err(ocaml::Error::Message(string_to_static_str(e))),//Err(ocaml::Error::Message(&e)),
+        Err(e) => return Err(connection_error(e.to_string())),
     };
 
     // check message type
     match result {
-        Message::ConnectionClosed => Err(ocaml::Error::Message("Remote closed unexpectedly.")),
-        Message::Error(e) => Err(ocaml::Error::Message(string_to_static_str(e))),//Err(ocaml::Error::Message(&e)),
-        Message::RetrieveResp{ result: FoundValue::Success { value } } => Ok(Lookup::Present(value).to_value(gc).into()),
+        Message::ConnectionClosed => Err(connection_error("Remote closed unexpectedly.".to_string())),
+        Message::Error{ code, detail } => Err(remote_error(code, detail)),
+        Message::RetrieveResp{ result: FoundValue::Success { value } } => match resolve(db, &key, value)? {
+            Some(value) => Ok(Lookup::Present(Bytes(value)).to_value(gc).into()),
+            None => Ok(Lookup::NotPresent.to_value(gc).into()),
+        },
         Message::RetrieveResp{ result: FoundValue::Failure} => Ok(Lookup::NotPresent.to_value(gc).into()),//Err(ocaml::Error::Message("Key not found.")),
-        other => Err(ocaml::Error::Message(string_to_static_str(format!("Unexpected response received from remote: {:#?}", other)))),
+        other => Err(protocol_error(format!("Unexpected response received from remote: {}", other))),
     }
 
 }
 
 #[ocaml::func]
-#[ocaml::sig("db -> 'keyval array")]
-pub unsafe fn dump(db: &mut Db) -> Result<Vec<ocaml::Pointer<KeyVal>>, ocaml::Error> {
+#[ocaml::sig("db -> string -> stat option")]
+pub unsafe fn stat(db: &mut Db, key: String) -> Result<Option<Stat>, ocaml::Error> {
+    // convert the string to a key ("sha256:<hex>"). if not a key, throw error
+    let key: Key = match key.parse() {
+        Ok(v) => v,
+        Err(_) => return Err(ocaml::Error::Message("Please pass a valid key as a string.")),
+    };
 
     // send the message
-    let data = Message::DumpReq;
+    let data = Message::StatReq { key };
     match send_message(&mut db.con, data) {
         Ok(()) => (),
-        Err(e) => return Err(ocaml::Error::Message(string_to_static_str(e))),
+        Err(e) => return Err(connection_error(e.to_string())),
     };
 
-    // get the response 
+    // get the response
     let result: Message = match receive_message(&mut db.con) {
         Ok(msg) => msg,
-        Err(e) => return Err(ocaml::Error::Message(string_to_static_str(e))),//Err(ocaml::Error::Message(&e)),
+        Err(e) => return Err(connection_error(e.to_string())),
     };
 
     // check message type
     match result {
-        Message::ConnectionClosed => Err(ocaml::Error::Message("Remote closed unexpectedly.")),
-        Message::Error(e) => Err(ocaml::Error::Message(string_to_static_str(e))),//Err(ocaml::Error::Message(&e)),
-        Message::DumpResp(v) => {
-            let mut returned: Vec<ocaml::Pointer<KeyVal>> = Vec::new();
-            for elem in v {
-                returned.push(KeyVal(KVPair { key: elem.key, value: elem.value }).to_value(gc).into());
-                //returned.push(CamlKV{ key_low_32: (elem.key as i32) as i64, key_high_32: (elem.key >> 32) as i64, value: Val { value: elem.value }.into() }.into());
-            }
-            Ok(returned)
-        },
-        other => Err(ocaml::Error::Message(string_to_static_str(format!("Unexpected response received from remote: {:#?}", other)))),
+        Message::ConnectionClosed => Err(connection_error("Remote closed unexpectedly.".to_string())),
+        Message::Error{ code, detail } => Err(remote_error(code, detail)),
+        Message::StatResp{ meta } => Ok(meta.map(Stat::from)),
+        other => Err(protocol_error(format!("Unexpected response received from remote: {}", other))),
     }
 
 }
 
-// for error messages: https://stackoverflow.com/questions/23975391/how-to-convert-a-string-into-a-static-str
-// i do not like this but ocaml-rs has put me in this position...hopefully ocaml's garbage collection deals with this well?
-fn string_to_static_str(s: String) -> &'static str {
-    Box::leak(s.into_boxed_str())
-}
\ No newline at end of file
+// true if the server held a value for it
+#[ocaml::func]
+#[ocaml::sig("db -> string -> bool")]
+pub unsafe fn delete(db: &mut Db, key: String) -> Result<bool, ocaml::Error> {
+    // convert the string to a key ("sha256:<hex>"). if not a key, throw error
+    let key: Key = match key.parse() {
+        Ok(v) => v,
+        Err(_) => return Err(ocaml::Error::Message("Please pass a valid key as a string.")),
+    };
+
+    // send the message
+    let data = Message::DeleteReq { key };
+    match send_message(&mut db.con, data) {
+        Ok(()) => (),
+        Err(e) => return Err(connection_error(e.to_string())),
+    };
+
+    // get the response
+    let result: Message = match receive_message(&mut db.con) {
+        Ok(msg) => msg,
+        Err(e) => return Err(connection_error(e.to_string())),
+    };
+
+    // check message type
+    match result {
+        Message::ConnectionClosed => Err(connection_error("Remote closed unexpectedly.".to_string())),
+        Message::Error{ code, detail } => Err(remote_error(code, detail)),
+        Message::DeleteResp{ found } => Ok(found),
+        other => Err(protocol_error(format!("Unexpected response received from remote: {}", other))),
+    }
+
+}
+
+// what a named key holds. a multi-value register has one sibling per write that none of the others saw
+#[derive(ToValue, FromValue)]
+#[ocaml::sig("Register of bytes | Siblings of bytes array | Counter of int | Set of bytes array")]
+pub enum Named {
+    Register(Bytes),
+    Siblings(Vec<Bytes>),
+    Counter(ocaml::Int),
+    Set(Vec<Bytes>),
+}
+
+impl From<CrdtValue> for Named {
+    fn from(value: CrdtValue) -> Named {
+        match value {
+            CrdtValue::Register(value) => Named::Register(Bytes(value)),
+            CrdtValue::Siblings(values) => Named::Siblings(values.into_iter().map(Bytes).collect()),
+            CrdtValue::Counter(n) => Named::Counter(n as ocaml::Int),
+            CrdtValue::Set(elements) => Named::Set(elements.into_iter().map(Bytes).collect()),
+        }
+    }
+}
+
+// last writer wins. fails with Invalid_request if the name already holds something else
+#[ocaml::func]
+#[ocaml::sig("db -> string -> bytes -> unit")]
+pub unsafe fn set_register(db: &mut Db, name: String, value: &[u8]) -> Result<(), ocaml::Error> {
+    update_named(db, name, CrdtOp::SetLww(value.to_vec()))
+}
+
+// concurrent writes are all kept, read_named gives them back as siblings
+#[ocaml::func]
+#[ocaml::sig("db -> string -> bytes -> unit")]
+pub unsafe fn set_mv_register(db: &mut Db, name: String, value: &[u8]) -> Result<(), ocaml::Error> {
+    update_named(db, name, CrdtOp::SetMv(value.to_vec()))
+}
+
+// grow-only counter, by must not be negative
+#[ocaml::func]
+#[ocaml::sig("db -> string -> int -> unit")]
+pub unsafe fn increment(db: &mut Db, name: String, by: ocaml::Int) -> Result<(), ocaml::Error> {
+    let by: u64 = match u64::try_from(by) {
+        Ok(v) => v,
+        Err(_) => return Err(ocaml::Error::Message("A grow-only counter can't be decremented, use a pn counter.")),
+    };
+    update_named(db, name, CrdtOp::Increment(by))
+}
+
+// pn counter, negative to decrement
+#[ocaml::func]
+#[ocaml::sig("db -> string -> int -> unit")]
+pub unsafe fn add_to_counter(db: &mut Db, name: String, by: ocaml::Int) -> Result<(), ocaml::Error> {
+    update_named(db, name, CrdtOp::Add(by as i64))
+}
+
+#[ocaml::func]
+#[ocaml::sig("db -> string -> bytes -> unit")]
+pub unsafe fn add_to_gset(db: &mut Db, name: String, element: &[u8]) -> Result<(), ocaml::Error> {
+    update_named(db, name, CrdtOp::AddG(element.to_vec()))
+}
+
+#[ocaml::func]
+#[ocaml::sig("db -> string -> bytes -> unit")]
+pub unsafe fn add_to_orset(db: &mut Db, name: String, element: &[u8]) -> Result<(), ocaml::Error> {
+    update_named(db, name, CrdtOp::AddOr(element.to_vec()))
+}
+
+// only takes out the adds this replica has seen, a concurrent add elsewhere wins
+#[ocaml::func]
+#[ocaml::sig("db -> string -> bytes -> unit")]
+pub unsafe fn remove_from_orset(db: &mut Db, name: String, element: &[u8]) -> Result<(), ocaml::Error> {
+    update_named(db, name, CrdtOp::RemoveOr(element.to_vec()))
+}
+
+fn update_named(db: &mut Db, name: String, op: CrdtOp) -> Result<(), ocaml::Error> {
+    // send the message
+    let data = Message::UpdateNamedReq { name, op };
+    match send_message(&mut db.con, data) {
+        Ok(()) => (),
+        Err(e) => return Err(connection_error(e.to_string())),
+    };
+
+    // get the response
+    let result: Message = match receive_message(&mut db.con) {
+        Ok(msg) => msg,
+        Err(e) => return Err(connection_error(e.to_string())),
+    };
+
+    // check message type
+    match result {
+        Message::ConnectionClosed => Err(connection_error("Remote closed unexpectedly.".to_string())),
+        Message::Error{ code, detail } => Err(remote_error(code, detail)),
+        Message::UpdateNamedResp => Ok(()),
+        other => Err(protocol_error(format!("Unexpected response received from remote: {}", other))),
+    }
+}
+
+#[ocaml::func]
+#[ocaml::sig("db -> string -> named option")]
+pub unsafe fn read_named(db: &mut Db, name: String) -> Result<Option<Named>, ocaml::Error> {
+    // send the message
+    let data = Message::ReadNamedReq { name };
+    match send_message(&mut db.con, data) {
+        Ok(()) => (),
+        Err(e) => return Err(connection_error(e.to_string())),
+    };
+
+    // get the response
+    let result: Message = match receive_message(&mut db.con) {
+        Ok(msg) => msg,
+        Err(e) => return Err(connection_error(e.to_string())),
+    };
+
+    // check message type
+    match result {
+        Message::ConnectionClosed => Err(connection_error("Remote closed unexpectedly.".to_string())),
+        Message::Error{ code, detail } => Err(remote_error(code, detail)),
+        Message::ReadNamedResp{ value } => Ok(value.map(Named::from)),
+        other => Err(protocol_error(format!("Unexpected response received from remote: {}", other))),
+    }
+
+}
+
+#[derive(ToValue, FromValue)]
+#[ocaml::sig("{index: int; key: string; value: bytes option; deleted: bool}")]
+pub struct Event {
+    index: ocaml::Int, // resume from index + 1
+    key: String,
+    value: Option<Bytes>, // only if the subscription asked for values, never for deletes
+    deleted: bool,
+}
+
+// turns db into a feed, read it with next_event. open another db for anything else
+#[ocaml::func]
+#[ocaml::sig("db -> int -> bool -> unit")]
+pub unsafe fn subscribe(db: &mut Db, from_index: ocaml::Int, with_values: bool) -> Result<(), ocaml::Error> {
+    if from_index < 0 {
+        return Err(ocaml::Error::Message("Please pass a non-negative index."));
+    }
+
+    // send the message. nothing comes back until there's an event
+    let data = Message::SubscribeReq { from_index: from_index as usize, with_values };
+    match send_message(&mut db.con, data) {
+        Ok(()) => Ok(()),
+        Err(e) => Err(connection_error(e.to_string())),
+    }
+}
+
+// blocks until the next key lands
+#[ocaml::func]
+#[ocaml::sig("db -> event")]
+pub unsafe fn next_event(db: &mut Db) -> Result<Event, ocaml::Error> {
+    // get the event
+    let result: Message = match receive_message(&mut db.con) {
+        Ok(msg) => msg,
+        Err(e) => return Err(connection_error(e.to_string())),
+    };
+
+    // check message type
+    match result {
+        Message::ConnectionClosed => Err(connection_error("Remote closed unexpectedly.".to_string())),
+        Message::Error{ code, detail } => Err(remote_error(code, detail)),
+        Message::SubscribeEvent{ index, item, deleted } => Ok(Event {
+            index: index as ocaml::Int,
+            key: item.key.to_string(),
+            value: item.value.map(Bytes),
+            deleted,
+        }),
+        other => Err(protocol_error(format!("Unexpected response received from remote: {}", other))),
+    }
+}
+
+// how many entries to ask for per ScanReq when dumping
+const SCAN_PAGE_SIZE: usize = 1000;
+
+#[ocaml::func]
+#[ocaml::sig("db -> 'keyval array")]
+pub unsafe fn dump(db: &mut Db) -> Result<Vec<ocaml::Pointer<KeyVal>>, ocaml::Error> {
+    let mut returned: Vec<ocaml::Pointer<KeyVal>> = Vec::new();
+
+    // page through the store so neither side has to hold the whole thing in one frame
+    for item in scan_all(db, false)? {
+        if let Some(value) = item.value {
+            returned.push(KeyVal(KVPair { key: item.key, value }).to_value(gc).into());
+        }
+    }
+
+    Ok(returned)
+}
+
+#[ocaml::func]
+#[ocaml::sig("db -> string array")]
+pub unsafe fn keys(db: &mut Db) -> Result<Vec<String>, ocaml::Error> {
+    // keys only, cheap enough to diff replicas with
+    Ok(scan_all(db, true)?.into_iter().map(|item| item.key.to_string()).collect())
+}
+
+fn scan_all(db: &mut Db, keys_only: bool) -> Result<Vec<ScanItem>, ocaml::Error> {
+    let mut items: Vec<ScanItem> = Vec::new();
+    let mut cursor: usize = 0;
+
+    loop {
+        // send the message
+        let data = Message::ScanReq { cursor, limit: SCAN_PAGE_SIZE, keys_only };
+        match send_message(&mut db.con, data) {
+            Ok(()) => (),
+            Err(e) => return Err(connection_error(e.to_string())),
+        };
+
+        // get the response
+        let result: Message = match receive_message(&mut db.con) {
+            Ok(msg) => msg,
+            Err(e) => return Err(connection_error(e.to_string())),
+        };
+
+        // check message type
+        match result {
+            Message::ConnectionClosed => return Err(connection_error("Remote closed unexpectedly.".to_string())),
+            Message::Error{ code, detail } => return Err(remote_error(code, detail)),
+            Message::ScanResp { items: page, next_cursor } => {
+                items.extend(page);
+                match next_cursor {
+                    Some(next) => cursor = next,
+                    None => return Ok(items),
+                }
+            },
+            other => return Err(protocol_error(format!("Unexpected response received from remote: {}", other))),
+        }
+    }
+}
+
+// errors surface in ocaml as the exceptions registered at the bottom of rust.ml, each carrying a description, so callers can
+// match on what went wrong (and e.g. retry Overloaded but not Hash_mismatch). falls back to a Failure if they aren't registered
+fn raise_named(name: &str, description: String) -> ocaml::Error {
+    match ocaml::Error::raise_with_arg(name, unsafe { ocaml::Value::string(description) }) {
+        Err(e) => e,
+        Ok(()) => ocaml::Error::Message("Failed to raise exception."),
+    }
+}
+
+fn remote_error(code: ErrorCode, detail: Option<String>) -> ocaml::Error {
+    let name = match code {
+        ErrorCode::HashMismatch => "secko_hash_mismatch",
+        ErrorCode::FrameTooLarge => "secko_frame_too_large",
+        ErrorCode::UnsupportedVersion => "secko_unsupported_version",
+        ErrorCode::Overloaded => "secko_overloaded",
+        ErrorCode::UnknownMessage => "secko_unknown_message",
+        ErrorCode::InvalidRequest => "secko_invalid_request",
+        ErrorCode::StorageFailure => "secko_storage_failure",
+        ErrorCode::Internal => "secko_internal_error",
+        ErrorCode::KeyCollision => "secko_key_collision",
+        ErrorCode::KeyDeleted => "secko_key_deleted",
+    };
+    raise_named(name, detail.unwrap_or_else(|| code.to_string()))
+}
+
+// couldn't talk to the server at all (connect, handshake, or the connection dropped)
+fn connection_error(description: String) -> ocaml::Error {
+    raise_named("secko_connection_error", description)
+}
+
+// server answered with something that isn't a response to what we asked
+fn protocol_error(description: String) -> ocaml::Error {
+    raise_named("secko_protocol_error", description)
+}
@@ -1,22 +1,74 @@
 use std::net::TcpStream;
-use std::collections::hash_map::DefaultHasher;
-use std::hash::{Hash, Hasher};
 
 use std::env;
+use std::process::exit;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use std::io::{stdin,stdout,Write};
-use secko_messages::{Message, KVPair, FoundValue, send_message, receive_message};
+use secko_messages::{Message, KVPair, FoundValue, ErrorCode, Key, CrdtOp, CrdtValue, Manifest, chunk, hash_value, send_message, receive_message, connect, FrameError};
+
+// how many entries to ask for per ScanReq
+const SCAN_PAGE_SIZE: usize = 1000;
+
+// how many chunks of a large value to fetch per MultiRetrieveReq
+const CHUNKS_PER_REQUEST: usize = 8;
+
+// exit codes for one-shot mode, so scripts can tell failures apart (and e.g. retry on overload but not on a hash mismatch)
+const EXIT_OK: i32 = 0;
+const EXIT_USAGE: i32 = 1;
+const EXIT_CONNECTION: i32 = 2;
+
+fn exit_code(code: ErrorCode) -> i32 {
+    match code {
+        ErrorCode::HashMismatch => 10,
+        ErrorCode::FrameTooLarge => 11,
+        ErrorCode::UnsupportedVersion => 12,
+        ErrorCode::Overloaded => 13,
+        ErrorCode::UnknownMessage => 14,
+        ErrorCode::InvalidRequest => 15,
+        ErrorCode::StorageFailure => 16,
+        ErrorCode::Internal => 17,
+        ErrorCode::KeyCollision => 18,
+        ErrorCode::KeyDeleted => 19,
+    }
+}
+
+fn print_error(what: &str, code: ErrorCode, detail: Option<String>) -> i32 {
+    match detail {
+        Some(d) => eprintln!("{} failed with error: {} ({})", what, code, d),
+        None => eprintln!("{} failed with error: {}", what, code),
+    }
+    exit_code(code)
+}
+
+// the connection went away or sent something we couldn't read, nothing more will come of it
+fn connection_failed(e: FrameError) -> i32 {
+    eprintln!("Lost the connection with error: {}", e);
+    EXIT_CONNECTION
+}
 
 // cli front provided here, rest of library elsewhere. this is mainly for testing
+// "<ip>:<port>" alone starts the interactive prompt, "<ip>:<port> <command...>" runs that one command and exits with its status
 fn main() {
     let args: Vec<String> = env::args().collect();
-    if args.len() != 2 {
-        println!("Insufficient arguments. Please provide \"<ip>:<port>\".");
-        return;
+    if args.len() < 2 {
+        println!("Insufficient arguments. Please provide \"<ip>:<port>\", optionally followed by a command.");
+        exit(EXIT_USAGE);
     }
 
     // let mut stream = TcpStream::connect("127.0.0.1:6359").unwrap();
-    let mut stream = TcpStream::connect(&args[1]).unwrap();
+    let mut stream = match connect(&args[1]) {
+        Ok(s) => s,
+        Err(e) => {
+            eprintln!("Failed to connect with error: {}", e);
+            exit(EXIT_CONNECTION);
+        }
+    };
+
+    if args.len() > 2 {
+        exit(run_command(&mut stream, &args[2..].join(" ")));
+    }
+
     let mut s = String::new();
 
     loop {
@@ -26,120 +78,674 @@ fn main() {
         stdin().read_line(&mut s).expect("Did not enter a correct string");
         s = s.trim().to_string();
 
-        // https://stackoverflow.com/questions/34559640/what-is-the-correct-idiomatic-way-to-check-if-a-string-starts-with-a-certain-c
-        match s.get(..1) {
-            Some("P") => {
-                // get the value specified after "POST" -> not super sanitized
-                let (_, value) = s.split_once(' ').unwrap(); 
+        run_command(&mut stream, &s);
 
-                // push
-                match push_req(&mut stream, value.to_string()) {
-                    Message::Error(e) => println!("Push failed with error: {}", e),
-                    Message::PushResp{success: true} => println!("Key pushed successfully."),
-                    _ => println!("Execution should not have reached this point.")
-                }
-            },
+        s.clear();
+    }
+}
 
-            Some("G") => {
-                // get the value specified after "GET"
-                let key: &str = s.split_once(' ').unwrap().1;
-                
-                let key: u64 = match key.parse::<u64>() {
-                    Ok(v) => v,
+// runs one command, printing the result. returns the exit code for it
+fn run_command(stream: &mut TcpStream, s: &str) -> i32 {
+    // https://stackoverflow.com/questions/34559640/what-is-the-correct-idiomatic-way-to-check-if-a-string-starts-with-a-certain-c
+    match s.get(..1) {
+        Some("P") if s.starts_with("PNADD") => {
+            // get the name and the amount specified after "PNADD", negative to decrement
+            let parsed = s.split_whitespace().skip(1).collect::<Vec<&str>>();
+            let (name, by) = match parsed.as_slice() {
+                [name, by] => match by.parse::<i64>() {
+                    Ok(by) => (name.to_string(), by),
                     Err(_) => {
-                        println!("Please provide a valid u64 value.");
-                        return
+                        println!("Please provide a whole number to add.");
+                        return EXIT_USAGE
                     }
-                };
+                },
+                _ => {
+                    println!("Please use the form \"PNADD <name> <amount>\".");
+                    return EXIT_USAGE
+                }
+            };
+
+            update_named(stream, name, CrdtOp::Add(by))
+        },
 
-                // get
-                match get_req(&mut stream, key) {
-                    Message::RetrieveResp{ result: FoundValue::Success { value }} => println!("Value found: {}",value),
-                    Message::RetrieveResp{ result: FoundValue::Failure} => println!("Value not found."),
-                    _ => println!("Execution should not have reached this point.")
+        Some("P") if s.starts_with("PUTEX") => {
+            // get the ttl in seconds and the value specified after "PUTEX", the server works out the key
+            let (ttl, value) = match s.split_once(' ').and_then(|(_, rest)| rest.split_once(' ')) {
+                Some(v) => v,
+                None => {
+                    println!("Please use the form \"PUTEX <seconds> <value>\".");
+                    return EXIT_USAGE
                 }
-            },
+            };
+            let ttl: Duration = match ttl.parse::<f64>().ok().and_then(|t| Duration::try_from_secs_f64(t).ok()) {
+                Some(v) => v,
+                None => {
+                    println!("Please provide a valid number of seconds for the ttl.");
+                    return EXIT_USAGE
+                }
+            };
 
-            Some("D") => {
-                // dump
-                match dump_req(&mut stream) {
-                    Message::DumpResp(dumped) => println!("{:#?}", dumped),
-                    _ => println!("Execution should not have reached this point.")
+            // put
+            match put_req(stream, value.to_string(), Some(ttl)) {
+                Ok(Message::Error{ code, detail }) => print_error("Put", code, detail),
+                Ok(Message::PutResp{ key }) => {
+                    println!("Stored under key: {}", key);
+                    EXIT_OK
+                },
+                Err(e) => connection_failed(e),
+                Ok(_) => {
+                    println!("Execution should not have reached this point.");
+                    EXIT_USAGE
                 }
-            },
+            }
+        },
 
-            Some("L") => {
-                // get cluster
-                match cluster_req(&mut stream) {
-                    Message::ClusterResp(cluster) => println!("{:#?}", cluster),
-                    _ => println!("Execution should not have reached this point.")
+        Some("P") if s.starts_with("PUT") => {
+            // get the value specified after "PUT", the server works out the key
+            let (_, value) = s.split_once(' ').unwrap();
+
+            // put
+            match put_req(stream, value.to_string(), None) {
+                Ok(Message::Error{ code, detail }) => print_error("Put", code, detail),
+                Ok(Message::PutResp{ key }) => {
+                    println!("Stored under key: {}", key);
+                    EXIT_OK
+                },
+                Err(e) => connection_failed(e),
+                Ok(_) => {
+                    println!("Execution should not have reached this point.");
+                    EXIT_USAGE
                 }
-            },
+            }
+        },
+
+        Some("P") => {
+            // get the value specified after "POST" -> not super sanitized
+            let (_, value) = s.split_once(' ').unwrap(); 
+
+            // push
+            match push_req(stream, value.to_string()) {
+                Ok(Message::Error{ code, detail }) => print_error("Push", code, detail),
+                Ok(Message::PushResp{success: true}) => {
+                    println!("Key pushed successfully.");
+                    EXIT_OK
+                },
+                Err(e) => connection_failed(e),
+                Ok(_) => {
+                    println!("Execution should not have reached this point.");
+                    EXIT_USAGE
+                }
+            }
+        },
+
+        Some("G") => {
+            // get the value specified after "GET"
+            let key: &str = s.split_once(' ').unwrap().1;
             
-            Some("S") => {
-                // get the value specified after "SELECT"
-                let (_, new_address) = s.split_once(' ').unwrap(); 
-                println!("{}", new_address);
-
-                // create a new connection
-                stream = match TcpStream::connect(new_address) {
-                    Ok(s) => {
-                        println!("New connection successful.");
-                        s
-                    },
+            let key: Key = match key.trim().parse::<Key>() {
+                Ok(v) => v,
+                Err(e) => {
+                    println!("Please provide a valid key. {}", e);
+                    return EXIT_USAGE
+                }
+            };
+
+            // get
+            match get_req(stream, key) {
+                Ok(Message::RetrieveResp{ result: FoundValue::Success { value }}) => {
+                    println!("Value found: {}", String::from_utf8_lossy(&value));
+                    EXIT_OK
+                },
+                Ok(Message::RetrieveResp{ result: FoundValue::Failure}) => {
+                    println!("Value not found.");
+                    EXIT_OK
+                },
+                Ok(Message::Error{ code, detail }) => print_error("Get", code, detail),
+                Err(e) => connection_failed(e),
+                Ok(_) => {
+                    println!("Execution should not have reached this point.");
+                    EXIT_USAGE
+                }
+            }
+        },
+
+        Some("B") => {
+            // get the values specified after "BATCH", separated by ';'
+            let (_, values) = s.split_once(' ').unwrap();
+            let values: Vec<String> = values.split(';').map(|v| v.to_string()).collect();
+
+            // push all of them in one go
+            match push_batch_req(stream, values.clone()) {
+                Ok(Message::Error{ code, detail }) => print_error("Batch push", code, detail),
+                Ok(Message::PushBatchResp(results)) => {
+                    for (value, result) in values.iter().zip(results) {
+                        println!("{} -> {}", value, result);
+                    }
+                    EXIT_OK
+                },
+                Err(e) => connection_failed(e),
+                Ok(_) => {
+                    println!("Execution should not have reached this point.");
+                    EXIT_USAGE
+                }
+            }
+        },
+
+        Some("M") => {
+            // get the keys specified after "MGET", separated by spaces
+            let (_, keys) = s.split_once(' ').unwrap();
+            let keys: Vec<Key> = match keys.split_whitespace().map(|k| k.parse::<Key>()).collect() {
+                Ok(v) => v,
+                Err(e) => {
+                    println!("Please provide valid keys. {}", e);
+                    return EXIT_USAGE
+                }
+            };
+
+            // get all of them in one go
+            match multi_get_req(stream, keys.clone()) {
+                Ok(Message::MultiRetrieveResp(results)) => {
+                    for (key, result) in keys.iter().zip(results) {
+                        match result {
+                            FoundValue::Success { value } => println!("{} -> {}", key, String::from_utf8_lossy(&value)),
+                            FoundValue::Failure => println!("{} not found.", key),
+                        }
+                    }
+                    EXIT_OK
+                },
+                Ok(Message::Error{ code, detail }) => print_error("Multi get", code, detail),
+                Err(e) => connection_failed(e),
+                Ok(_) => {
+                    println!("Execution should not have reached this point.");
+                    EXIT_USAGE
+                }
+            }
+        },
+
+        Some("D") if s.starts_with("DELETE") => {
+            // get the key specified after "DELETE"
+            let key: &str = s.split_once(' ').unwrap().1;
+
+            let key: Key = match key.trim().parse::<Key>() {
+                Ok(v) => v,
+                Err(e) => {
+                    println!("Please provide a valid key. {}", e);
+                    return EXIT_USAGE
+                }
+            };
+
+            // delete
+            match delete_req(stream, key) {
+                Ok(Message::DeleteResp{ found: true }) => {
+                    println!("Key deleted.");
+                    EXIT_OK
+                },
+                Ok(Message::DeleteResp{ found: false }) => {
+                    println!("Value not found, the delete was recorded anyway.");
+                    EXIT_OK
+                },
+                Ok(Message::Error{ code, detail }) => print_error("Delete", code, detail),
+                Err(e) => connection_failed(e),
+                Ok(_) => {
+                    println!("Execution should not have reached this point.");
+                    EXIT_USAGE
+                }
+            }
+        },
+
+        Some("D") => {
+            // dump, a page at a time
+            scan(stream, false)
+        },
+
+        Some("K") => {
+            // list keys only, a page at a time
+            scan(stream, true)
+        },
+
+        Some("L") => {
+            // get cluster
+            match cluster_req(stream) {
+                Ok(Message::ClusterResp(cluster)) => {
+                    println!("{:#?}", cluster);
+                    EXIT_OK
+                },
+                Ok(Message::Error{ code, detail }) => print_error("List cluster", code, detail),
+                Err(e) => connection_failed(e),
+                Ok(_) => {
+                    println!("Execution should not have reached this point.");
+                    EXIT_USAGE
+                }
+            }
+        },
+        
+        Some("S") if s.starts_with("SETREG") || s.starts_with("SETMV") => {
+            // get the name and the value specified after "SETREG"/"SETMV"
+            let (name, value) = match s.split_once(' ').and_then(|(_, rest)| rest.split_once(' ')) {
+                Some(v) => v,
+                None => {
+                    println!("Please use the form \"SETREG <name> <value>\" or \"SETMV <name> <value>\".");
+                    return EXIT_USAGE
+                }
+            };
+            let op = if s.starts_with("SETREG") { CrdtOp::SetLww(value.as_bytes().to_vec()) } else { CrdtOp::SetMv(value.as_bytes().to_vec()) };
+
+            update_named(stream, name.to_string(), op)
+        },
+
+        Some("S") if s.starts_with("SADD") => {
+            // get the name and the element specified after "SADD"
+            let (name, element) = match s.split_once(' ').and_then(|(_, rest)| rest.split_once(' ')) {
+                Some(v) => v,
+                None => {
+                    println!("Please use the form \"SADD <name> <element>\".");
+                    return EXIT_USAGE
+                }
+            };
+
+            update_named(stream, name.to_string(), CrdtOp::AddG(element.as_bytes().to_vec()))
+        },
+
+        Some("O") => {
+            // get the name and the element specified after "ORADD"/"ORREM"
+            let (name, element) = match s.split_once(' ').and_then(|(_, rest)| rest.split_once(' ')) {
+                Some(v) => v,
+                None => {
+                    println!("Please use the form \"ORADD <name> <element>\" or \"ORREM <name> <element>\".");
+                    return EXIT_USAGE
+                }
+            };
+            let op = if s.starts_with("ORREM") { CrdtOp::RemoveOr(element.as_bytes().to_vec()) } else { CrdtOp::AddOr(element.as_bytes().to_vec()) };
+
+            update_named(stream, name.to_string(), op)
+        },
+
+        Some("I") => {
+            // get the name and the amount specified after "INCR", 1 if there isn't one
+            let parsed = s.split_whitespace().skip(1).collect::<Vec<&str>>();
+            let (name, by) = match parsed.as_slice() {
+                [name] => (name.to_string(), 1),
+                [name, by] => match by.parse::<u64>() {
+                    Ok(by) => (name.to_string(), by),
                     Err(_) => {
-                        println!("Invalid address format. Please use form \"SELECT <ip>:<port>\".");
-                        continue
+                        println!("Please provide a positive whole number to increment by.");
+                        return EXIT_USAGE
                     }
-                };
-            },
+                },
+                _ => {
+                    println!("Please use the form \"INCR <name> [amount]\".");
+                    return EXIT_USAGE
+                }
+            };
 
-            _ => println!("Invalid command. Please enter either \"POST <value>\", \"GET <key>\", \"DUMP\", \"LISTCLUSTER\", or \"SELECT <ip>:<port>\".")
-        };
+            update_named(stream, name, CrdtOp::Increment(by))
+        },
 
-        s.clear();
+        Some("R") => {
+            // get the name specified after "READ"
+            let name: &str = match s.split_once(' ') {
+                Some((_, name)) => name.trim(),
+                None => {
+                    println!("Please use the form \"READ <name>\".");
+                    return EXIT_USAGE
+                }
+            };
+
+            // read
+            match read_named_req(stream, name.to_string()) {
+                Ok(Message::ReadNamedResp{ value: Some(CrdtValue::Register(value)) }) => {
+                    println!("Value: {}", String::from_utf8_lossy(&value));
+                    EXIT_OK
+                },
+                Ok(Message::ReadNamedResp{ value: Some(CrdtValue::Siblings(values)) }) => {
+                    for value in values.iter() {
+                        println!("Sibling: {}", String::from_utf8_lossy(value));
+                    }
+                    EXIT_OK
+                },
+                Ok(Message::ReadNamedResp{ value: Some(CrdtValue::Counter(n)) }) => {
+                    println!("Count: {}", n);
+                    EXIT_OK
+                },
+                Ok(Message::ReadNamedResp{ value: Some(CrdtValue::Set(elements)) }) => {
+                    if elements.is_empty() {
+                        println!("Set is empty.");
+                    }
+                    for element in elements.iter() {
+                        println!("Member: {}", String::from_utf8_lossy(element));
+                    }
+                    EXIT_OK
+                },
+                Ok(Message::ReadNamedResp{ value: None }) => {
+                    println!("Name not found.");
+                    EXIT_OK
+                },
+                Ok(Message::Error{ code, detail }) => print_error("Read", code, detail),
+                Err(e) => connection_failed(e),
+                Ok(_) => {
+                    println!("Execution should not have reached this point.");
+                    EXIT_USAGE
+                }
+            }
+        },
+
+        Some("S") if s.starts_with("SUBSCRIBE") => {
+            // get the index specified after "SUBSCRIBE", and whether to include values ("SUBSCRIBE <index> VALUES")
+            let args: Vec<&str> = s.split_whitespace().skip(1).collect();
+            let from_index: usize = match args.first().map(|i| i.parse::<usize>()) {
+                Some(Ok(v)) => v,
+                _ => {
+                    println!("Please provide a valid index to start from.");
+                    return EXIT_USAGE
+                }
+            };
+            let with_values = args.get(1) == Some(&"VALUES");
+
+            // runs until the server goes away, the index printed is the one to resume from (+ 1)
+            subscribe(stream, from_index, with_values)
+        },
+
+        Some("S") if s.starts_with("STAT") => {
+            // get the key specified after "STAT"
+            let key: &str = s.split_once(' ').unwrap().1;
+
+            let key: Key = match key.trim().parse::<Key>() {
+                Ok(v) => v,
+                Err(e) => {
+                    println!("Please provide a valid key. {}", e);
+                    return EXIT_USAGE
+                }
+            };
+
+            // stat
+            match stat_req(stream, key) {
+                Ok(Message::StatResp{ meta: Some(meta) }) => {
+                    println!("Origin: {}", meta.origin);
+                    println!("Written at: {} ms since the epoch", epoch_millis(meta.written_at));
+                    println!("Received at: {} ms since the epoch", epoch_millis(meta.received_at));
+                    println!("Hops: {}", meta.hops);
+                    match meta.expires_at {
+                        Some(t) => println!("Expires at: {} ms since the epoch", epoch_millis(t)),
+                        None => println!("Expires: never"),
+                    }
+                    EXIT_OK
+                },
+                Ok(Message::StatResp{ meta: None }) => {
+                    println!("Value not found.");
+                    EXIT_OK
+                },
+                Ok(Message::Error{ code, detail }) => print_error("Stat", code, detail),
+                Err(e) => connection_failed(e),
+                Ok(_) => {
+                    println!("Execution should not have reached this point.");
+                    EXIT_USAGE
+                }
+            }
+        },
+
+        Some("S") => {
+            // get the value specified after "SELECT"
+            let (_, new_address) = s.split_once(' ').unwrap(); 
+            println!("{}", new_address);
+
+            // create a new connection
+            match connect(new_address) {
+                Ok(s) => {
+                    println!("New connection successful.");
+                    *stream = s;
+                    EXIT_OK
+                },
+                Err(_) => {
+                    println!("Invalid address format. Please use form \"SELECT <ip>:<port>\".");
+                    EXIT_CONNECTION
+                }
+            }
+        },
+
+        _ => {
+            println!("Invalid command. Please enter either \"POST <value>\", \"PUT <value>\", \"PUTEX <seconds> <value>\", \"GET <key>\", \"BATCH <value>;<value>;...\", \"MGET <key> <key> ...\", \"STAT <key>\", \"DELETE <key>\", \"SETREG <name> <value>\", \"SETMV <name> <value>\", \"INCR <name> [amount]\", \"PNADD <name> <amount>\", \"SADD <name> <element>\", \"ORADD <name> <element>\", \"ORREM <name> <element>\", \"READ <name>\", \"SUBSCRIBE <index> [VALUES]\", \"DUMP\", \"KEYS\", \"LISTCLUSTER\", or \"SELECT <ip>:<port>\".");
+            EXIT_USAGE
+        }
     }
 }
 
+// one request and its answer. any error is the connection's, the server's own come back as Message::Error
+fn request(stream: &mut TcpStream, data: Message) -> Result<Message, FrameError> {
+    send_message(stream, data)?;
+    receive_message(stream)
+}
+
 // functions unfortunately repeated here because other ones have specific ocaml return values. a refactor could be done but it wouldn't be to too much benefit as a lot of the logic in the other methods is dependent on ocaml encoding issues
-fn push_req(stream: &mut TcpStream, value: String) -> Message {
-    // make key for it
-    let mut hash = DefaultHasher::new();
-    value.to_string().hash(&mut hash);
-    let hashed: u64 = hash.finish(); 
+fn push_req(stream: &mut TcpStream, value: String) -> Result<Message, FrameError> {
+    // make key for it, values typed in are just their UTF-8 bytes. large ones go as chunks, then the manifest under that key
+    let value = value.into_bytes();
+    let pair = match chunk::split(&value) {
+        Some((chunks, manifest)) => {
+            if let Some(failed) = push_chunks_req(stream, chunks, None)? {
+                return Ok(failed);
+            }
+            manifest
+        },
+        None => KVPair {key: hash_value(&value), value: value},
+    };
 
-    let data = Message::PushReq ( KVPair {key: hashed, value: value} );
-    send_message(stream, data).unwrap();
+    let data = Message::PushReq ( pair, None );
+    request(stream, data)
+}
 
-    let result: Message = receive_message(stream).unwrap();
-    // println!("Response: {:#?}", result);
-    
-    result
+fn put_req(stream: &mut TcpStream, value: String, ttl: Option<Duration>) -> Result<Message, FrameError> {
+    // a large value is pushed as chunks and a manifest instead, answered as if it had been put
+    if let Some((chunks, manifest)) = chunk::split(value.as_bytes()) {
+        if let Some(failed) = push_chunks_req(stream, chunks, ttl)? {
+            return Ok(failed);
+        }
+        let key = manifest.key;
+        return match request(stream, Message::PushReq(manifest, ttl))? {
+            Message::PushResp{ success: true } => Ok(Message::PutResp{ key }),
+            other => Ok(other),
+        };
+    }
+
+    let data = Message::PutReq { value: value.into_bytes(), ttl };
+    request(stream, data)
 }
 
-fn get_req(stream: &mut TcpStream, key: u64) -> Message {
+fn push_batch_req(stream: &mut TcpStream, values: Vec<String>) -> Result<Message, FrameError> {
+    // make keys for them. large ones have their chunks pushed now, and their manifest goes in the batch instead
+    let mut pairs: Vec<KVPair> = Vec::with_capacity(values.len());
+    for value in values {
+        let value = value.into_bytes();
+        match chunk::split(&value) {
+            Some((chunks, manifest)) => {
+                if let Some(failed) = push_chunks_req(stream, chunks, None)? {
+                    return Ok(failed);
+                }
+                pairs.push(manifest);
+            },
+            None => pairs.push(KVPair {key: hash_value(&value), value: value}),
+        }
+    }
+
+    let data = Message::PushBatchReq(pairs, None);
+    request(stream, data)
+}
+
+fn multi_get_req(stream: &mut TcpStream, keys: Vec<Key>) -> Result<Message, FrameError> {
+    let data = Message::MultiRetrieveReq(keys.clone());
+    let result: Message = request(stream, data)?;
+
+    // put any large values back together
+    match result {
+        Message::MultiRetrieveResp(results) => {
+            let mut resolved: Vec<FoundValue> = Vec::with_capacity(results.len());
+            for (key, found) in keys.iter().zip(results) {
+                resolved.push(match found {
+                    FoundValue::Success { value } => match resolve_req(stream, key, value)? {
+                        Ok(Some(value)) => FoundValue::Success { value },
+                        Ok(None) => FoundValue::Failure,
+                        Err(failed) => return Ok(*failed),
+                    },
+                    FoundValue::Failure => FoundValue::Failure,
+                });
+            }
+            Ok(Message::MultiRetrieveResp(resolved))
+        },
+        other => Ok(other),
+    }
+}
+
+fn get_req(stream: &mut TcpStream, key: Key) -> Result<Message, FrameError> {
     let data = Message::RetrieveReq { key: key };
-    send_message(stream, data).unwrap();
-    
-    let result: Message = receive_message(stream).unwrap();
+    let result: Message = request(stream, data)?;
     
-    result
+    // put a large value back together
+    match result {
+        Message::RetrieveResp{ result: FoundValue::Success { value } } => match resolve_req(stream, &key, value)? {
+            Ok(Some(value)) => Ok(Message::RetrieveResp{ result: FoundValue::Success { value } }),
+            Ok(None) => Ok(Message::RetrieveResp{ result: FoundValue::Failure }),
+            Err(failed) => Ok(*failed),
+        },
+        other => Ok(other),
+    }
 }
 
-fn dump_req(stream: &mut TcpStream) -> Message {
-    let data = Message::DumpReq;
-    send_message(stream, data).unwrap();
-    
-    let result: Message = receive_message(stream).unwrap();
-    
-    result
+// one at a time, so no frame is much bigger than a chunk. hands back the first response that isn't a success
+fn push_chunks_req(stream: &mut TcpStream, chunks: Vec<KVPair>, ttl: Option<Duration>) -> Result<Option<Message>, FrameError> {
+    for pair in chunks {
+        match request(stream, Message::PushReq(pair, ttl))? {
+            Message::PushResp{ success: true } => (),
+            other => return Ok(Some(other)),
+        }
+    }
+    Ok(None)
+}
+
+// the value stored under key, put back together if what's stored is a manifest. None if a chunk hasn't reached this replica yet,
+// the server's answer instead if it wasn't chunks
+fn resolve_req(stream: &mut TcpStream, key: &Key, value: Vec<u8>) -> Result<Result<Option<Vec<u8>>, Box<Message>>, FrameError> {
+    let manifest = match Manifest::stored_as(key, &value) {
+        Some(m) => m,
+        None => return Ok(Ok(Some(value))),
+    };
+
+    let mut chunks: Vec<Vec<u8>> = Vec::with_capacity(manifest.chunks.len());
+    for keys in manifest.chunks.chunks(CHUNKS_PER_REQUEST) {
+        match request(stream, Message::MultiRetrieveReq(keys.to_vec()))? {
+            Message::MultiRetrieveResp(results) => for found in results {
+                match found {
+                    FoundValue::Success { value } => chunks.push(value),
+                    FoundValue::Failure => return Ok(Ok(None)),
+                }
+            },
+            other => return Ok(Err(Box::new(other))),
+        }
+    }
+
+    match manifest.reassemble(key, chunks) {
+        Ok(value) => Ok(Ok(Some(value))),
+        Err(e) => Ok(Err(Box::new(Message::Error{ code: ErrorCode::HashMismatch, detail: Some(e) }))),
+    }
+}
+
+fn delete_req(stream: &mut TcpStream, key: Key) -> Result<Message, FrameError> {
+    let data = Message::DeleteReq { key };
+    request(stream, data)
+}
+
+// sends any write to a name and reports how it went
+fn update_named(stream: &mut TcpStream, name: String, op: CrdtOp) -> i32 {
+    match update_named_req(stream, name.clone(), op) {
+        Ok(Message::UpdateNamedResp) => {
+            println!("Updated {}.", name);
+            EXIT_OK
+        },
+        Ok(Message::Error{ code, detail }) => print_error("Update", code, detail),
+        Err(e) => connection_failed(e),
+        Ok(_) => {
+            println!("Execution should not have reached this point.");
+            EXIT_USAGE
+        }
+    }
+}
+
+fn update_named_req(stream: &mut TcpStream, name: String, op: CrdtOp) -> Result<Message, FrameError> {
+    let data = Message::UpdateNamedReq { name, op };
+    request(stream, data)
 }
 
-fn cluster_req(stream: &mut TcpStream) -> Message {
+fn read_named_req(stream: &mut TcpStream, name: String) -> Result<Message, FrameError> {
+    let data = Message::ReadNamedReq { name };
+    request(stream, data)
+}
+
+fn epoch_millis(t: SystemTime) -> u128 {
+    t.duration_since(UNIX_EPOCH).unwrap_or_default().as_millis()
+}
+
+fn stat_req(stream: &mut TcpStream, key: Key) -> Result<Message, FrameError> {
+    let data = Message::StatReq { key };
+    request(stream, data)
+}
+
+fn subscribe(stream: &mut TcpStream, from_index: usize, with_values: bool) -> i32 {
+    let data = Message::SubscribeReq { from_index, with_values };
+    if let Err(e) = send_message(stream, data) {
+        return connection_failed(e);
+    }
+
+    loop {
+        match receive_message(stream) {
+            Ok(Message::SubscribeEvent { index, item, deleted: true }) => println!("{} {} deleted", index, item.key),
+            Ok(Message::SubscribeEvent { index, item, deleted: false }) => match item.value {
+                Some(value) => println!("{} {} -> {}", index, item.key, String::from_utf8_lossy(&value)),
+                None => println!("{} {}", index, item.key),
+            },
+            Ok(Message::Error{ code, detail }) => return print_error("Subscribe", code, detail),
+            Ok(_) => {
+                println!("Execution should not have reached this point.");
+                return EXIT_USAGE;
+            },
+            Err(e) => {
+                eprintln!("Subscription ended with error: {}", e);
+                return EXIT_CONNECTION;
+            }
+        }
+    }
+}
+
+fn scan(stream: &mut TcpStream, keys_only: bool) -> i32 {
+    let mut cursor: usize = 0;
+
+    loop {
+        match scan_req(stream, cursor, keys_only) {
+            Ok(Message::ScanResp { items, next_cursor }) => {
+                for item in items {
+                    match item.value {
+                        Some(value) => println!("{} -> {}", item.key, String::from_utf8_lossy(&value)),
+                        None => println!("{}", item.key),
+                    }
+                }
+                match next_cursor {
+                    Some(next) => cursor = next,
+                    None => return EXIT_OK,
+                }
+            },
+            Ok(Message::Error{ code, detail }) => return print_error("Scan", code, detail),
+            Err(e) => return connection_failed(e),
+            Ok(_) => {
+                println!("Execution should not have reached this point.");
+                return EXIT_USAGE;
+            }
+        }
+    }
+}
+
+fn scan_req(stream: &mut TcpStream, cursor: usize, keys_only: bool) -> Result<Message, FrameError> {
+    let data = Message::ScanReq { cursor, limit: SCAN_PAGE_SIZE, keys_only };
+    request(stream, data)
+}
+
+fn cluster_req(stream: &mut TcpStream) -> Result<Message, FrameError> {
     let data = Message::ClusterReq;
-    send_message(stream, data).unwrap();
-    
-    let result: Message = receive_message(stream).unwrap();
-    
-    result
+    request(stream, data)
 }
\ No newline at end of file
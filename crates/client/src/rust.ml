@@ -5,8 +5,57 @@ open! Bigarray
 (* file: lib.rs *)
 
 type db
-type lookup = Present of string | NotPresent
+type lookup = Present of bytes | NotPresent
+type push_outcome = Accepted | Duplicate | HashMismatch | Collision | Deleted
+type stat = {origin: string; written_at: float; received_at: float; hops: int; expires_at: float option}
+type named = Register of bytes | Siblings of bytes array | Counter of int | Set of bytes array
+type event = {index: int; key: string; value: bytes option; deleted: bool}
 external init: string -> int -> db = "init"
-external push: db -> string -> unit = "push"
+external push: db -> bytes -> unit = "push"
+external put: db -> bytes -> string = "put"
+external put_expiring: db -> bytes -> float -> string = "put_expiring"
+external push_batch: db -> bytes array -> push_outcome array = "push_batch"
+external get_many: db -> string array -> lookup array = "get_many"
 external get: db -> string -> lookup = "get"
+external stat: db -> string -> stat option = "stat"
+external delete: db -> string -> bool = "delete"
+external set_register: db -> string -> bytes -> unit = "set_register"
+external set_mv_register: db -> string -> bytes -> unit = "set_mv_register"
+external increment: db -> string -> int -> unit = "increment"
+external add_to_counter: db -> string -> int -> unit = "add_to_counter"
+external add_to_gset: db -> string -> bytes -> unit = "add_to_gset"
+external add_to_orset: db -> string -> bytes -> unit = "add_to_orset"
+external remove_from_orset: db -> string -> bytes -> unit = "remove_from_orset"
+external read_named: db -> string -> named option = "read_named"
+external subscribe: db -> int -> bool -> unit = "subscribe"
+external next_event: db -> event = "next_event"
 external dump: db -> 'keyval array = "dump"
+external keys: db -> string array = "keys"
+
+(* errors raised by the functions above, each with a description. see remote_error in lib.rs *)
+exception Hash_mismatch of string
+exception Frame_too_large of string
+exception Unsupported_version of string
+exception Overloaded of string
+exception Unknown_message of string
+exception Invalid_request of string
+exception Storage_failure of string
+exception Internal_error of string
+exception Key_collision of string
+exception Key_deleted of string
+exception Connection_error of string
+exception Protocol_error of string
+
+let () =
+  Callback.register_exception "secko_hash_mismatch" (Hash_mismatch "");
+  Callback.register_exception "secko_frame_too_large" (Frame_too_large "");
+  Callback.register_exception "secko_unsupported_version" (Unsupported_version "");
+  Callback.register_exception "secko_overloaded" (Overloaded "");
+  Callback.register_exception "secko_unknown_message" (Unknown_message "");
+  Callback.register_exception "secko_invalid_request" (Invalid_request "");
+  Callback.register_exception "secko_storage_failure" (Storage_failure "");
+  Callback.register_exception "secko_internal_error" (Internal_error "");
+  Callback.register_exception "secko_key_collision" (Key_collision "");
+  Callback.register_exception "secko_key_deleted" (Key_deleted "");
+  Callback.register_exception "secko_connection_error" (Connection_error "");
+  Callback.register_exception "secko_protocol_error" (Protocol_error "")
@@ -1,16 +1,18 @@
 (* open Secko_client *)
 
 let db = Secko_client.Rust.init "127.0.0.1" 6359 in
-  let _ = Secko_client.Rust.push db "sample value" in
+  let _ = Secko_client.Rust.push db (Bytes.of_string "sample value") in
+  let key = Secko_client.Rust.put db (Bytes.of_string "put value") in
+    Printf.printf("\n%s\n") key;
   (* let retrieve = Secko_client.Rust.get db "abc" in
-    Printf.printf("\n%s") retrieve; *) (* throws error bc not u64 *)
-  let retrieve = Secko_client.Rust.get db "25" in
+    Printf.printf("\n%s") retrieve; *) (* throws error bc not a key *)
+  let retrieve = Secko_client.Rust.get db "sha256:318de017a845687221ece7813c25d086e19496d5860d2b1c3cb910bb386b3a6d" in
     match retrieve with 
-    | Present(s) -> Printf.printf("\n%s\n") s;
+    | Present(s) -> Printf.printf("\n%s\n") (Bytes.to_string s);
     | NotPresent -> Printf.printf("\nNot Present\n"); (* yep *)
-  let retrieve = Secko_client.Rust.get db "18380983780737452339" in
+  let retrieve = Secko_client.Rust.get db "sha256:a109b9672d2b2083b88c0fa33b1948b1f976f1a7e2c4407b784a2bb458f498a7" in
     match retrieve with 
-    | Present(s) -> Printf.printf("\n%s\n") s; (* yep *)
+    | Present(s) -> Printf.printf("\n%s\n") (Bytes.to_string s); (* yep *)
     | NotPresent -> Printf.printf("\nNot Present\n");
   let dumped = Secko_client.Rust.dump db in
-    Array.iter (fun (a, b) -> Printf.printf "\n%s %s\n" a b) dumped;
+    Array.iter (fun (a, b) -> Printf.printf "\n%s %s\n" a (Bytes.to_string b)) dumped;
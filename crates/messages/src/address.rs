@@ -0,0 +1,249 @@
+// content addressing. a key is the hash of the value it points at, tagged with the algorithm that produced it so a server can
+// check any key it is handed without guessing, and so another algorithm can be added later without old keys changing meaning.
+// the hash has to come out the same on every build, which rules out DefaultHasher (it is allowed to change between rust releases)
+use serde::{Serialize, Deserialize};
+use std::str::FromStr;
+
+pub const DIGEST_LEN: usize = 32;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum HashAlgorithm {
+    Sha256,
+    Name, // sha256 of a name, for named keys. a different tag, so no value's key is ever one, and no value matches one
+}
+
+// what new keys are made with
+pub const DEFAULT_ALGORITHM: HashAlgorithm = HashAlgorithm::Sha256;
+
+impl HashAlgorithm {
+    pub fn name(&self) -> &'static str {
+        match self {
+            HashAlgorithm::Sha256 => "sha256",
+            HashAlgorithm::Name => "name",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<HashAlgorithm> {
+        match name {
+            "sha256" => Some(HashAlgorithm::Sha256),
+            "name" => Some(HashAlgorithm::Name),
+            _ => None,
+        }
+    }
+
+    pub fn digest(&self, value: &[u8]) -> [u8; DIGEST_LEN] {
+        match self {
+            HashAlgorithm::Sha256 | HashAlgorithm::Name => sha256(value),
+        }
+    }
+}
+
+impl std::fmt::Display for HashAlgorithm {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+// algorithm tag + digest. written out as "<algorithm>:<hex digest>", e.g. "sha256:2cf24dba..."
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Key {
+    algorithm: HashAlgorithm,
+    digest: [u8; DIGEST_LEN],
+}
+
+impl Key {
+    pub fn new(algorithm: HashAlgorithm, digest: [u8; DIGEST_LEN]) -> Key {
+        Key { algorithm, digest }
+    }
+
+    // the key for value under a given algorithm
+    pub fn of(algorithm: HashAlgorithm, value: &[u8]) -> Key {
+        Key { algorithm, digest: algorithm.digest(value) }
+    }
+
+    pub fn algorithm(&self) -> HashAlgorithm {
+        self.algorithm
+    }
+
+    pub fn digest(&self) -> &[u8; DIGEST_LEN] {
+        &self.digest
+    }
+
+    // whether this is the key of value, using whichever algorithm the key says it was made with
+    pub fn matches(&self, value: &[u8]) -> bool {
+        self.algorithm != HashAlgorithm::Name && self.algorithm.digest(value) == self.digest
+    }
+}
+
+// keys are the hash of the value's bytes
+pub fn hash_value(value: &[u8]) -> Key {
+    Key::of(DEFAULT_ALGORITHM, value)
+}
+
+impl std::fmt::Display for Key {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}:", self.algorithm)?;
+        for b in self.digest.iter() {
+            write!(f, "{:02x}", b)?;
+        }
+        Ok(())
+    }
+}
+
+// the derived one prints the digest as a list of numbers, which is useless in logs
+impl std::fmt::Debug for Key {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self)
+    }
+}
+
+impl FromStr for Key {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Key, String> {
+        let (name, hex) = match s.split_once(':') {
+            Some(v) => v,
+            None => return Err(format!("Key {} is missing its algorithm, expected <algorithm>:<hex digest>", s)),
+        };
+
+        let algorithm = match HashAlgorithm::from_name(name) {
+            Some(a) => a,
+            None => return Err(format!("Unknown hash algorithm {}", name)),
+        };
+
+        if hex.len() != DIGEST_LEN * 2 || !hex.is_ascii() {
+            return Err(format!("Digest should be {} hex characters, got {}", DIGEST_LEN * 2, hex.len()));
+        }
+
+        let mut digest = [0u8; DIGEST_LEN];
+        for (i, b) in digest.iter_mut().enumerate() {
+            *b = match u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16) {
+                Ok(v) => v,
+                Err(_) => return Err(format!("Digest {} is not valid hex", hex)),
+            };
+        }
+
+        Ok(Key { algorithm, digest })
+    }
+}
+
+// SHA-256 as in FIPS 180-4. written out here rather than pulled in as a dependency, it's small and this is the one thing
+// every client and server must agree on byte for byte
+const K: [u32; 64] = [
+    0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4, 0xab1c5ed5,
+    0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174,
+    0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da,
+    0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7, 0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967,
+    0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85,
+    0xa2bfe8a1, 0xa81a664b, 0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070,
+    0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+    0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2,
+];
+
+const H0: [u32; 8] = [
+    0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab, 0x5be0cd19,
+];
+
+pub fn sha256(data: &[u8]) -> [u8; DIGEST_LEN] {
+    let mut state = H0;
+
+    // whole blocks straight from the input, then the tail with the padding (a 1 bit, zeros, and the length in bits)
+    let mut blocks = data.chunks_exact(64);
+    for block in &mut blocks {
+        compress(&mut state, block);
+    }
+
+    let rest = blocks.remainder();
+    let mut tail = [0u8; 128];
+    tail[..rest.len()].copy_from_slice(rest);
+    tail[rest.len()] = 0x80;
+    let tail_len = if rest.len() < 56 { 64 } else { 128 };
+    tail[tail_len - 8..tail_len].copy_from_slice(&((data.len() as u64) * 8).to_be_bytes());
+    for block in tail[..tail_len].chunks_exact(64) {
+        compress(&mut state, block);
+    }
+
+    let mut digest = [0u8; DIGEST_LEN];
+    for (i, word) in state.iter().enumerate() {
+        digest[i * 4..i * 4 + 4].copy_from_slice(&word.to_be_bytes());
+    }
+    digest
+}
+
+fn compress(state: &mut [u32; 8], block: &[u8]) {
+    let mut w = [0u32; 64];
+    for i in 0..16 {
+        w[i] = u32::from_be_bytes(block[i * 4..i * 4 + 4].try_into().unwrap());
+    }
+    for i in 16..64 {
+        let s0 = w[i - 15].rotate_right(7) ^ w[i - 15].rotate_right(18) ^ (w[i - 15] >> 3);
+        let s1 = w[i - 2].rotate_right(17) ^ w[i - 2].rotate_right(19) ^ (w[i - 2] >> 10);
+        w[i] = w[i - 16].wrapping_add(s0).wrapping_add(w[i - 7]).wrapping_add(s1);
+    }
+
+    let [mut a, mut b, mut c, mut d, mut e, mut f, mut g, mut h] = *state;
+    for i in 0..64 {
+        let s1 = e.rotate_right(6) ^ e.rotate_right(11) ^ e.rotate_right(25);
+        let ch = (e & f) ^ (!e & g);
+        let t1 = h.wrapping_add(s1).wrapping_add(ch).wrapping_add(K[i]).wrapping_add(w[i]);
+        let s0 = a.rotate_right(2) ^ a.rotate_right(13) ^ a.rotate_right(22);
+        let maj = (a & b) ^ (a & c) ^ (b & c);
+        let t2 = s0.wrapping_add(maj);
+
+        h = g;
+        g = f;
+        f = e;
+        e = d.wrapping_add(t1);
+        d = c;
+        c = b;
+        b = a;
+        a = t1.wrapping_add(t2);
+    }
+
+    for (s, v) in state.iter_mut().zip([a, b, c, d, e, f, g, h]) {
+        *s = s.wrapping_add(v);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(digest: &[u8]) -> String {
+        digest.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    // the examples from FIPS 180-4
+    #[test]
+    fn sha256_matches_the_standard_vectors() {
+        assert_eq!(hex(&sha256(b"")), "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855");
+        assert_eq!(hex(&sha256(b"abc")), "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+        assert_eq!(hex(&sha256(b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq")), "248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1"); // 448 bits, two blocks
+        assert_eq!(hex(&sha256(&vec![b'a'; 1_000_000])), "cdc76e5c9914fb9281a1c7e284d73e67f1809a48a497200e046d39ccc7112cd0");
+    }
+
+    // 55 bytes is the most that leaves room for the padding in the same block, 56 pushes the length into another, 64 fills one
+    // exactly
+    #[test]
+    fn sha256_pads_at_the_block_edges() {
+        for (n, digest) in [
+            (55, "d5e285683cd4efc02d021a5c62014694958901005d6f71e89e0989fac77e4072"),
+            (56, "04c26261370ee7541549d16dee320c723e3fd14671e66a099afe0a377c16888e"),
+            (63, "75220b47218278e656f2013bb8f0c455a25eaf01e86c64924e9d48d89776d6f2"),
+            (64, "7ce100971f64e7001e8fe5a51973ecdfe1ced42befe7ee8d5fd6219506b5393c"),
+            (119, "000b48d4edf0fa7bee3c6236ecd2785baa5db4eeb8bb54341b029e0d9fa5fb0c"),
+            (120, "13f05a0b594787f5ecd315edc96141bd3243203d1b7d4f0836f37308b276ba98"),
+        ] {
+            assert_eq!(hex(&sha256(&vec![b'x'; n])), digest, "{} bytes", n);
+        }
+    }
+
+    #[test]
+    fn keys_round_trip_through_their_text_form() {
+        let key = hash_value(b"hello world");
+        assert_eq!(key.to_string(), "sha256:b94d27b9934d3e08a52e52d7da7dabfac484efe37a5380ee9088f7ace2efcde9");
+        assert_eq!(key.to_string().parse::<Key>().unwrap(), key);
+        assert!(key.matches(b"hello world"));
+        assert!(!key.matches(b"hello world!"));
+    }
+}
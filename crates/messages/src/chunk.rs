@@ -0,0 +1,81 @@
+// large values are stored as chunks, each under its own content address, plus a manifest listing them in order. the manifest is
+// stored under the key of the whole value, so to a client nothing changes: it hashes the value as always and gets the value back
+// from that key. the manifest's bytes don't hash to that key, which is how it's told apart from a value that just looks like one.
+// deleting the value deletes the manifest and every chunk no other manifest lists, and those deletes spread like any other
+use serde::{Serialize, Deserialize};
+use bincode::{serialize, deserialize};
+
+use crate::{Key, KVPair, hash_value};
+
+// values longer than this are split, and no chunk is longer
+pub const CHUNK_SIZE: usize = 1024 * 1024;
+
+// starts every manifest, ahead of the bincode
+const MANIFEST_MAGIC: &[u8; 8] = b"SEKOMNF1";
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Manifest {
+    pub len: u64, // of the whole value
+    pub chunks: Vec<Key>,
+}
+
+impl Manifest {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = MANIFEST_MAGIC.to_vec();
+        bytes.extend(serialize(self).unwrap());
+        bytes
+    }
+
+    // None for anything that isn't a manifest
+    pub fn from_bytes(bytes: &[u8]) -> Option<Manifest> {
+        match bytes.strip_prefix(MANIFEST_MAGIC) {
+            Some(rest) => deserialize(rest).ok(),
+            None => None,
+        }
+    }
+
+    // the manifest stored under key, if value is one. a value that hashes to its key is always just a value
+    pub fn stored_as(key: &Key, value: &[u8]) -> Option<Manifest> {
+        if key.matches(value) {
+            return None;
+        }
+        Manifest::from_bytes(value)
+    }
+
+    // puts the value back together from its chunks, in order. fails unless every chunk and the whole value hash to what they should
+    pub fn reassemble(&self, key: &Key, chunks: Vec<Vec<u8>>) -> Result<Vec<u8>, String> {
+        if chunks.len() != self.chunks.len() {
+            return Err(format!("Expected {} chunks, got {}.", self.chunks.len(), chunks.len()));
+        }
+
+        // len comes from whoever pushed the manifest, so it's only taken once the chunks we hold bear it out
+        let total: u64 = chunks.iter().map(|chunk| chunk.len() as u64).sum();
+        if total != self.len {
+            return Err(format!("Chunks add up to {} bytes, the manifest says {}.", total, self.len));
+        }
+
+        let mut value: Vec<u8> = Vec::with_capacity(total as usize);
+        for (chunk_key, chunk) in self.chunks.iter().zip(chunks) {
+            if !chunk_key.matches(&chunk) {
+                return Err(format!("Chunk {} doesn't match its key.", chunk_key));
+            }
+            value.extend(chunk);
+        }
+
+        if value.len() as u64 != self.len || !key.matches(&value) {
+            return Err(format!("Chunks don't add up to the value stored under {}.", key));
+        }
+        Ok(value)
+    }
+}
+
+// the chunks to store for a value, then the manifest, under the value's own key. None if the value is small enough to go whole
+pub fn split(value: &[u8]) -> Option<(Vec<KVPair>, KVPair)> {
+    if value.len() <= CHUNK_SIZE {
+        return None;
+    }
+
+    let chunks: Vec<KVPair> = value.chunks(CHUNK_SIZE).map(|chunk| KVPair { key: hash_value(chunk), value: chunk.to_vec() }).collect();
+    let manifest = Manifest { len: value.len() as u64, chunks: chunks.iter().map(|pair| pair.key).collect() };
+    Some((chunks, KVPair { key: hash_value(value), value: manifest.to_bytes() }))
+}
@@ -0,0 +1,534 @@
+// named, mutable keys. content addressed values can't change, so anything that has to (the current pointer to a config blob, a
+// counter, a membership set) lives under a name instead, as a CRDT: replicas apply writes locally and merge whole states when antientropy brings them over,
+// and every replica ends up with the same thing whatever order the merges happen in
+use serde::{Serialize, Deserialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::{Key, ReplicaId, HashAlgorithm};
+
+// where a name lives in the replica lists. tagged as a name's, so it can't be mistaken for the key of the value with the same bytes
+pub fn name_key(name: &str) -> Key {
+    Key::of(HashAlgorithm::Name, name.as_bytes())
+}
+
+// hybrid logical clock timestamp. wall clock time when the clocks are sane, with a counter to keep order when they aren't (two
+// writes in the same microsecond, or a replica whose clock is behind one it has heard from). the replica breaks any remaining tie
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub struct Hlc {
+    pub wall: u64, // microseconds since the epoch
+    pub logical: u32,
+    pub node: ReplicaId,
+}
+
+impl std::fmt::Display for Hlc {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}.{}@{}", self.wall, self.logical, self.node)
+    }
+}
+
+// hands out timestamps for one replica, each later than anything it has handed out or seen
+#[derive(Debug)]
+pub struct Clock {
+    node: ReplicaId,
+    last: Mutex<(u64, u32)>,
+}
+
+impl Clock {
+    pub fn new(node: ReplicaId) -> Clock {
+        Clock { node, last: Mutex::new((0, 0)) }
+    }
+
+    fn physical() -> u64 {
+        SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_micros() as u64
+    }
+
+    // for a local write
+    pub fn now(&self) -> Hlc {
+        let mut last = self.last.lock().unwrap();
+        let wall = last.0.max(Clock::physical());
+        let logical = if wall == last.0 { last.1 + 1 } else { 0 };
+        *last = (wall, logical);
+        Hlc { wall, logical, node: self.node }
+    }
+
+    // for a timestamp that came from another replica, so that our next one comes after it
+    pub fn observe(&self, seen: &Hlc) {
+        let mut last = self.last.lock().unwrap();
+        let wall = last.0.max(seen.wall).max(Clock::physical());
+        let logical = if wall == last.0 && wall == seen.wall {
+            last.1.max(seen.logical) + 1
+        } else if wall == last.0 {
+            last.1 + 1
+        } else if wall == seen.wall {
+            seen.logical + 1
+        } else {
+            0
+        };
+        *last = (wall, logical);
+    }
+}
+
+// last writer wins. concurrent writes are settled by timestamp, so one of them is silently lost
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct LwwRegister {
+    pub value: Vec<u8>,
+    pub stamp: Hlc,
+}
+
+impl LwwRegister {
+    pub fn set(&mut self, value: Vec<u8>, stamp: Hlc) {
+        if stamp > self.stamp {
+            *self = LwwRegister { value, stamp };
+        }
+    }
+
+    pub fn merge(&mut self, other: &LwwRegister) -> bool {
+        if other.stamp > self.stamp {
+            *self = other.clone();
+            return true;
+        }
+        false
+    }
+}
+
+// how many writes from each replica a value has seen
+pub type VersionVector = BTreeMap<ReplicaId, u64>;
+
+// whether a has seen strictly less than b
+fn happened_before(a: &VersionVector, b: &VersionVector) -> bool {
+    a != b && a.iter().all(|(node, n)| b.get(node).is_some_and(|m| n <= m))
+}
+
+// multi-value register. a write replaces everything the writing replica had seen, writes that didn't see each other are both
+// kept as siblings for the reader to pick from (and settle with their next write)
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct MvRegister {
+    pub siblings: Vec<(Vec<u8>, VersionVector)>, // sorted by version, so equal states compare equal
+}
+
+impl MvRegister {
+    pub fn set(&mut self, value: Vec<u8>, node: ReplicaId) {
+        let mut version = VersionVector::new();
+        for (_, seen) in self.siblings.iter() {
+            for (n, count) in seen.iter() {
+                let held = version.entry(*n).or_insert(0);
+                *held = (*held).max(*count);
+            }
+        }
+        *version.entry(node).or_insert(0) += 1;
+        self.siblings = vec![(value, version)];
+    }
+
+    pub fn merge(&mut self, other: &MvRegister) -> bool {
+        let mut all: Vec<(Vec<u8>, VersionVector)> = self.siblings.iter().chain(other.siblings.iter()).cloned().collect();
+        all.sort_by(|a, b| a.1.cmp(&b.1));
+        all.dedup_by(|a, b| a.1 == b.1);
+
+        // keep whatever nothing else has seen past
+        let merged: Vec<(Vec<u8>, VersionVector)> = all.iter()
+            .filter(|(_, version)| !all.iter().any(|(_, other)| happened_before(version, other)))
+            .cloned()
+            .collect();
+
+        if merged == self.siblings {
+            return false;
+        }
+        self.siblings = merged;
+        true
+    }
+}
+
+// grow-only counter. each replica only ever bumps its own count, so merging is the larger of each
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct GCounter {
+    pub counts: BTreeMap<ReplicaId, u64>,
+}
+
+impl GCounter {
+    pub fn increment(&mut self, by: u64, node: ReplicaId) {
+        let count = self.counts.entry(node).or_insert(0);
+        *count = count.saturating_add(by);
+    }
+
+    pub fn merge(&mut self, other: &GCounter) -> bool {
+        let mut changed = false;
+        for (node, theirs) in other.counts.iter() {
+            let ours = self.counts.entry(*node).or_insert(0);
+            if theirs > ours {
+                *ours = *theirs;
+                changed = true;
+            }
+        }
+        changed
+    }
+
+    pub fn total(&self) -> u64 {
+        self.counts.values().fold(0, |sum, n| sum.saturating_add(*n))
+    }
+}
+
+// counter that can go down too, as one grow-only counter of increments and one of decrements
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct PnCounter {
+    pub inc: GCounter,
+    pub dec: GCounter,
+}
+
+impl PnCounter {
+    pub fn add(&mut self, by: i64, node: ReplicaId) {
+        if by >= 0 {
+            self.inc.increment(by as u64, node);
+        } else {
+            self.dec.increment(by.unsigned_abs(), node);
+        }
+    }
+
+    pub fn merge(&mut self, other: &PnCounter) -> bool {
+        // both halves, no short circuit
+        let inc = self.inc.merge(&other.inc);
+        let dec = self.dec.merge(&other.dec);
+        inc || dec
+    }
+
+    pub fn total(&self) -> i64 {
+        (self.inc.total() as i128 - self.dec.total() as i128).clamp(i64::MIN as i128, i64::MAX as i128) as i64
+    }
+}
+
+// grow-only set. nothing ever leaves, merging is the union
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct GSet {
+    pub elements: BTreeSet<Vec<u8>>,
+}
+
+impl GSet {
+    pub fn add(&mut self, element: Vec<u8>) {
+        self.elements.insert(element);
+    }
+
+    pub fn merge(&mut self, other: &GSet) -> bool {
+        let before = self.elements.len();
+        self.elements.extend(other.elements.iter().cloned());
+        self.elements.len() != before
+    }
+}
+
+// observed-remove set. every add is tagged with the timestamp it was made at, and a remove only takes out the tags the removing
+// replica had seen, so an add that raced a remove survives it. removed tags are kept so a merge can't bring them back
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct OrSet {
+    pub adds: BTreeMap<Vec<u8>, BTreeSet<Hlc>>,
+    pub removed: BTreeSet<Hlc>,
+}
+
+impl OrSet {
+    pub fn add(&mut self, element: Vec<u8>, stamp: Hlc) {
+        self.adds.entry(element).or_default().insert(stamp);
+    }
+
+    pub fn remove(&mut self, element: &[u8]) {
+        if let Some(tags) = self.adds.remove(element) {
+            self.removed.extend(tags);
+        }
+    }
+
+    pub fn merge(&mut self, other: &OrSet) -> bool {
+        let before = self.clone();
+        self.removed.extend(other.removed.iter().copied());
+        for (element, tags) in other.adds.iter() {
+            self.adds.entry(element.clone()).or_default().extend(tags.iter().copied());
+        }
+
+        // drop whatever either side had removed, and elements left with no tags
+        let removed = &self.removed;
+        self.adds.retain(|_, tags| {
+            tags.retain(|tag| !removed.contains(tag));
+            !tags.is_empty()
+        });
+        *self != before
+    }
+
+    pub fn elements(&self) -> Vec<Vec<u8>> {
+        self.adds.keys().cloned().collect()
+    }
+}
+
+// the state behind a name. a name keeps the type it was first written with
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum Crdt {
+    Lww(LwwRegister),
+    Mv(MvRegister),
+    GCounter(GCounter),
+    PnCounter(PnCounter),
+    GSet(GSet),
+    OrSet(OrSet),
+}
+
+// a write to a name, as a client asks for it
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum CrdtOp {
+    SetLww(Vec<u8>),
+    SetMv(Vec<u8>),
+    Increment(u64), // grow-only counter
+    Add(i64), // pn counter, negative to decrement
+    AddG(Vec<u8>), // grow-only set
+    AddOr(Vec<u8>),
+    RemoveOr(Vec<u8>),
+}
+
+// what reading a name gives back
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum CrdtValue {
+    Register(Vec<u8>),
+    Siblings(Vec<Vec<u8>>),
+    Counter(i64),
+    Set(Vec<Vec<u8>>), // sorted
+}
+
+impl CrdtOp {
+    // the type it writes to
+    pub fn kind(&self) -> &'static str {
+        match self {
+            CrdtOp::SetLww(_) => "lww register",
+            CrdtOp::SetMv(_) => "multi-value register",
+            CrdtOp::Increment(_) => "grow-only counter",
+            CrdtOp::Add(_) => "pn counter",
+            CrdtOp::AddG(_) => "grow-only set",
+            CrdtOp::AddOr(_) | CrdtOp::RemoveOr(_) => "or-set",
+        }
+    }
+}
+
+impl Crdt {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Crdt::Lww(_) => "lww register",
+            Crdt::Mv(_) => "multi-value register",
+            Crdt::GCounter(_) => "grow-only counter",
+            Crdt::PnCounter(_) => "pn counter",
+            Crdt::GSet(_) => "grow-only set",
+            Crdt::OrSet(_) => "or-set",
+        }
+    }
+
+    // a new one holding just op
+    pub fn from_op(op: &CrdtOp, node: ReplicaId, stamp: Hlc) -> Crdt {
+        let mut crdt = match op {
+            CrdtOp::SetLww(value) => return Crdt::Lww(LwwRegister { value: value.clone(), stamp }),
+            CrdtOp::SetMv(_) => Crdt::Mv(MvRegister { siblings: Vec::new() }),
+            CrdtOp::Increment(_) => Crdt::GCounter(GCounter::default()),
+            CrdtOp::Add(_) => Crdt::PnCounter(PnCounter::default()),
+            CrdtOp::AddG(_) => Crdt::GSet(GSet::default()),
+            CrdtOp::AddOr(_) | CrdtOp::RemoveOr(_) => Crdt::OrSet(OrSet::default()),
+        };
+        crdt.apply(op, node, stamp).unwrap(); // same type, made just above
+        crdt
+    }
+
+    // fails if op is for another type, leaving this as it was
+    pub fn apply(&mut self, op: &CrdtOp, node: ReplicaId, stamp: Hlc) -> Result<(), String> {
+        match (self, op) {
+            (Crdt::Lww(reg), CrdtOp::SetLww(value)) => reg.set(value.clone(), stamp),
+            (Crdt::Mv(reg), CrdtOp::SetMv(value)) => reg.set(value.clone(), node),
+            (Crdt::GCounter(counter), CrdtOp::Increment(by)) => counter.increment(*by, node),
+            (Crdt::PnCounter(counter), CrdtOp::Add(by)) => counter.add(*by, node),
+            (Crdt::GSet(set), CrdtOp::AddG(element)) => set.add(element.clone()),
+            (Crdt::OrSet(set), CrdtOp::AddOr(element)) => set.add(element.clone(), stamp),
+            (Crdt::OrSet(set), CrdtOp::RemoveOr(element)) => set.remove(element),
+            (held, op) => return Err(format!("Can't use a {} write on a name holding a {}.", op.kind(), held.kind())),
+        }
+        Ok(())
+    }
+
+    // true if anything changed. fails if other is another type, leaving this as it was
+    pub fn merge(&mut self, other: &Crdt) -> Result<bool, String> {
+        match (self, other) {
+            (Crdt::Lww(a), Crdt::Lww(b)) => Ok(a.merge(b)),
+            (Crdt::Mv(a), Crdt::Mv(b)) => Ok(a.merge(b)),
+            (Crdt::GCounter(a), Crdt::GCounter(b)) => Ok(a.merge(b)),
+            (Crdt::PnCounter(a), Crdt::PnCounter(b)) => Ok(a.merge(b)),
+            (Crdt::GSet(a), Crdt::GSet(b)) => Ok(a.merge(b)),
+            (Crdt::OrSet(a), Crdt::OrSet(b)) => Ok(a.merge(b)),
+            (held, other) => Err(format!("Can't merge a {} into a {}.", other.kind(), held.kind())),
+        }
+    }
+
+    pub fn value(&self) -> CrdtValue {
+        match self {
+            Crdt::Lww(reg) => CrdtValue::Register(reg.value.clone()),
+            Crdt::Mv(reg) => CrdtValue::Siblings(reg.siblings.iter().map(|(value, _)| value.clone()).collect()),
+            Crdt::GCounter(counter) => CrdtValue::Counter(i64::try_from(counter.total()).unwrap_or(i64::MAX)),
+            Crdt::PnCounter(counter) => CrdtValue::Counter(counter.total()),
+            Crdt::GSet(set) => CrdtValue::Set(set.elements.iter().cloned().collect()),
+            Crdt::OrSet(set) => CrdtValue::Set(set.elements()),
+        }
+    }
+
+    // latest timestamp in here, for keeping our clock ahead of it
+    pub fn stamp(&self) -> Option<Hlc> {
+        match self {
+            Crdt::Lww(reg) => Some(reg.stamp),
+            Crdt::OrSet(set) => set.adds.values().flatten().chain(set.removed.iter()).max().copied(),
+            _ => None,
+        }
+    }
+}
+
+impl std::fmt::Display for CrdtValue {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            CrdtValue::Register(value) => write!(f, "CrdtValue::Register({})", String::from_utf8_lossy(value))?,
+            CrdtValue::Siblings(values) => {
+                let values: Vec<_> = values.iter().map(|v| String::from_utf8_lossy(v)).collect();
+                write!(f, "CrdtValue::Siblings({:?})", values)?
+            },
+            CrdtValue::Counter(n) => write!(f, "CrdtValue::Counter({})", n)?,
+            CrdtValue::Set(elements) => {
+                let elements: Vec<_> = elements.iter().map(|e| String::from_utf8_lossy(e)).collect();
+                write!(f, "CrdtValue::Set({:?})", elements)?
+            },
+        };
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stamp(wall: u64, node: ReplicaId) -> Hlc {
+        Hlc { wall, logical: 0, node }
+    }
+
+    fn merged(a: &Crdt, b: &Crdt) -> Crdt {
+        let mut out = a.clone();
+        out.merge(b).unwrap();
+        out
+    }
+
+    // the three laws a merge has to keep for replicas to end up the same whatever order antientropy runs in
+    fn check_laws(a: Crdt, b: Crdt, c: Crdt) {
+        assert_eq!(merged(&a, &b), merged(&b, &a), "not commutative");
+        assert_eq!(merged(&merged(&a, &b), &c), merged(&a, &merged(&b, &c)), "not associative");
+        for state in [&a, &b, &c, &merged(&a, &b)] {
+            let mut again = state.clone();
+            assert!(!again.merge(state).unwrap(), "merging with itself changed something");
+            assert_eq!(&again, state, "not idempotent");
+        }
+    }
+
+    // three replicas that each did their own thing to one name, starting from a shared write
+    fn diverged(ops: [&[CrdtOp]; 3]) -> (Crdt, Crdt, Crdt) {
+        let base = Crdt::from_op(&ops[0][0], 1, stamp(1, 1));
+        let mut states = Vec::new();
+        for (i, replica_ops) in ops.iter().enumerate() {
+            let node = i as ReplicaId + 1;
+            let mut state = base.clone();
+            for (n, op) in replica_ops.iter().enumerate().skip(if i == 0 { 1 } else { 0 }) {
+                state.apply(op, node, stamp(10 + n as u64, node)).unwrap();
+            }
+            states.push(state);
+        }
+        let c = states.pop().unwrap();
+        let b = states.pop().unwrap();
+        let a = states.pop().unwrap();
+        (a, b, c)
+    }
+
+    #[test]
+    fn lww_merge_laws() {
+        let (a, b, c) = diverged([
+            &[CrdtOp::SetLww(b"a".to_vec()), CrdtOp::SetLww(b"a2".to_vec())],
+            &[CrdtOp::SetLww(b"b".to_vec())],
+            &[CrdtOp::SetLww(b"c".to_vec()), CrdtOp::SetLww(b"c2".to_vec())],
+        ]);
+        check_laws(a, b, c);
+    }
+
+    #[test]
+    fn mv_merge_laws() {
+        let (a, b, c) = diverged([
+            &[CrdtOp::SetMv(b"a".to_vec()), CrdtOp::SetMv(b"a2".to_vec())],
+            &[CrdtOp::SetMv(b"b".to_vec())],
+            &[CrdtOp::SetMv(b"c".to_vec()), CrdtOp::SetMv(b"c2".to_vec())],
+        ]);
+        // none of the three saw the others, so all of them are kept
+        let CrdtValue::Siblings(mut values) = merged(&merged(&a, &b), &c).value() else { panic!("not siblings") };
+        values.sort();
+        assert_eq!(values, vec![b"a2".to_vec(), b"b".to_vec(), b"c2".to_vec()]);
+        check_laws(a, b, c);
+    }
+
+    #[test]
+    fn gcounter_merge_laws() {
+        let (a, b, c) = diverged([
+            &[CrdtOp::Increment(1), CrdtOp::Increment(4)],
+            &[CrdtOp::Increment(2)],
+            &[CrdtOp::Increment(7), CrdtOp::Increment(1)],
+        ]);
+        assert_eq!(merged(&merged(&a, &b), &c).value(), CrdtValue::Counter(1 + 4 + 2 + 7 + 1));
+        check_laws(a, b, c);
+    }
+
+    #[test]
+    fn pncounter_merge_laws() {
+        let (a, b, c) = diverged([
+            &[CrdtOp::Add(5), CrdtOp::Add(-3)],
+            &[CrdtOp::Add(-10)],
+            &[CrdtOp::Add(2), CrdtOp::Add(-1)],
+        ]);
+        assert_eq!(merged(&merged(&a, &b), &c).value(), CrdtValue::Counter(5 - 3 - 10 + 2 - 1));
+        check_laws(a, b, c);
+    }
+
+    #[test]
+    fn gset_merge_laws() {
+        let (a, b, c) = diverged([
+            &[CrdtOp::AddG(b"x".to_vec()), CrdtOp::AddG(b"y".to_vec())],
+            &[CrdtOp::AddG(b"z".to_vec())],
+            &[CrdtOp::AddG(b"y".to_vec()), CrdtOp::AddG(b"w".to_vec())],
+        ]);
+        check_laws(a, b, c);
+    }
+
+    #[test]
+    fn orset_merge_laws() {
+        let (a, b, c) = diverged([
+            &[CrdtOp::AddOr(b"x".to_vec()), CrdtOp::AddOr(b"y".to_vec())],
+            &[CrdtOp::RemoveOr(b"x".to_vec()), CrdtOp::AddOr(b"z".to_vec())],
+            &[CrdtOp::AddOr(b"x".to_vec()), CrdtOp::RemoveOr(b"y".to_vec())],
+        ]);
+        check_laws(a, b, c);
+    }
+
+    #[test]
+    fn name_key_isnt_the_key_of_the_same_bytes() {
+        assert_ne!(name_key("foo"), crate::hash_value(b"foo"));
+        assert!(!name_key("foo").matches(b"foo"));
+    }
+
+    #[test]
+    fn clock_stays_ahead_of_a_remote_that_is_ahead_of_us() {
+        let clock = Clock::new(1);
+        let before = clock.now();
+
+        // an hour past our wall clock
+        let remote = Hlc { wall: before.wall + 3_600_000_000, logical: 5, node: 2 };
+        clock.observe(&remote);
+
+        let mut last = clock.now();
+        assert!(last > remote, "{} isn't after {}", last, remote);
+        assert_eq!(last.wall, remote.wall, "should hold the remote's wall time rather than fall back to ours");
+        for _ in 0..1000 {
+            let next = clock.now();
+            assert!(next > last, "{} isn't after {}", next, last);
+            last = next;
+        }
+
+        // seeing something older doesn't take us back
+        clock.observe(&before);
+        assert!(clock.now() > last);
+    }
+}
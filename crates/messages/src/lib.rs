@@ -1,12 +1,24 @@
 use serde::{Serialize, Deserialize};
 use bincode::{serialize, deserialize};
 use std::collections::HashMap;
-use std::net::TcpStream;
-use std::io::{Write, Read};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::io::{Write, Read, ErrorKind};
+use std::time::{Duration, SystemTime};
 
+pub mod address;
+pub use address::{Key, HashAlgorithm, hash_value};
+
+pub mod crdt;
+pub use crdt::{Crdt, CrdtOp, CrdtValue, Clock, Hlc, name_key};
+
+pub mod chunk;
+pub use chunk::{Manifest, CHUNK_SIZE};
 
 pub type ReplicaId = u64;
-pub type Key = u64;
+pub type RequestId = u64;
+
+// the most items a server will hand back in a single ScanResp, larger limits are clamped down to this
+pub const MAX_SCAN_LIMIT: usize = 10_000;
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct DigestPair {
@@ -18,16 +30,28 @@ pub struct DigestPair {
 pub struct UpdateMessage {
     pub sending_rate: f64,
     pub replica_keys: HashMap<ReplicaId, Vec<(Key, usize)>>, // (key, order)
-    pub key_values: Vec<KVPair>
+    pub key_values: Vec<(KVPair, EntryMeta)>, // metadata as the sender has it, the receiver bumps it a hop
+    pub tombstones: Vec<Tombstone>, // keys the sender has deleted, sent instead of their values
+    pub named: Vec<(String, Crdt)>, // whole states, the receiver merges them into its own
+}
+
+// a deleted key as antientropy passes it on. meta is for the delete (origin is the replica the client deleted it on), acked is every
+// replica the sender knows to hold the tombstone. once that covers every replica it knows of, it can forget the key altogether
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Tombstone {
+    pub key: Key,
+    pub meta: EntryMeta,
+    pub acked: Vec<ReplicaId>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub enum Message {
 
-    RetrieveReq{ key: u64 },
+    RetrieveReq{ key: Key },
     RetrieveResp{ result: FoundValue },
 
-    PushReq(KVPair),
+    // a ttl makes the value expire that long after it's written, None takes the server's default
+    PushReq(KVPair, Option<Duration>),
     PushResp{ success: bool },
 
     DumpReq,
@@ -39,11 +63,52 @@ pub enum Message {
     ClusterReq,
     ClusterResp(Vec<ClusterNode>),
 
-    Error(String),
+    Error{ code: ErrorCode, detail: Option<String> },
     ConnectionClosed,
 
     DigestMessage(ReplicaId, Vec<DigestPair>),
-    UpdateMessage(ReplicaId, UpdateMessage)
+    UpdateMessage(ReplicaId, UpdateMessage),
+
+    // bincode numbers variants by position, so new ones only ever go on the end. anywhere else and every variant after them
+    // would decode as a different one on a peer a version behind
+
+    // cursor is a position in the server's (append-only) list of keys it holds, start at 0 and pass back next_cursor until it is None
+    ScanReq{ cursor: usize, limit: usize, keys_only: bool },
+    ScanResp{ items: Vec<ScanItem>, next_cursor: Option<usize> },
+
+    // results come back in the same order as the request. the ttl applies to every value in it
+    PushBatchReq(Vec<KVPair>, Option<Duration>),
+    PushBatchResp(Vec<PushResult>),
+
+    MultiRetrieveReq(Vec<Key>),
+    MultiRetrieveResp(Vec<FoundValue>),
+
+    // the server works out the key, for clients that don't want to carry the hash function around
+    PutReq{ value: Vec<u8>, ttl: Option<Duration> },
+    PutResp{ key: Key },
+
+    // meta is None if the key isn't held
+    StatReq{ key: Key },
+    StatResp{ meta: Option<EntryMeta> },
+
+    // turns the connection into a feed of the same list a scan walks: one SubscribeEvent per key from from_index on, then one
+    // for each new key as it lands, until the connection closes. resume from the last index seen + 1 after reconnecting.
+    // a deleted key shows up again, with deleted set and no value
+    SubscribeReq{ from_index: usize, with_values: bool },
+    SubscribeEvent{ index: usize, item: ScanItem, deleted: bool },
+
+    // hides the key everywhere from then on, and the value goes once every replica has heard. found is false if this server held no
+    // value for it, it still records the delete so that the value is dropped by replicas that do
+    DeleteReq{ key: Key },
+    DeleteResp{ found: bool },
+
+    // named keys, which unlike the rest can change. fails with InvalidRequest if op is for a different type than the name holds
+    UpdateNamedReq{ name: String, op: CrdtOp },
+    UpdateNamedResp,
+
+    // value is None if nothing has been written under the name
+    ReadNamedReq{ name: String },
+    ReadNamedResp{ value: Option<CrdtValue> },
 }
 
 impl std::fmt::Display for Message {
@@ -51,17 +116,38 @@ impl std::fmt::Display for Message {
         match self {
             Message::RetrieveReq { key } => write!(f, "Message::RetrieveReq {{ key: {} }}", key)?,
             Message::RetrieveResp { result } => write!(f, "Message::RetrieveReq {{ result: {} }}", result)?,
-            Message::PushReq(pair) => write!(f, "Message::PushReq ({})", pair)?,
+            Message::PushReq(pair, ttl) => write!(f, "Message::PushReq ({}, {:?})", pair, ttl)?,
             Message::PushResp { success } => write!(f, "Message::PushResp {{ success: {} }}", success)?,
+            Message::PutReq { value, ttl } => write!(f, "Message::PutReq {{ value: {}, ttl: {:?} }}", String::from_utf8_lossy(value), ttl)?,
+            Message::PutResp { key } => write!(f, "Message::PutResp {{ key: {} }}", key)?,
+            Message::PushBatchReq(pairs, ttl) => write!(f, "Message::PushBatchReq({:?}, {:?})", pairs, ttl)?,
+            Message::PushBatchResp(results) => write!(f, "Message::PushBatchResp({:?})", results)?,
+            Message::MultiRetrieveReq(keys) => write!(f, "Message::MultiRetrieveReq({:?})", keys)?,
+            Message::MultiRetrieveResp(results) => write!(f, "Message::MultiRetrieveResp({:?})", results)?,
+            Message::DeleteReq { key } => write!(f, "Message::DeleteReq {{ key: {} }}", key)?,
+            Message::DeleteResp { found } => write!(f, "Message::DeleteResp {{ found: {} }}", found)?,
+            Message::UpdateNamedReq { name, op } => write!(f, "Message::UpdateNamedReq {{ name: {}, op: {:?} }}", name, op)?,
+            Message::UpdateNamedResp => write!(f, "Message::UpdateNamedResp")?,
+            Message::ReadNamedReq { name } => write!(f, "Message::ReadNamedReq {{ name: {} }}", name)?,
+            Message::ReadNamedResp { value: Some(value) } => write!(f, "Message::ReadNamedResp {{ value: {} }}", value)?,
+            Message::ReadNamedResp { value: None } => write!(f, "Message::ReadNamedResp {{ value: None }}")?,
+            Message::StatReq { key } => write!(f, "Message::StatReq {{ key: {} }}", key)?,
+            Message::StatResp { meta: Some(meta) } => write!(f, "Message::StatResp {{ meta: {} }}", meta)?,
+            Message::StatResp { meta: None } => write!(f, "Message::StatResp {{ meta: None }}")?,
             Message::DumpReq => write!(f, "Message::DumpReq")?,
             Message::DumpResp(v) => write!(f, "Message::DumpResp({:?})", v)?,
             Message::DumpLenReq => write!(f, "Message::DumpLenReq")?,
             Message::DumpLenResp(l) => write!(f, "Message::DumpLenResp({})", l)?,
+            Message::ScanReq { cursor, limit, keys_only } => write!(f, "Message::ScanReq {{ cursor: {}, limit: {}, keys_only: {} }}", cursor, limit, keys_only)?,
+            Message::ScanResp { items, next_cursor } => write!(f, "Message::ScanResp {{ items: {:?}, next_cursor: {:?} }}", items, next_cursor)?,
+            Message::SubscribeReq { from_index, with_values } => write!(f, "Message::SubscribeReq {{ from_index: {}, with_values: {} }}", from_index, with_values)?,
+            Message::SubscribeEvent { index, item, deleted } => write!(f, "Message::SubscribeEvent {{ index: {}, item: {}, deleted: {} }}", index, item, deleted)?,
             Message::ClusterReq => write!(f, "Message::ClusterReq")?,
             Message::ClusterResp(v) => write!(f, "Message::ClusterResp({:?})", v)?,
-            Message::Error(s) => write!(f, "Message::Error({})", s)?,
+            Message::Error { code, detail: Some(detail) } => write!(f, "Message::Error {{ code: {}, detail: {} }}", code, detail)?,
+            Message::Error { code, detail: None } => write!(f, "Message::Error {{ code: {} }}", code)?,
             Message::ConnectionClosed => write!(f, "Message::ConnectionClosed")?,
-            Message::UpdateMessage(id, msg) => write!(f, "Message::UpdateMessage{{from: {}, sending_rate: {}, replica_keys: {:?}, key_values: {:?}}}", id, msg.sending_rate, msg.replica_keys, msg.key_values)?,
+            Message::UpdateMessage(id, msg) => write!(f, "Message::UpdateMessage{{from: {}, sending_rate: {}, replica_keys: {:?}, key_values: {:?}, tombstones: {:?}, named: {:?}}}", id, msg.sending_rate, msg.replica_keys, msg.key_values, msg.tombstones, msg.named)?,
             Message::DigestMessage(id, pairs) => write!(f, "Message::DigestMessage{{from: {}, pairs: {:?}}}", id, pairs)?,
         };
         Ok(())
@@ -82,89 +168,559 @@ impl std::fmt::Display for ClusterNode {
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct KVPair {
-    pub key: u64,
-    pub value: String,
+    pub key: Key,
+    pub value: Vec<u8>,
 }
 
 impl std::fmt::Display for KVPair {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        write!(f, "KVPair {{ key: {}, value: {} }}", self.key, self.value)?;
+        write!(f, "KVPair {{ key: {}, value: {} }}", self.key, String::from_utf8_lossy(&self.value))?;
+        Ok(())
+    }
+}
+
+// where and when a stored value came from, kept alongside it on every replica
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct EntryMeta {
+    pub origin: ReplicaId, // the replica a client first wrote it to
+    pub written_at: SystemTime, // when the origin got it
+    pub received_at: SystemTime, // when this replica got it
+    pub hops: u32, // antientropy hops away from the origin, 0 on the origin itself
+    pub expires_at: Option<SystemTime>, // set by the origin, so every replica drops it at the same time. None never expires
+}
+
+impl EntryMeta {
+    // for a value written to origin just now
+    pub fn new(origin: ReplicaId) -> EntryMeta {
+        let now = SystemTime::now();
+        EntryMeta { origin, written_at: now, received_at: now, hops: 0, expires_at: None }
+    }
+
+    // the same, expiring ttl after it was written
+    pub fn expiring(self, ttl: Option<Duration>) -> EntryMeta {
+        EntryMeta { expires_at: ttl.map(|ttl| self.written_at + ttl), ..self }
+    }
+
+    pub fn is_expired(&self) -> bool {
+        match self.expires_at {
+            Some(t) => SystemTime::now() >= t,
+            None => false,
+        }
+    }
+
+    // what the next replica over should record when it gets this value from us
+    pub fn relayed(&self) -> EntryMeta {
+        EntryMeta { received_at: SystemTime::now(), hops: self.hops + 1, ..*self }
+    }
+}
+
+impl std::fmt::Display for EntryMeta {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        // staleness is what we usually care about, so show how long it took to get here rather than raw times
+        let lag = self.received_at.duration_since(self.written_at).unwrap_or_default();
+        write!(f, "EntryMeta {{ origin: {}, hops: {}, lag (ms): {}", self.origin, self.hops, lag.as_millis())?;
+        if let Some(expires_at) = self.expires_at {
+            write!(f, ", ttl (ms): {}", expires_at.duration_since(self.written_at).unwrap_or_default().as_millis())?;
+        }
+        write!(f, " }}")?;
+        Ok(())
+    }
+}
+
+// per-item outcome of a PushBatchReq
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub enum PushResult {
+    Accepted,
+    Duplicate, // already held, nothing new was stored
+    HashMismatch,
+    Collision, // a different value is already held under this key, it was kept
+    Deleted, // the key was deleted and not every replica has heard yet, so it can't be pushed again
+}
+
+impl std::fmt::Display for PushResult {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            PushResult::Accepted => write!(f, "PushResult::Accepted")?,
+            PushResult::Duplicate => write!(f, "PushResult::Duplicate")?,
+            PushResult::HashMismatch => write!(f, "PushResult::HashMismatch")?,
+            PushResult::Collision => write!(f, "PushResult::Collision")?,
+            PushResult::Deleted => write!(f, "PushResult::Deleted")?,
+        };
+        Ok(())
+    }
+}
+
+// value is None when the scan asked for keys only
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ScanItem {
+    pub key: Key,
+    pub value: Option<Vec<u8>>,
+}
+
+impl std::fmt::Display for ScanItem {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match &self.value {
+            Some(value) => write!(f, "ScanItem {{ key: {}, value: {} }}", self.key, String::from_utf8_lossy(value))?,
+            None => write!(f, "ScanItem {{ key: {} }}", self.key)?,
+        };
+        Ok(())
+    }
+}
+
+// what went wrong, for clients to act on without string matching. the detail string is only for humans
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCode {
+    HashMismatch, // key isn't the hash of the value, sending it again won't help
+    FrameTooLarge,
+    UnsupportedVersion,
+    Overloaded, // server is out of capacity right now, try again later
+    UnknownMessage, // couldn't decode the request, or it isn't one this listener serves
+    InvalidRequest, // decoded fine, but the arguments make no sense
+    StorageFailure,
+    Internal,
+    KeyCollision, // a different value is already held under that key. the stored one is kept
+    KeyDeleted, // the key was deleted recently. it can be pushed again once every replica has dropped it
+}
+
+impl ErrorCode {
+    // whether the same request could succeed if sent again later
+    pub fn is_retryable(&self) -> bool {
+        matches!(self, ErrorCode::Overloaded | ErrorCode::StorageFailure)
+    }
+}
+
+impl std::fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ErrorCode::HashMismatch => write!(f, "ErrorCode::HashMismatch")?,
+            ErrorCode::FrameTooLarge => write!(f, "ErrorCode::FrameTooLarge")?,
+            ErrorCode::UnsupportedVersion => write!(f, "ErrorCode::UnsupportedVersion")?,
+            ErrorCode::Overloaded => write!(f, "ErrorCode::Overloaded")?,
+            ErrorCode::UnknownMessage => write!(f, "ErrorCode::UnknownMessage")?,
+            ErrorCode::InvalidRequest => write!(f, "ErrorCode::InvalidRequest")?,
+            ErrorCode::StorageFailure => write!(f, "ErrorCode::StorageFailure")?,
+            ErrorCode::Internal => write!(f, "ErrorCode::Internal")?,
+            ErrorCode::KeyCollision => write!(f, "ErrorCode::KeyCollision")?,
+            ErrorCode::KeyDeleted => write!(f, "ErrorCode::KeyDeleted")?,
+        };
         Ok(())
     }
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub enum FoundValue {
-    Success { value: String },
+    Success { value: Vec<u8> },
     Failure,
 }
 
 impl std::fmt::Display for FoundValue {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         match self {
-            FoundValue::Success { value } => write!(f, "FoundValue::Success {{ value: {} }}", value)?,
+            FoundValue::Success { value } => write!(f, "FoundValue::Success {{ value: {} }}", String::from_utf8_lossy(value))?,
             FoundValue::Failure => write!(f, "FoundValue::Failure")?,
         };
         Ok(())
     }
 }
 
-pub fn send_message(stream: &mut TcpStream, msg: Message) -> Result<(), String> {
-    // first, serialize the request
-    let data = match serialize(&msg) {
+// wire framing. every frame starts with a fixed size header so that peers on different builds can at least tell
+// what they are looking at before trying to decode the payload:
+//   magic (4 bytes) | protocol version (u16) | flags (u16) | payload length (u64), all big endian
+pub const MAGIC: [u8; 4] = *b"SEKO";
+pub const PROTOCOL_VERSION: u16 = 2; // bump whenever the encoding of Message changes, including a variant added on the end. 2: PushReq has a ttl
+pub const MIN_PROTOCOL_VERSION: u16 = 2; // oldest version we can still decode. only raise it when an older encoding is dropped, not for additions
+pub const HEADER_LEN: usize = 16;
+pub const DEFAULT_MAX_FRAME_SIZE: u64 = 512 * 1024 * 1024; // a generous upper bound, values of tens of MB are pushed in the latency tests
+
+// frame flags
+pub const FLAG_HANDSHAKE: u16 = 0x0001; // payload is a version negotiation, not a Message
+pub const FLAG_REQUEST_ID: u16 = 0x0002; // payload starts with an 8 byte request id, echoed back on the response
+pub const REQUEST_ID_LEN: usize = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameHeader {
+    pub version: u16,
+    pub flags: u16,
+    pub len: u64,
+}
+
+impl FrameHeader {
+    pub fn encode(&self) -> [u8; HEADER_LEN] {
+        let mut buf = [0; HEADER_LEN];
+        buf[0..4].copy_from_slice(&MAGIC);
+        buf[4..6].copy_from_slice(&self.version.to_be_bytes());
+        buf[6..8].copy_from_slice(&self.flags.to_be_bytes());
+        buf[8..16].copy_from_slice(&self.len.to_be_bytes());
+        buf
+    }
+
+    pub fn decode(buf: &[u8; HEADER_LEN]) -> Result<FrameHeader, FrameError> {
+        let magic: [u8; 4] = buf[0..4].try_into().unwrap();
+        if magic != MAGIC {
+            return Err(FrameError::BadMagic(magic));
+        }
+
+        Ok(FrameHeader {
+            version: u16::from_be_bytes(buf[4..6].try_into().unwrap()),
+            flags: u16::from_be_bytes(buf[6..8].try_into().unwrap()),
+            len: u64::from_be_bytes(buf[8..16].try_into().unwrap()),
+        })
+    }
+}
+
+#[derive(Debug)]
+pub enum FrameError {
+    Io(String),
+    Closed, // peer closed the connection cleanly, between frames
+    Truncated, // peer closed the connection partway through a frame
+    Timeout,
+    TooLarge { len: u64, max: u64 },
+    Encode(String),
+    Decode(String),
+    BadMagic([u8; 4]),
+    UnsupportedVersion { version: u16, min: u16, max: u16 }, // version offered/used by the peer, and the range we accept
+}
+
+impl From<std::io::Error> for FrameError {
+    fn from(e: std::io::Error) -> Self {
+        match e.kind() {
+            ErrorKind::WouldBlock | ErrorKind::TimedOut => FrameError::Timeout, // platforms disagree on which one a socket timeout is
+            ErrorKind::UnexpectedEof => FrameError::Truncated,
+            _ => FrameError::Io(e.to_string()),
+        }
+    }
+}
+
+impl std::fmt::Display for FrameError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            FrameError::Io(e) => write!(f, "FrameError::Io({})", e)?,
+            FrameError::Closed => write!(f, "FrameError::Closed")?,
+            FrameError::Truncated => write!(f, "FrameError::Truncated")?,
+            FrameError::Timeout => write!(f, "FrameError::Timeout")?,
+            FrameError::TooLarge { len, max } => write!(f, "FrameError::TooLarge {{ len: {}, max: {} }}", len, max)?,
+            FrameError::Encode(e) => write!(f, "FrameError::Encode({})", e)?,
+            FrameError::Decode(e) => write!(f, "FrameError::Decode({})", e)?,
+            FrameError::BadMagic(m) => write!(f, "FrameError::BadMagic({:?})", m)?,
+            FrameError::UnsupportedVersion { version, min, max } => write!(f, "FrameError::UnsupportedVersion {{ version: {}, supported: {}..={} }}", version, min, max)?,
+        };
+        Ok(())
+    }
+}
+
+impl std::error::Error for FrameError {}
+
+// per-connection limits. the read timeout covers a frame once its first byte has arrived, the idle timeout covers
+// waiting for the next frame to start. None means block forever, like a plain TcpStream.
+#[derive(Debug, Clone, Copy)]
+pub struct FrameConfig {
+    pub max_frame_size: u64,
+    pub read_timeout: Option<Duration>,
+    pub write_timeout: Option<Duration>,
+    pub idle_timeout: Option<Duration>,
+}
+
+impl Default for FrameConfig {
+    fn default() -> Self {
+        FrameConfig { max_frame_size: DEFAULT_MAX_FRAME_SIZE, read_timeout: None, write_timeout: None, idle_timeout: None }
+    }
+}
+
+impl FrameConfig {
+    // sets the socket options that stay fixed for the life of the connection
+    pub fn apply(&self, stream: &TcpStream) -> Result<(), FrameError> {
+        stream.set_write_timeout(self.write_timeout)?;
+        stream.set_read_timeout(self.read_timeout)?;
+        Ok(())
+    }
+}
+
+// the highest version both sides understand, if any. it's only what goes back in the handshake answer, nothing holds on to it
+pub fn negotiate_version(peer_min: u16, peer_max: u16) -> Option<u16> {
+    let highest = std::cmp::min(peer_max, PROTOCOL_VERSION);
+    let lowest = std::cmp::max(peer_min, MIN_PROTOCOL_VERSION);
+
+    if highest >= lowest {
+        Some(highest)
+    } else {
+        None
+    }
+}
+
+// serializes a message into a complete frame (header followed by payload), shared with the async helpers in secko_tests
+pub fn encode_frame(msg: &Message) -> Result<Vec<u8>, FrameError> {
+    encode_frame_tagged(None, msg)
+}
+
+// same, but with a request id in front of the message if one is given
+pub fn encode_frame_tagged(id: Option<RequestId>, msg: &Message) -> Result<Vec<u8>, FrameError> {
+    let data = match serialize(msg) {
         Ok(data) => data,
-        Err(e) => return Err(e.to_string()),
+        Err(e) => return Err(FrameError::Encode(e.to_string())),
     };
 
-    // then, get the length of that serialized data
-    let len: [u8; 8] = data.len().to_be_bytes(); // NOTE: works if usize is 64 bytes.
-
-    // now, write the message's length...
-    let _: Result<(), String> = match stream.write(&len) {
-        Ok(_) => Ok(()),
-        Err(e) => return Err(e.to_string()),
+    let (flags, id_len) = match id {
+        Some(_) => (FLAG_REQUEST_ID, REQUEST_ID_LEN),
+        None => (0, 0),
     };
-    
-    // ...followed by the message itself.
-    let _: Result<(), String> = match stream.write(&data) {
-        Ok(_) => Ok(()),
-        Err(e) => return Err(e.to_string()),
+    let header = FrameHeader { version: PROTOCOL_VERSION, flags, len: (id_len + data.len()) as u64 };
+
+    let mut frame = Vec::with_capacity(HEADER_LEN + id_len + data.len());
+    frame.extend_from_slice(&header.encode());
+    if let Some(id) = id {
+        frame.extend_from_slice(&id.to_be_bytes());
+    }
+    frame.extend_from_slice(&data);
+    Ok(frame)
+}
+
+// turns a received payload back into a message, after checking the header describes something we can decode
+pub fn decode_frame(header: &FrameHeader, payload: &[u8]) -> Result<Message, FrameError> {
+    decode_frame_tagged(header, payload).map(|(_, msg)| msg)
+}
+
+// same, but also hands back the request id if the frame carried one
+pub fn decode_frame_tagged(header: &FrameHeader, payload: &[u8]) -> Result<(Option<RequestId>, Message), FrameError> {
+    // a newer peer only adds variants on the end, so anything it sends that we know about still decodes. anything we don't
+    // fails below as a Decode error, which is what we'd say about garbage too
+    if header.version < MIN_PROTOCOL_VERSION {
+        return Err(FrameError::UnsupportedVersion { version: header.version, min: MIN_PROTOCOL_VERSION, max: PROTOCOL_VERSION });
+    }
+
+    if header.flags & FLAG_HANDSHAKE != 0 {
+        return Err(FrameError::Decode("unexpected handshake frame".to_string()));
+    }
+
+    let (id, payload) = if header.flags & FLAG_REQUEST_ID != 0 {
+        if payload.len() < REQUEST_ID_LEN {
+            return Err(FrameError::Decode(format!("request id flag set but payload has length {}", payload.len())));
+        }
+        let (id, rest) = payload.split_at(REQUEST_ID_LEN);
+        (Some(u64::from_be_bytes(id.try_into().unwrap())), rest)
+    } else {
+        (None, payload)
     };
 
+    match deserialize(payload) {
+        Ok(msg) => Ok((id, msg)),
+        Err(e) => Err(FrameError::Decode(e.to_string())),
+    }
+}
+
+pub fn send_message(stream: &mut TcpStream, msg: Message) -> Result<(), FrameError> {
+    send_message_tagged(stream, None, msg)
+}
+
+// responses should carry the id of the request they answer, so a client with several requests in flight can match them up
+pub fn send_message_tagged(stream: &mut TcpStream, id: Option<RequestId>, msg: Message) -> Result<(), FrameError> {
+    // first, serialize the request into a frame
+    let frame = encode_frame_tagged(id, &msg)?;
+
+    // now, write the header and message in one go...
+    stream.write_all(&frame)?;
+
     // and finally flush
-    let _: Result<(), String> = match stream.flush() {
-        Ok(_) => Ok(()),
-        Err(e) => return Err(e.to_string()),
-    };
+    stream.flush()?;
 
     Ok(())
-    
 }
 
-pub fn receive_message(stream: &mut TcpStream) -> Result<Message, String> {
-    // first, get the size
+pub fn receive_message(stream: &mut TcpStream) -> Result<Message, FrameError> {
+    receive_message_tagged(stream).map(|(_, msg)| msg)
+}
+
+pub fn receive_message_tagged(stream: &mut TcpStream) -> Result<(Option<RequestId>, Message), FrameError> {
+    // leaves the socket's timeouts alone, only enforces the default size limit
+    let (header, payload) = read_frame(stream, DEFAULT_MAX_FRAME_SIZE)?;
+    decode_frame_tagged(&header, &payload)
+}
+
+pub fn receive_message_with(stream: &mut TcpStream, config: &FrameConfig) -> Result<Message, FrameError> {
+    receive_message_tagged_with(stream, config).map(|(_, msg)| msg)
+}
+
+pub fn receive_message_tagged_with(stream: &mut TcpStream, config: &FrameConfig) -> Result<(Option<RequestId>, Message), FrameError> {
+    // wait for the next frame to start under the idle timeout...
+    stream.set_read_timeout(config.idle_timeout)?;
+    let mut first = [0; 1];
+    read_first_byte(stream, &mut first)?;
+
+    // ...then the rest of it has to arrive under the read timeout
+    stream.set_read_timeout(config.read_timeout)?;
+    let (header, payload) = read_frame_rest(stream, first[0], config.max_frame_size)?;
+    decode_frame_tagged(&header, &payload)
+}
+
+fn read_frame(stream: &mut TcpStream, max_frame_size: u64) -> Result<(FrameHeader, Vec<u8>), FrameError> {
+    let mut first = [0; 1];
+    read_first_byte(stream, &mut first)?;
+    read_frame_rest(stream, first[0], max_frame_size)
+}
+
+// distinguishes a clean close (nothing of the next frame read yet) from one partway through a frame
+fn read_first_byte(stream: &mut TcpStream, buf: &mut [u8; 1]) -> Result<(), FrameError> {
+    loop {
+        match stream.read(buf) {
+            Ok(0) => return Err(FrameError::Closed),
+            Ok(_) => return Ok(()),
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
+fn read_frame_rest(stream: &mut TcpStream, first: u8, max_frame_size: u64) -> Result<(FrameHeader, Vec<u8>), FrameError> {
+    // get the rest of the header. read_exact keeps going across short reads, which a single read() does not
     // https://users.rust-lang.org/t/reading-length-payload-from-a-tcpstream/51211
-    let mut size_buffer = [0; 8];
-    let _: Result<(), String> = match stream.read(&mut size_buffer) {
-        Ok(_) => Ok(()),
-        Err(e) => return Err(e.to_string()),
-    };
-    let bincode_size: usize = usize::from_be_bytes(size_buffer);
+    let mut header_buffer = [0; HEADER_LEN];
+    header_buffer[0] = first;
+    stream.read_exact(&mut header_buffer[1..])?;
+    let header = FrameHeader::decode(&header_buffer)?;
 
-    if bincode_size == 0 { // catch EOF
-        return Ok(Message::ConnectionClosed);
+    // refuse before allocating anything, the length is whatever the peer says it is
+    if header.len > max_frame_size {
+        return Err(FrameError::TooLarge { len: header.len, max: max_frame_size });
     }
 
-    // now, read in the request
-    let mut request = vec![0; bincode_size];
-    let _: Result<(), String> = match stream.read(&mut request) {
-        Ok(_) => Ok(()),
-        Err(e) => return Err(e.to_string()),
-    };
+    // now, read in the payload
+    let mut payload = vec![0; header.len as usize];
+    stream.read_exact(&mut payload)?;
+
+    Ok((header, payload))
+}
+
+// version handshake, run once right after a connection is made. the connecting side offers the range of versions it
+// understands, the accepting side answers with the version it picked (or 0 if there is no overlap, followed by its own range).
+// these frames never contain a bincode Message, so they stay readable no matter how Message changes between versions.
+// all it settles is whether the two ranges overlap. there's no version kept for the rest of the connection: every frame
+// carries its sender's version, and a variant the receiver doesn't know yet is a Decode error for just that message
+pub fn client_handshake(stream: &mut TcpStream) -> Result<(), FrameError> {
+    let mut offer = Vec::with_capacity(4);
+    offer.extend_from_slice(&MIN_PROTOCOL_VERSION.to_be_bytes());
+    offer.extend_from_slice(&PROTOCOL_VERSION.to_be_bytes());
+    write_handshake(stream, &offer)?;
+
+    let answer = read_handshake(stream)?;
+    if answer.len() != 6 {
+        return Err(FrameError::Decode(format!("handshake answer has length {}", answer.len())));
+    }
+
+    let chosen = u16::from_be_bytes([answer[0], answer[1]]);
+    let peer_min = u16::from_be_bytes([answer[2], answer[3]]);
+    let peer_max = u16::from_be_bytes([answer[4], answer[5]]);
 
-    // and return the deserialized result
-    match deserialize(&request) {
-        Ok(msg) => Ok(msg),
-        Err(e) => return Err(e.to_string()),
+    if chosen == 0 || chosen < MIN_PROTOCOL_VERSION || chosen > PROTOCOL_VERSION {
+        return Err(FrameError::UnsupportedVersion { version: peer_max, min: peer_min, max: peer_max });
     }
-}
\ No newline at end of file
+
+    Ok(())
+}
+
+pub fn server_handshake(stream: &mut TcpStream) -> Result<(), FrameError> {
+    let offer = read_handshake(stream)?;
+    if offer.len() != 4 {
+        return Err(FrameError::Decode(format!("handshake offer has length {}", offer.len())));
+    }
+
+    let peer_min = u16::from_be_bytes([offer[0], offer[1]]);
+    let peer_max = u16::from_be_bytes([offer[2], offer[3]]);
+    let chosen = negotiate_version(peer_min, peer_max);
+
+    let mut answer = Vec::with_capacity(6);
+    answer.extend_from_slice(&chosen.unwrap_or(0).to_be_bytes());
+    answer.extend_from_slice(&MIN_PROTOCOL_VERSION.to_be_bytes());
+    answer.extend_from_slice(&PROTOCOL_VERSION.to_be_bytes());
+    write_handshake(stream, &answer)?;
+
+    match chosen {
+        Some(_) => Ok(()),
+        None => Err(FrameError::UnsupportedVersion { version: peer_max, min: MIN_PROTOCOL_VERSION, max: PROTOCOL_VERSION }),
+    }
+}
+
+// connects and runs the handshake, so callers get back a stream that is ready for send_message/receive_message
+pub fn connect<A: ToSocketAddrs>(addr: A) -> Result<TcpStream, FrameError> {
+    connect_with(addr, &FrameConfig::default())
+}
+
+// same, but with the timeouts applied before the handshake so an unresponsive peer can't hold us up
+pub fn connect_with<A: ToSocketAddrs>(addr: A, config: &FrameConfig) -> Result<TcpStream, FrameError> {
+    let mut stream = TcpStream::connect(addr)?;
+    config.apply(&stream)?;
+    client_handshake(&mut stream)?;
+    Ok(stream)
+}
+
+fn write_handshake(stream: &mut TcpStream, payload: &[u8]) -> Result<(), FrameError> {
+    let header = FrameHeader { version: PROTOCOL_VERSION, flags: FLAG_HANDSHAKE, len: payload.len() as u64 };
+
+    let mut frame = Vec::with_capacity(HEADER_LEN + payload.len());
+    frame.extend_from_slice(&header.encode());
+    frame.extend_from_slice(payload);
+
+    stream.write_all(&frame)?;
+    stream.flush()?;
+    Ok(())
+}
+
+fn read_handshake(stream: &mut TcpStream) -> Result<Vec<u8>, FrameError> {
+    // the handshake layout is fixed and tiny, so anything else here means the peer is not speaking the same protocol at all
+    let (header, payload) = read_frame(stream, 64)?;
+    if header.flags & FLAG_HANDSHAKE == 0 {
+        return Err(FrameError::Decode("expected a handshake frame".to_string()));
+    }
+    Ok(payload)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn variant(msg: &Message) -> u32 {
+        let data = serialize(msg).unwrap();
+        u32::from_le_bytes(data[0..4].try_into().unwrap())
+    }
+
+    fn reframed(msg: &Message, version: u16) -> (FrameHeader, Vec<u8>) {
+        let frame = encode_frame(msg).unwrap();
+        let mut header = FrameHeader::decode(frame[0..HEADER_LEN].try_into().unwrap()).unwrap();
+        header.version = version;
+        (header, frame[HEADER_LEN..].to_vec())
+    }
+
+    // a peer a version behind has to read these the same way we do
+    #[test]
+    fn variants_keep_their_place() {
+        assert_eq!(variant(&Message::RetrieveReq { key: hash_value(b"a") }), 0);
+        assert_eq!(variant(&Message::DumpReq), 4);
+        assert_eq!(variant(&Message::ConnectionClosed), 11);
+        assert_eq!(variant(&Message::DigestMessage(1, vec![])), 12);
+        assert_eq!(variant(&Message::ScanReq { cursor: 0, limit: 1, keys_only: true }), 14);
+        assert_eq!(variant(&Message::ReadNamedResp { value: None }), 31);
+    }
+
+    #[test]
+    fn frame_from_a_newer_peer_still_decodes() {
+        let (header, payload) = reframed(&Message::DumpLenResp(3), PROTOCOL_VERSION + 1);
+        assert!(matches!(decode_frame(&header, &payload), Ok(Message::DumpLenResp(3))));
+
+        // a variant added after us is just something we can't decode
+        let mut unknown = payload.clone();
+        unknown[0..4].copy_from_slice(&1000u32.to_le_bytes());
+        assert!(matches!(decode_frame(&header, &unknown), Err(FrameError::Decode(_))));
+    }
+
+    #[test]
+    fn frame_older_than_we_can_read_is_refused() {
+        let (header, payload) = reframed(&Message::DumpLenReq, MIN_PROTOCOL_VERSION - 1);
+        assert!(matches!(decode_frame(&header, &payload), Err(FrameError::UnsupportedVersion { .. })));
+    }
+
+    #[test]
+    fn negotiation_picks_the_highest_shared_version() {
+        assert_eq!(negotiate_version(MIN_PROTOCOL_VERSION, PROTOCOL_VERSION + 3), Some(PROTOCOL_VERSION));
+        assert_eq!(negotiate_version(0, MIN_PROTOCOL_VERSION), Some(MIN_PROTOCOL_VERSION));
+        assert_eq!(negotiate_version(PROTOCOL_VERSION + 1, PROTOCOL_VERSION + 2), None);
+        assert_eq!(negotiate_version(1, 1), None); // from before PushReq had a ttl, we can't read that
+    }
+}
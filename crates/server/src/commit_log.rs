@@ -0,0 +1,859 @@
+// the commit log. every change to the store is appended as a record, and the ones the snapshot doesn't have are replayed on top
+// of it at startup. it's split into segments, path.<position of the segment's first record>, a new one started whenever the last
+// gets past a size. segments the snapshots cover entirely are deleted. all big endian:
+//   header: magic (8 bytes) | format version (u16) | unused (u16) | position of the segment's first record (u64)
+//   record: payload length (u32) | crc32 of the payload (u32) | payload, a bincoded LogRecord
+// a crash can leave the last record half written, recovery cuts it off. logs from before segments were a single text file at path,
+// a line per change, that becomes the first segment the first time it's opened
+use std::collections::HashSet;
+use std::fs::{File, OpenOptions, rename, remove_file, read_dir, metadata};
+use std::io::{self, Read, Write, Seek, SeekFrom, BufRead, BufReader};
+use std::path::Path;
+use std::sync::{Arc, atomic::Ordering};
+use serde::{Serialize, Deserialize};
+use secko_messages::{Key, EntryMeta, Crdt, ReplicaId, hash_value, name_key};
+
+use crate::{Commit, Entry, Named, named_entry, sync_dir_of, map::LockFreeMap, storage::Storage};
+
+pub const LOG_MAGIC: [u8; 8] = *b"SEKOLOG\0";
+pub const LOG_VERSION: u16 = 2; // bump whenever the header or LogRecord changes
+pub const HEADER_LEN: u64 = 20;
+pub const SEGMENT_BYTES: u64 = 64 * 1024 * 1024; // a new segment is started once the last is past this
+const RECORD_HEADER_LEN: usize = 8;
+
+// what text logs start with, followed by the snapshot's line count
+const TEXT_HEADER: &str = "Snapshotted Until Line: ";
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum LogRecord {
+    Put { key: Key, value: Vec<u8>, meta: EntryMeta },
+    Delete { key: Key, meta: EntryMeta }, // the acks aren't logged, antientropy brings them back
+    Named { name: String, state: Crdt }, // the whole state after the change, replaying it is just one more merge
+}
+
+impl LogRecord {
+    // what goes in the log for commit. every commit gets exactly one record, our replica list has a key on the end for each and
+    // replaying the log has to put them back. a put whose value has since been deleted logs the tombstone, one already collected
+    // is logged as it was if it expired and as a delete if it didn't
+    pub fn of(commit: &Commit, map: &dyn Storage) -> io::Result<LogRecord> {
+        Ok(match commit {
+            Commit::Put { key, meta } => match map.get(key)? {
+                Some(entry) if entry.is_deleted() => LogRecord::Delete { key: *key, meta: entry.meta },
+                Some(entry) => LogRecord::Put { key: *key, value: entry.value.clone(), meta: entry.meta },
+                None if meta.is_expired() => LogRecord::Put { key: *key, value: Vec::new(), meta: *meta },
+                None => LogRecord::Delete { key: *key, meta: *meta },
+            },
+            Commit::Delete { key, meta } => LogRecord::Delete { key: *key, meta: *meta },
+            Commit::Named { named } => {
+                // cleared before the state is read, so a change after it has been read queues another commit
+                named.queued.store(false, Ordering::SeqCst);
+                LogRecord::Named { name: named.name.clone(), state: named.state.lock().unwrap().clone() }
+            },
+        })
+    }
+
+    // the key it put on the end of our list
+    pub fn key(&self) -> Key {
+        match self {
+            LogRecord::Put { key, .. } | LogRecord::Delete { key, .. } => *key,
+            LogRecord::Named { name, .. } => name_key(name),
+        }
+    }
+}
+
+// a record as it goes on the end of the log
+pub fn encode(record: &LogRecord) -> Vec<u8> {
+    let payload = bincode::serialize(record).unwrap();
+
+    let mut bytes = Vec::with_capacity(RECORD_HEADER_LEN + payload.len());
+    bytes.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    bytes.extend_from_slice(&crc32(&payload).to_be_bytes());
+    bytes.extend(payload);
+    bytes
+}
+
+fn header(base: u64) -> Vec<u8> {
+    let mut bytes = LOG_MAGIC.to_vec();
+    bytes.extend_from_slice(&LOG_VERSION.to_be_bytes());
+    bytes.extend_from_slice(&0u16.to_be_bytes());
+    bytes.extend_from_slice(&base.to_be_bytes());
+    bytes
+}
+
+// the segment whose first record is at position base. zero padded so they list in order
+pub fn segment_path(path: &str, base: u64) -> String {
+    format!("{}.{:020}", path, base)
+}
+
+// every segment of the log at path, oldest first, with the position each starts at
+pub fn segments(path: &str) -> io::Result<Vec<(u64, String)>> {
+    let file_name = match Path::new(path).file_name() {
+        Some(name) => format!("{}.", name.to_string_lossy()),
+        None => return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("{} isn't a file name", path))),
+    };
+    let dir = match Path::new(path).parent() {
+        Some(d) if !d.as_os_str().is_empty() => d,
+        _ => Path::new("."),
+    };
+
+    let mut found: Vec<(u64, String)> = Vec::new();
+    for entry in read_dir(dir)? {
+        let name = entry?.file_name().to_string_lossy().into_owned();
+        // anything else sharing the prefix, like a segment still being created, isn't all digits after it
+        let base = name.strip_prefix(&file_name).filter(|b| b.len() == 20 && b.bytes().all(|c| c.is_ascii_digit())).and_then(|b| b.parse::<u64>().ok());
+        if let Some(base) = base {
+            found.push((base, segment_path(path, base)));
+        }
+    }
+    found.sort();
+    Ok(found)
+}
+
+// writes a whole segment next to where it goes and moves it into place, so there's never half a header there
+fn write_new(path: &str, base: u64, records: &[LogRecord]) -> io::Result<()> {
+    let target = segment_path(path, base);
+    let tmp = format!("{}.new", target);
+    let mut f = File::create(&tmp)?;
+    f.write_all(&header(base))?;
+    for record in records {
+        f.write_all(&encode(record))?;
+    }
+    f.sync_all()?;
+    rename(&tmp, &target)?;
+
+    // and the rename itself, or power loss can leave the old segment (or none) in its place
+    sync_dir_of(&target)
+}
+
+// an empty segment, starting at position base
+pub fn create(path: &str, base: u64) -> io::Result<()> {
+    write_new(path, base, &[])
+}
+
+// what recover found
+#[derive(Debug)]
+pub struct Recovered {
+    pub start: u64, // position of the first record, where the snapshot leaves off
+    pub end: u64, // position the next record appended gets
+    pub records: Vec<LogRecord>, // every whole record from start on
+    pub truncated: u64, // bytes of torn record cut off the end
+    pub converted: bool, // it was a text log from an older version
+}
+
+// reads the log from snapshotted on, after cutting off anything past the last whole record. only the segments holding those are
+// opened. None for snapshotted is a store without a snapshot manifest, which replays from the start (a text log leaves out the
+// lines its snapshot has)
+pub fn recover(path: &str, local_replica_id: ReplicaId, snapshotted: Option<u64>) -> Result<Recovered, String> {
+    let mut converted = false;
+    let mut truncated: u64 = 0;
+    if metadata(path).is_ok() {
+        let records = read_text(path, local_replica_id)?;
+        if let Err(e) = write_new(path, 0, &records).and_then(|()| remove_file(path)).and_then(|()| sync_dir_of(path)) {
+            return Err(format!("Couldn't turn the commit log into segments: {}", e));
+        }
+        converted = true;
+    }
+
+    let mut segments = match segments(path) {
+        Ok(s) => s,
+        Err(e) => return Err(format!("Couldn't list the commit log's segments: {}", e)),
+    };
+    if segments.is_empty() {
+        if let Err(e) = create(path, 0) {
+            return Err(format!("Commit log creation failed: {}", e));
+        }
+        segments.push((0, segment_path(path, 0)));
+    }
+
+    let start = snapshotted.unwrap_or(0);
+    if start < segments[0].0 {
+        return Err(format!("The commit log starts at record {}, but replay has to start at {}.", segments[0].0, start));
+    }
+
+    // from the segment holding start. the last is always read, it's where appending carries on
+    let first = segments.iter().rposition(|(base, _)| *base <= start).unwrap();
+    let last = segments.len() - 1;
+    let mut records: Vec<LogRecord> = Vec::new();
+    let mut end: u64 = 0;
+    for (i, (base, segment)) in segments.iter().enumerate().skip(first) {
+        let read = read_segment(segment, *base)?;
+        if read.damaged {
+            // cutting there would throw away every record after the bad one, which were all acknowledged
+            return Err(format!("{} is damaged part way through, there are whole records after the bad one.", segment));
+        }
+        if read.torn > 0 {
+            // only ever the last segment is being written to, an earlier one was synced before the next was started
+            if i != last {
+                return Err(format!("{} is damaged part way through.", segment));
+            }
+            let cut = OpenOptions::new().write(true).open(segment).and_then(|f| f.set_len(read.good_len).and_then(|()| f.sync_all()));
+            if let Err(e) = cut {
+                return Err(format!("Couldn't cut the torn record off the commit log: {}", e));
+            }
+            truncated += read.torn;
+        }
+        end = base + read.records.len() as u64;
+        records.extend(read.records.into_iter().skip(start.saturating_sub(*base) as usize));
+    }
+
+    // the snapshot can get to disk ahead of records that didn't. numbering carries on from the snapshot, or whatever is appended
+    // next would be taken as already in it
+    if start > end {
+        if let Err(e) = create(path, start) {
+            return Err(format!("Couldn't start a commit log segment: {}", e));
+        }
+        end = start;
+    }
+
+    Ok(Recovered { start, end, records, truncated, converted })
+}
+
+// puts records back into map and named, as they were when they were logged. gives back why any named state couldn't be merged,
+// and fails if map can't take an entry
+pub fn replay(records: Vec<LogRecord>, map: &dyn Storage, named: &LockFreeMap<Key, Named>, local_replica_id: ReplicaId) -> Result<Vec<String>, String> {
+    let mut skipped: Vec<String> = Vec::new();
+    for record in records {
+        match record {
+            // merged in rather than replacing, the snapshot may already be further along
+            LogRecord::Named { name, state } => {
+                let (entry, created) = named_entry(named, &name, || state.clone());
+                if !created {
+                    if let Err(e) = entry.state.lock().unwrap().merge(&state) {
+                        skipped.push(format!("{}: {}", name, e));
+                    }
+                }
+            },
+            LogRecord::Delete { key, meta } => {
+                if let Err(e) = map.insert(key, Arc::new(Entry::tombstone(meta, HashSet::from([local_replica_id, meta.origin])))) {
+                    return Err(format!("Couldn't replay the delete of {}: {}", key, e));
+                }
+            },
+            LogRecord::Put { key, value, meta } => {
+                // expired while we were down
+                if meta.is_expired() {
+                    continue;
+                }
+                if let Err(e) = map.insert(key, Arc::new(Entry::new(value, meta))) {
+                    return Err(format!("Couldn't replay the put of {}: {}", key, e));
+                }
+            },
+        }
+    }
+    Ok(skipped)
+}
+
+// what was in a segment, up to the first record that isn't whole
+struct Segment {
+    records: Vec<LogRecord>,
+    good_len: u64,
+    torn: u64,
+    damaged: bool, // there are whole records past good_len, so what's there isn't a torn write
+}
+
+fn read_segment(segment: &str, base: u64) -> Result<Segment, String> {
+    let file = match File::open(segment) {
+        Ok(f) => f,
+        Err(e) => return Err(format!("Couldn't open {}: {}", segment, e)),
+    };
+    let (found_base, records, good_len, file_len) = read_binary(&file)?;
+    if found_base != base {
+        return Err(format!("{} says it starts at record {}.", segment, found_base));
+    }
+    let damaged = match good_len < file_len {
+        true => whole_record_after(&file, good_len)?,
+        false => false,
+    };
+    Ok(Segment { records, good_len, torn: file_len - good_len, damaged })
+}
+
+// whether a whole record starts anywhere after the bad one at from. a crash only ever tears the end of the last write, so the
+// bytes after a torn record never hold one. the bad record's own length can't be trusted, hence trying every offset
+fn whole_record_after(mut file: &File, from: u64) -> Result<bool, String> {
+    let mut tail: Vec<u8> = Vec::new();
+    if let Err(e) = file.seek(SeekFrom::Start(from)).and_then(|_| file.read_to_end(&mut tail)) {
+        return Err(format!("Couldn't read the end of the commit log: {}", e));
+    }
+    for offset in 1..tail.len() {
+        let mut rest = &tail[offset..];
+        if rest.len() < RECORD_HEADER_LEN {
+            break;
+        }
+        if read_record(&mut rest, (tail.len() - offset) as u64).is_some() {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+// what check found in a segment
+#[derive(Debug)]
+pub struct SegmentCheck {
+    pub path: String,
+    pub base: u64, // position of its first record
+    pub records: u64, // whole ones
+    pub torn: u64, // bytes past the last whole record
+    pub damaged: bool, // whole records follow those bytes, so they aren't a torn write and recovery won't cut them off
+}
+
+// what check found in the whole log
+#[derive(Debug)]
+pub struct Checked {
+    pub segments: Vec<SegmentCheck>, // oldest first
+    pub records: Vec<LogRecord>, // every whole record of every segment, from the first segment's base on
+}
+
+// reads every segment of the log at path without changing any of it, unlike recover, so it's safe on a log that's in use. a text
+// log from an older version is only turned into segments by recover
+pub fn check(path: &str) -> Result<Checked, String> {
+    if metadata(path).is_ok() {
+        return Err(format!("{} is a commit log from an older version, it becomes segments once it's recovered.", path));
+    }
+    let segments = match segments(path) {
+        Ok(s) => s,
+        Err(e) => return Err(format!("Couldn't list the commit log's segments: {}", e)),
+    };
+    if segments.is_empty() {
+        return Err(format!("There's no commit log at {}.", path));
+    }
+
+    let mut checked = Checked { segments: Vec::new(), records: Vec::new() };
+    for (base, segment) in segments {
+        let read = read_segment(&segment, base)?;
+        checked.segments.push(SegmentCheck { path: segment, base, records: read.records.len() as u64, torn: read.torn, damaged: read.damaged });
+        checked.records.extend(read.records);
+    }
+    Ok(checked)
+}
+
+// a segment: the position in its header, its whole records, how far they go and how long the file is
+fn read_binary(file: &File) -> Result<(u64, Vec<LogRecord>, u64, u64), String> {
+    let file_len = match file.metadata() {
+        Ok(m) => m.len(),
+        Err(e) => return Err(format!("Couldn't read the commit log's size: {}", e)),
+    };
+    let mut reader = BufReader::new(file);
+
+    let mut head = [0u8; HEADER_LEN as usize];
+    if reader.read_exact(&mut head).is_err() {
+        return Err("The commit log's header is cut short.".to_string());
+    }
+    if head[..8] != LOG_MAGIC {
+        return Err("Not a commit log.".to_string());
+    }
+    let found = u16::from_be_bytes([head[8], head[9]]);
+    if found != LOG_VERSION {
+        return Err(format!("The commit log is format version {}, this build only reads {}.", found, LOG_VERSION));
+    }
+    let value = u64::from_be_bytes(head[12..20].try_into().unwrap());
+
+    // up to the first record that isn't whole. normally the end of the file, short of that after a crash mid write
+    let mut records: Vec<LogRecord> = Vec::new();
+    let mut good_len: u64 = HEADER_LEN;
+    while let Some((record, len)) = read_record(&mut reader, file_len - good_len) {
+        records.push(record);
+        good_len += len as u64;
+    }
+    Ok((value, records, good_len, file_len))
+}
+
+// appends to the last segment, and starts a new one once that's past segment_bytes. a segment is synced before the next one is
+// started, so only ever the last can be torn
+pub struct Appender {
+    path: String,
+    file: File,
+    len: u64, // of the last segment
+    position: u64, // the next record's
+    segment_bytes: u64,
+    broken: bool, // a failed write couldn't be cut back off, so nothing more can go after it
+}
+
+impl Appender {
+    // carries on from end, where recover found the log to end
+    pub fn open(path: &str, end: u64, segment_bytes: u64) -> io::Result<Appender> {
+        let segment = match segments(path)?.pop() {
+            Some((_, s)) => s,
+            None => return Err(io::Error::new(io::ErrorKind::NotFound, "the commit log has no segments")),
+        };
+        let file = OpenOptions::new().append(true).open(segment)?;
+        let len = file.metadata()?.len();
+        Ok(Appender { path: path.to_string(), file, len, position: end, segment_bytes, broken: false })
+    }
+
+    // count records, already encoded, all into the same segment with a single write. with sync, they're on disk when this returns.
+    // on an error none of them went in, so the same records can be appended again
+    pub fn append(&mut self, records: &[u8], count: u64, sync: bool) -> io::Result<()> {
+        if self.broken {
+            return Err(io::Error::other("the commit log has part of a failed write on the end, it's cut off on restart"));
+        }
+        let written = self.file.write_all(records).and_then(|()| match sync {
+            true => self.file.sync_data(),
+            false => Ok(()),
+        });
+        if let Err(e) = written {
+            // whatever made it out would be a bad record with whole ones after it once they're appended again
+            if let Err(cut) = self.file.set_len(self.len) {
+                self.broken = true;
+                return Err(io::Error::other(format!("{}, and couldn't cut the part written back off: {}", e, cut)));
+            }
+            return Err(e);
+        }
+        self.len += records.len() as u64;
+        self.position += count;
+
+        // the records are in, so this can't fail the append. it's tried again after the next one
+        if self.len >= self.segment_bytes {
+            let _ = self.start_segment();
+        }
+        Ok(())
+    }
+
+    fn start_segment(&mut self) -> io::Result<()> {
+        self.file.sync_data()?;
+        create(&self.path, self.position)?;
+        self.file = OpenOptions::new().append(true).open(segment_path(&self.path, self.position))?;
+        self.len = HEADER_LEN;
+        Ok(())
+    }
+}
+
+// deletes the segments holding nothing from keep_from on, which the snapshots have already. the last segment always stays, it's
+// the one being appended to. returns how many went
+pub fn compact(path: &str, keep_from: u64) -> io::Result<usize> {
+    let segments = segments(path)?;
+    let mut removed: usize = 0;
+    for pair in segments.windows(2) {
+        if pair[1].0 <= keep_from {
+            remove_file(&pair[0].1)?;
+            removed += 1;
+        }
+    }
+    if removed > 0 {
+        sync_dir_of(path)?;
+    }
+    Ok(removed)
+}
+
+// None at the end of the log, and for a record that's cut short or doesn't match its checksum
+fn read_record(reader: &mut impl Read, remaining: u64) -> Option<(LogRecord, usize)> {
+    let mut head = [0u8; RECORD_HEADER_LEN];
+    reader.read_exact(&mut head).ok()?;
+    let len = u32::from_be_bytes(head[0..4].try_into().unwrap()) as usize;
+    let crc = u32::from_be_bytes(head[4..8].try_into().unwrap());
+
+    // a length running past the end of the file is a torn write, not something to allocate for
+    if (RECORD_HEADER_LEN + len) as u64 > remaining {
+        return None;
+    }
+
+    let mut payload = vec![0u8; len];
+    reader.read_exact(&mut payload).ok()?;
+    if crc32(&payload) != crc {
+        return None;
+    }
+    let record = bincode::deserialize(&payload).ok()?;
+    Some((record, RECORD_HEADER_LEN + len))
+}
+
+// the records of a text log, less the lines the snapshot already has
+fn read_text(path: &str, local_replica_id: ReplicaId) -> Result<Vec<LogRecord>, String> {
+    let file = match File::open(path) {
+        Ok(f) => f,
+        Err(e) => return Err(format!("Couldn't open the text commit log: {}", e)),
+    };
+    let lines: Vec<String> = match BufReader::new(file).lines().collect() {
+        Ok(lines) => lines,
+        Err(e) => return Err(format!("Couldn't read the text commit log: {}", e)),
+    };
+
+    let snapshotted: usize = match lines.first().and_then(|l| l.strip_prefix(TEXT_HEADER)).and_then(|n| n.trim().parse().ok()) {
+        Some(n) => n,
+        None => return Err(format!("{} isn't a commit log this build can read.", path)),
+    };
+    Ok(lines.iter().skip(1 + snapshotted).filter_map(|l| parse_text_line(l, local_replica_id)).collect())
+}
+
+// a line of a text log, "key -> value". keys then were u64s and values text, with nothing else kept, so the value is rekeyed from
+// its content and taken as written here, now
+fn parse_text_line(line: &str, local_replica_id: ReplicaId) -> Option<LogRecord> {
+    let (key, value) = line.split_once(" -> ")?;
+    key.parse::<u64>().ok()?;
+    let value = value.as_bytes().to_vec();
+    Some(LogRecord::Put { key: hash_value(&value), value, meta: EntryMeta::new(local_replica_id) })
+}
+
+// crc-32 as zlib and ethernet have it
+pub fn crc32(data: &[u8]) -> u32 {
+    crc32_update(0, data)
+}
+
+// carries on the crc of whatever came before data, for checksumming as it streams past
+pub fn crc32_update(crc: u32, data: &[u8]) -> u32 {
+    const TABLE: [u32; 256] = {
+        let mut table = [0u32; 256];
+        let mut i = 0;
+        while i < 256 {
+            let mut c = i as u32;
+            let mut k = 0;
+            while k < 8 {
+                c = if c & 1 == 1 { 0xedb88320 ^ (c >> 1) } else { c >> 1 };
+                k += 1;
+            }
+            table[i] = c;
+            i += 1;
+        }
+        table
+    };
+
+    let mut crc = !crc;
+    for b in data {
+        crc = TABLE[((crc ^ *b as u32) & 0xff) as usize] ^ (crc >> 8);
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use secko_messages::{CrdtOp, Hlc};
+    use std::fs::{read, create_dir_all, remove_dir_all};
+    use std::os::unix::fs::FileExt;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    // a fresh directory per test, so they can run side by side. the log goes in it as "log"
+    fn temp_log(name: &str) -> String {
+        let dir = std::env::temp_dir().join(format!("secko-commit-log-{}-{}", std::process::id(), name));
+        let _ = remove_dir_all(&dir);
+        create_dir_all(&dir).unwrap();
+        dir.join("log").to_str().unwrap().to_string()
+    }
+
+    fn clean_up(path: &str) {
+        remove_dir_all(Path::new(path).parent().unwrap()).unwrap();
+    }
+
+    fn append(segment: &str, records: &[LogRecord]) {
+        let mut f = OpenOptions::new().append(true).open(segment).unwrap();
+        for record in records {
+            f.write_all(&encode(record)).unwrap();
+        }
+    }
+
+    // the same meta every time, so records made separately compare equal
+    fn put(value: &[u8]) -> LogRecord {
+        let at = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        LogRecord::Put { key: hash_value(value), value: value.to_vec(), meta: EntryMeta { origin: 7, written_at: at, received_at: at, hops: 0, expires_at: None } }
+    }
+
+    fn file_len(path: &str) -> u64 {
+        std::fs::metadata(path).unwrap().len()
+    }
+
+    fn bases(path: &str) -> Vec<u64> {
+        segments(path).unwrap().iter().map(|(base, _)| *base).collect()
+    }
+
+    #[test]
+    fn crc32_matches_the_standard_check_value() {
+        assert_eq!(crc32(b"123456789"), 0xcbf43926);
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(crc32_update(crc32(b"1234"), b"56789"), 0xcbf43926);
+    }
+
+    #[test]
+    fn empty_log_round_trips() {
+        let path = temp_log("empty");
+
+        let recovered = recover(&path, 1, None).unwrap();
+        assert!(recovered.records.is_empty());
+        assert_eq!((recovered.start, recovered.end), (0, 0));
+        assert_eq!(recovered.truncated, 0);
+        assert!(!recovered.converted);
+        assert_eq!(file_len(&segment_path(&path, 0)), HEADER_LEN);
+        assert_eq!(bases(&path), vec![0]);
+        clean_up(&path);
+    }
+
+    #[test]
+    fn records_come_back_exactly_as_written() {
+        let path = temp_log("exact");
+        recover(&path, 1, None).unwrap();
+
+        // the values the text log couldn't keep, or kept only by hexing them
+        let every_byte: Vec<u8> = (0..=255).collect();
+        let large: Vec<u8> = (0..3_000_000u32).map(|i| (i % 251) as u8).collect();
+        let stamp = Hlc { wall: 1, logical: 2, node: 3 };
+        let records = vec![
+            put(b""),
+            put(b"plain"),
+            put(b"with\nnewlines\r\n"),
+            put(b"key -> value => more := trailing "),
+            put(&every_byte),
+            put(&large),
+            LogRecord::Put { key: hash_value(b"expiring"), value: b"expiring".to_vec(), meta: EntryMeta::new(7).expiring(Some(Duration::from_secs(60))) },
+            LogRecord::Delete { key: hash_value(b"plain"), meta: EntryMeta::new(9) },
+            LogRecord::Named { name: "a name\nwith a newline".to_string(), state: Crdt::from_op(&CrdtOp::SetLww(b"v".to_vec()), 3, stamp) },
+            LogRecord::Named { name: "members".to_string(), state: Crdt::from_op(&CrdtOp::AddOr(b"x".to_vec()), 3, stamp) },
+        ];
+        append(&segment_path(&path, 0), &records);
+
+        let recovered = recover(&path, 1, None).unwrap();
+        assert_eq!(recovered.records, records);
+        assert_eq!(recovered.end, records.len() as u64);
+        assert_eq!(recovered.truncated, 0);
+        clean_up(&path);
+    }
+
+    #[test]
+    fn replay_starts_where_the_snapshot_leaves_off() {
+        let path = temp_log("snapshotted");
+        recover(&path, 1, None).unwrap();
+        append(&segment_path(&path, 0), &[put(b"a"), put(b"b"), put(b"c")]);
+
+        let recovered = recover(&path, 1, Some(2)).unwrap();
+        assert_eq!(recovered.records, vec![put(b"c")]);
+        assert_eq!((recovered.start, recovered.end), (2, 3));
+        clean_up(&path);
+    }
+
+    #[test]
+    fn snapshot_past_the_end_moves_numbering_up_to_it() {
+        let path = temp_log("past-the-end");
+        recover(&path, 1, None).unwrap();
+        append(&segment_path(&path, 0), &[put(b"a"), put(b"b")]);
+
+        let recovered = recover(&path, 1, Some(5)).unwrap();
+        assert!(recovered.records.is_empty());
+        assert_eq!((recovered.start, recovered.end), (5, 5));
+        assert_eq!(bases(&path), vec![0, 5]);
+
+        // so what's appended next is replayed on top of that snapshot
+        let mut log = Appender::open(&path, recovered.end, SEGMENT_BYTES).unwrap();
+        log.append(&encode(&put(b"c")), 1, false).unwrap();
+        let recovered = recover(&path, 1, Some(5)).unwrap();
+        assert_eq!(recovered.records, vec![put(b"c")]);
+        assert_eq!(recovered.end, 6);
+        clean_up(&path);
+    }
+
+    #[test]
+    fn appending_rotates_segments_and_recovery_reads_only_what_it_needs() {
+        let path = temp_log("rotate");
+        let recovered = recover(&path, 1, None).unwrap();
+
+        // small enough that every two records start a new segment
+        let record_len = encode(&put(b"0")).len() as u64;
+        let mut log = Appender::open(&path, recovered.end, HEADER_LEN + 2 * record_len).unwrap();
+        let written: Vec<LogRecord> = (0..7).map(|i| put(i.to_string().as_bytes())).collect();
+        for record in written.iter() {
+            log.append(&encode(record), 1, true).unwrap();
+        }
+        assert_eq!(bases(&path), vec![0, 2, 4, 6]);
+
+        let recovered = recover(&path, 1, None).unwrap();
+        assert_eq!(recovered.records, written);
+        assert_eq!(recovered.end, 7);
+
+        // a damaged segment before the one holding the start is never opened
+        std::fs::write(segment_path(&path, 0), b"not a segment").unwrap();
+        let recovered = recover(&path, 1, Some(3)).unwrap();
+        assert_eq!(recovered.records, written[3..].to_vec());
+        assert_eq!((recovered.start, recovered.end), (3, 7));
+        clean_up(&path);
+    }
+
+    #[test]
+    fn compaction_drops_only_segments_every_snapshot_has() {
+        let path = temp_log("compact");
+        let recovered = recover(&path, 1, None).unwrap();
+        let record_len = encode(&put(b"0")).len() as u64;
+        let mut log = Appender::open(&path, recovered.end, HEADER_LEN + 2 * record_len).unwrap();
+        for i in 0..7 {
+            log.append(&encode(&put(i.to_string().as_bytes())), 1, false).unwrap();
+        }
+
+        // record 3 is in the segment at 2, so that one stays
+        assert_eq!(compact(&path, 3).unwrap(), 1);
+        assert_eq!(bases(&path), vec![2, 4, 6]);
+
+        // the last segment stays however far the snapshots go
+        assert_eq!(compact(&path, 100).unwrap(), 2);
+        assert_eq!(bases(&path), vec![6]);
+
+        // and recovery from before what's left is refused rather than replaying a gap
+        assert!(recover(&path, 1, Some(3)).is_err());
+        assert_eq!(recover(&path, 1, Some(7)).unwrap().records, Vec::<LogRecord>::new());
+        clean_up(&path);
+    }
+
+    #[test]
+    fn torn_record_is_cut_off_and_appending_carries_on() {
+        let path = temp_log("torn");
+        recover(&path, 1, None).unwrap();
+        let segment = segment_path(&path, 0);
+        append(&segment, &[put(b"first"), put(b"second")]);
+        let whole = file_len(&segment);
+        append(&segment, &[put(b"third, only partly written")]);
+
+        // as if the crash came part way through the last write
+        let f = OpenOptions::new().write(true).open(&segment).unwrap();
+        f.set_len(file_len(&segment) - 5).unwrap();
+        let torn = file_len(&segment) - whole;
+
+        let recovered = recover(&path, 1, None).unwrap();
+        assert_eq!(recovered.records, vec![put(b"first"), put(b"second")]);
+        assert_eq!(recovered.truncated, torn);
+        assert_eq!(file_len(&segment), whole);
+
+        append(&segment, &[put(b"fourth")]);
+        let recovered = recover(&path, 1, None).unwrap();
+        assert_eq!(recovered.records, vec![put(b"first"), put(b"second"), put(b"fourth")]);
+        assert_eq!(recovered.truncated, 0);
+        clean_up(&path);
+    }
+
+    #[test]
+    fn torn_record_header_is_cut_off() {
+        let path = temp_log("torn-header");
+        recover(&path, 1, None).unwrap();
+        let segment = segment_path(&path, 0);
+        append(&segment, &[put(b"first")]);
+        let whole = file_len(&segment);
+
+        let mut f = OpenOptions::new().append(true).open(&segment).unwrap();
+        f.write_all(&[0, 0, 1]).unwrap();
+
+        let recovered = recover(&path, 1, None).unwrap();
+        assert_eq!(recovered.records, vec![put(b"first")]);
+        assert_eq!(recovered.truncated, 3);
+        assert_eq!(file_len(&segment), whole);
+        clean_up(&path);
+    }
+
+    #[test]
+    fn torn_segment_before_the_last_is_refused() {
+        let path = temp_log("torn-middle");
+        recover(&path, 1, None).unwrap();
+        append(&segment_path(&path, 0), &[put(b"first"), put(b"second")]);
+        create(&path, 2).unwrap();
+
+        let f = OpenOptions::new().write(true).open(segment_path(&path, 0)).unwrap();
+        f.set_len(file_len(&segment_path(&path, 0)) - 1).unwrap();
+
+        assert!(recover(&path, 1, None).is_err());
+        clean_up(&path);
+    }
+
+    #[test]
+    fn check_reports_a_torn_record_without_cutting_it_off() {
+        let path = temp_log("check");
+        recover(&path, 1, None).unwrap();
+        append(&segment_path(&path, 0), &[put(b"first"), put(b"second")]);
+        create(&path, 2).unwrap();
+        let segment = segment_path(&path, 2);
+        append(&segment, &[put(b"third")]);
+
+        let mut f = OpenOptions::new().append(true).open(&segment).unwrap();
+        f.write_all(&[0, 0, 1]).unwrap();
+        let len = file_len(&segment);
+
+        let checked = check(&path).unwrap();
+        assert_eq!(checked.records, vec![put(b"first"), put(b"second"), put(b"third")]);
+        assert_eq!(checked.segments.iter().map(|s| (s.base, s.records, s.torn)).collect::<Vec<_>>(), vec![(0, 2, 0), (2, 1, 3)]);
+        assert_eq!(file_len(&segment), len);
+        clean_up(&path);
+    }
+
+    #[test]
+    fn record_that_fails_its_checksum_is_cut_off() {
+        let path = temp_log("checksum");
+        recover(&path, 1, None).unwrap();
+        let segment = segment_path(&path, 0);
+        append(&segment, &[put(b"first"), put(b"second")]);
+
+        // flip a bit in the last byte of the last payload
+        let mut bytes = read(&segment).unwrap();
+        *bytes.last_mut().unwrap() ^= 1;
+        std::fs::write(&segment, &bytes).unwrap();
+
+        let recovered = recover(&path, 1, None).unwrap();
+        assert_eq!(recovered.records, vec![put(b"first")]);
+        assert!(recovered.truncated > 0);
+        clean_up(&path);
+    }
+
+    #[test]
+    fn record_that_fails_its_checksum_part_way_through_is_refused() {
+        let path = temp_log("checksum-middle");
+        recover(&path, 1, None).unwrap();
+        let segment = segment_path(&path, 0);
+        append(&segment, &[put(b"first"), put(b"second"), put(b"third")]);
+
+        // flip a bit in the last byte of the first payload, the two after it are still whole
+        let mut bytes = read(&segment).unwrap();
+        bytes[HEADER_LEN as usize + encode(&put(b"first")).len() - 1] ^= 1;
+        std::fs::write(&segment, &bytes).unwrap();
+
+        assert!(recover(&path, 1, None).is_err());
+        assert_eq!(read(&segment).unwrap(), bytes);
+        let checked = check(&path).unwrap();
+        assert!(checked.segments[0].damaged);
+        assert!(checked.records.is_empty());
+        clean_up(&path);
+    }
+
+    #[test]
+    fn newer_format_is_refused() {
+        let path = temp_log("newer");
+        recover(&path, 1, None).unwrap();
+
+        let f = OpenOptions::new().write(true).open(segment_path(&path, 0)).unwrap();
+        f.write_all_at(&(LOG_VERSION + 1).to_be_bytes(), 8).unwrap();
+
+        assert!(recover(&path, 1, None).is_err());
+        clean_up(&path);
+    }
+
+    #[test]
+    fn other_files_are_refused() {
+        let path = temp_log("other");
+        std::fs::write(&path, b"not a commit log, and longer than a header").unwrap();
+
+        assert!(recover(&path, 1, None).is_err());
+        clean_up(&path);
+    }
+
+    #[test]
+    fn text_log_is_converted() {
+        let path = temp_log("text");
+        std::fs::write(&path, "Snapshotted Until Line: 0000001\n1 -> snapshotted!\n2 -> a value with spaces\n3 -> another").unwrap();
+
+        // the first line was in the snapshot, so it's left behind
+        let recovered = recover(&path, 4, None).unwrap();
+        assert!(recovered.converted);
+        assert_eq!(recovered.start, 0);
+        let values: Vec<(Key, Vec<u8>, ReplicaId)> = recovered.records.iter().map(|record| match record {
+            LogRecord::Put { key, value, meta } => (*key, value.clone(), meta.origin),
+            other => panic!("Expected a put, got {:?}", other),
+        }).collect();
+        assert_eq!(values, vec![
+            (hash_value(b"a value with spaces"), b"a value with spaces".to_vec(), 4),
+            (hash_value(b"another"), b"another".to_vec(), 4),
+        ]);
+
+        // and it's binary segments from here on
+        assert_eq!(read(segment_path(&path, 0)).unwrap()[..8], LOG_MAGIC);
+        let recovered = recover(&path, 4, None).unwrap();
+        assert!(!recovered.converted);
+        assert_eq!(recovered.records.len(), 2);
+        clean_up(&path);
+    }
+
+    #[test]
+    fn single_file_that_isnt_a_text_log_is_refused() {
+        let path = temp_log("not-text");
+        std::fs::write(&path, b"SEKOLOG\0 whatever this is").unwrap();
+        assert!(recover(&path, 1, None).is_err());
+        assert!(std::fs::metadata(&path).is_ok());
+        clean_up(&path);
+    }
+}
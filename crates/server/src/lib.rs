@@ -1,20 +1,189 @@
 // persistence
-use std::{sync::Arc, time::SystemTime};
+use std::{sync::{Arc, mpsc, atomic::AtomicBool}, collections::HashSet};
+use serde::{Serialize, Deserialize};
+use secko_messages::{Key, EntryMeta, Crdt, Manifest, name_key};
 
+pub mod commit_log;
+pub mod snapshot;
+pub mod storage;
+pub mod replicas;
+#[cfg(any(test, feature = "test-util"))]
+pub mod test_util;
+use storage::Storage;
+
+// what the store holds under each key
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Entry {
+    pub value: Vec<u8>, // empty once deleted
+    pub meta: EntryMeta, // for a tombstone, the delete's
+    pub deleted: Option<Mutex<HashSet<ReplicaId>>>, // Some for a tombstone, holding the replicas we know have it
+}
+
+impl Entry {
+    pub fn new(value: Vec<u8>, meta: EntryMeta) -> Entry {
+        Entry { value, meta, deleted: None }
+    }
+
+    // the value is dropped straight away, only the key is kept around so the delete can make its way to every replica
+    pub fn tombstone(meta: EntryMeta, acked: HashSet<ReplicaId>) -> Entry {
+        Entry { value: Vec::new(), meta, deleted: Some(Mutex::new(acked)) }
+    }
+
+    pub fn is_deleted(&self) -> bool {
+        self.deleted.is_some()
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.meta.is_expired()
+    }
+
+    // the value, unless deleted or expired
+    pub fn live(&self) -> Option<&Vec<u8>> {
+        match self.deleted {
+            Some(_) => None,
+            None if self.is_expired() => None,
+            None => Some(&self.value),
+        }
+    }
+}
+
+// entries are the same if they hold the same value. the metadata only says how this replica came by it, which differs
+// between replicas and between pushes of the same value. a tombstone or an expired value is never the same as a live one
+impl PartialEq for Entry {
+    fn eq(&self, other: &Entry) -> bool {
+        self.live() == other.live() && self.is_deleted() == other.is_deleted()
+    }
+}
+
+// insert_if_absent, except that an expired value is replaced. it only hasn't been collected yet
+pub fn store(map: &dyn Storage, key: Key, entry: Arc<Entry>) -> std::io::Result<InsertOutcome<Entry>> {
+    match map.insert_if_absent(key, entry.clone())? {
+        InsertOutcome::Collision(held) if !held.is_deleted() && held.is_expired() => {
+            map.insert(key, entry)?;
+            Ok(InsertOutcome::Inserted)
+        },
+        other => Ok(other),
+    }
+}
+
+// whether value can be stored under key. either it hashes to it, or it's the manifest for a value that does, which we can only tell
+// once every chunk is here (so chunks have to be pushed before their manifest)
+pub fn content_matches(map: &dyn Storage, key: &Key, value: &[u8]) -> std::io::Result<bool> {
+    if key.matches(value) {
+        return Ok(true);
+    }
+
+    let manifest = match Manifest::from_bytes(value) {
+        Some(m) => m,
+        None => return Ok(false),
+    };
+    let mut chunks: Vec<Vec<u8>> = Vec::with_capacity(manifest.chunks.len());
+    for chunk in manifest.chunks.iter() {
+        match map.get(chunk)?.as_ref().and_then(|e| e.live()) {
+            Some(value) => chunks.push(value.clone()),
+            None => return Ok(false),
+        }
+    }
+    Ok(manifest.reassemble(key, chunks).is_ok())
+}
+
+// a put only names its key and meta, the persister reads the value back out of the store as it writes it, so values don't sit in the
+// queue twice over. a name is read back the same way. a delete carries what it needs, the tombstone may be collected before then
 #[derive(Debug)]
-pub struct Commit {
-    pub key: u64,
-    pub value: Arc<String>,
-    pub timestamp: SystemTime, // when it was received on the server, for testing
+pub enum Commit {
+    Put { key: Key, meta: EntryMeta }, // the value's meta, for if it's gone by the time it's logged
+    Delete { key: Key, meta: EntryMeta },
+    Named { named: Arc<Named> }, // logged as the whole state by then, so changes made while it waits go in with it
+}
+
+impl Commit {
+    // what goes in the replica lists for it
+    pub fn key(&self) -> Key {
+        match self {
+            Commit::Put { key, .. } | Commit::Delete { key, .. } => *key,
+            Commit::Named { named } => name_key(&named.name),
+        }
+    }
+}
+
+// when a write is acknowledged to the client
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Durability {
+    Memory, // once it's in the map, before it's even queued for the commit log. lost if the process dies before it's written
+    Write, // once it's written to the commit log. survives the process dying, not the machine losing power
+    Fsync, // once the commit log is synced to disk
+}
+
+impl std::str::FromStr for Durability {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Durability, String> {
+        match s {
+            "memory" => Ok(Durability::Memory),
+            "write" => Ok(Durability::Write),
+            "fsync" => Ok(Durability::Fsync),
+            other => Err(format!("Unknown durability {}, expected memory, write or fsync.", other)),
+        }
+    }
+}
+
+// commits on their way to the persister. done is dropped once they've been written (and synced, for Durability::Fsync), after
+// being sent on if that worked, so whoever waits on it can tell the two apart
+pub struct Persist {
+    pub commits: Vec<Commit>,
+    pub done: Option<mpsc::Sender<()>>,
+}
+
+impl Persist {
+    // nobody waits on these
+    pub fn new(commits: Vec<Commit>) -> Persist {
+        Persist { commits, done: None }
+    }
+}
+
+// what the store holds under each name, keyed by name_key
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Named {
+    pub name: String,
+    pub state: Mutex<Crdt>,
+    #[serde(skip)]
+    pub queued: AtomicBool, // a Commit::Named for it is waiting on the persister. a change until then doesn't need one of its own
+}
+
+// only ever compared to find out whether the name is already there
+impl PartialEq for Named {
+    fn eq(&self, other: &Named) -> bool {
+        self.name == other.name
+    }
+}
+
+// the entry for name, made from init if there isn't one yet. true if it was made
+pub fn named_entry(named: &LockFreeMap<Key, Named>, name: &str, init: impl FnOnce() -> Crdt) -> (Arc<Named>, bool) {
+    let key = name_key(name);
+    let fresh = Arc::new(Named { name: name.to_string(), state: Mutex::new(init()), queued: AtomicBool::new(false) });
+    match named.insert_if_absent(key, fresh.clone()) {
+        InsertOutcome::Inserted => (fresh, true),
+        InsertOutcome::Duplicate => (named.get(&key).unwrap().val().clone(), false), // names are never removed
+        InsertOutcome::Collision(held) => (held, false), // two names with the same hash, which we don't expect to see
+    }
+}
+
+// syncs the directory path is in, which is what makes a file created or renamed there stay put through power loss
+pub fn sync_dir_of(path: &str) -> std::io::Result<()> {
+    let dir = match std::path::Path::new(path).parent() {
+        Some(d) if !d.as_os_str().is_empty() => d,
+        _ => std::path::Path::new("."),
+    };
+    std::fs::File::open(dir)?.sync_all()
 }
 
 // antientropy
 pub mod map;
-use map::LockFreeMap;
+use map::{LockFreeMap, InsertOutcome};
 use std::{net::{Ipv4Addr, SocketAddrV4}, sync::{Mutex}};
-use secko_messages::DigestPair;
+use secko_messages::{DigestPair, ReplicaId};
 
-pub fn create_digest(map: Arc<LockFreeMap<Mutex<Vec<u64>>>>) -> Vec<DigestPair> {
+pub fn create_digest(map: Arc<LockFreeMap<ReplicaId, Mutex<Vec<Key>>>>) -> Vec<DigestPair> {
     let mut result: Vec<DigestPair> = Vec::new();
 
     for peer in map.iter() {
@@ -1,14 +1,13 @@
 use std::{
-    io::{BufRead, BufReader, BufWriter, Write}, //to read and write from the stream
     net::{TcpListener, TcpStream, SocketAddrV4},
-    sync::{mpsc::{self}, Arc, Mutex, RwLock},
+    sync::{mpsc::{self}, Arc, Mutex, RwLock, Condvar},
     env,
+    io,
     thread,
-    time::{Duration, SystemTime},
-    hash::{Hash, Hasher},
-    collections::{hash_map::DefaultHasher, HashSet, HashMap},
-    fs::{File, OpenOptions, metadata},
-    os::unix::fs::FileExt, process::exit,
+    time::Duration,
+    collections::{HashSet, HashMap},
+    fs::metadata,
+    process::exit,
 };
 use chrono::offset::Utc;
 use chrono::DateTime; // https://stackoverflow.com/questions/45386585/how-to-format-systemtime-to-string
@@ -16,20 +15,29 @@ use chrono::DateTime; // https://stackoverflow.com/questions/45386585/how-to-for
 use atomic_counter::{AtomicCounter, RelaxedCounter}; // want to effectively share a reference that can be modified by one thread and we don't care about ordering or up to date in other thread, but just using arc wont work as mutex needed, just using mut wont work as we can be interrupted mid add, so using an atomic
 // generally atomic is more light weight https://stackoverflow.com/questions/15056237/which-is-more-efficient-basic-mutex-lock-or-atomic-integer
 // don't require strong ordering. simply need to read a pretty recent version of the value (https://cfsamsonbooks.gitbook.io/explaining-atomics-in-rust/)
-use bincode::{serialize_into, deserialize_from};
 
 mod threadpool;
 use threadpool::ThreadPool;
 
-use secko_messages::{ClusterNode, UpdateMessage, Message, FoundValue, KVPair, DigestPair, ReplicaId, Key, send_message, receive_message};
+use secko_messages::{ClusterNode, UpdateMessage, Message, FoundValue, KVPair, DigestPair, ReplicaId, RequestId, Key, EntryMeta, Tombstone, ScanItem, Crdt, Clock, Manifest, HashAlgorithm, name_key, PushResult, ErrorCode, MAX_SCAN_LIMIT, CHUNK_SIZE, hash_value, send_message, send_message_tagged, receive_message_with, receive_message_tagged_with, server_handshake, connect_with, FrameConfig, FrameError, DEFAULT_MAX_FRAME_SIZE};
 
-use secko_server::{Commit, u64_to_socketaddr, socketaddr_to_u64, create_digest, map::LockFreeMap};
+use secko_server::{Commit, Persist, Durability, Entry, Named, store, content_matches, named_entry, u64_to_socketaddr, socketaddr_to_u64, create_digest, map::{LockFreeMap, InsertOutcome}, commit_log::{self, LogRecord}, snapshot::{self, SnapshotManifest}, storage::{Storage, Backend, MemoryStorage, DiskStorage}, replicas::{ReplicaMap, Listed, Cursors, FirstListed, enlist, list_tails, restore}};
 
 // https://github.com/clap-rs/clap/blob/master/examples/escaped-positional.rs
 use clap::{arg, Arg, ArgAction, command, value_parser};
 
 use rand::{seq::SliceRandom, thread_rng};
 
+// most batches the persister writes (and syncs) together. keeps a flood of small writes from building one huge group
+const MAX_GROUP_COMMIT: usize = 1024;
+
+// how long the persister waits before trying again to log what it couldn't
+const PERSIST_RETRY: Duration = Duration::from_secs(1);
+
+// roughly how many bytes of values go in one update, on top of the 250 key cap. large values are chunked, so this is a handful of
+// chunks, and the chunks of one value can't hold up everything else for long
+const UPDATE_VALUE_BYTES: usize = 16 * CHUNK_SIZE;
+
 fn main() {
     // parse arguments for address, commit log filename, and snapshot filename
     let matches = command!()
@@ -39,6 +47,18 @@ fn main() {
         .arg(arg!(rate: -r <ANTIENTROPYRATE>).value_parser(value_parser!(String))) // for antientropy
         .arg(arg!(commit: -c <COMMITLOGFILE>).value_parser(value_parser!(String))) // for persistence
         .arg(arg!(snapshot: -s <SNAPSHOTFILE>).value_parser(value_parser!(String))) // for persistence
+        .arg(arg!(max_frame: -m <MAXFRAMEBYTES>).value_parser(value_parser!(String))) // for connections
+        .arg(arg!(read_timeout: -t <READTIMEOUTSECS>).value_parser(value_parser!(String))) // for connections
+        .arg(arg!(write_timeout: -w <WRITETIMEOUTSECS>).value_parser(value_parser!(String))) // for connections
+        .arg(arg!(idle_timeout: -i <IDLETIMEOUTSECS>).value_parser(value_parser!(String))) // for connections
+        .arg(arg!(max_backlog: -q <MAXQUEUEDCLIENTS>).value_parser(value_parser!(String))) // for connections
+        .arg(arg!(default_ttl: -e <DEFAULTTTLSECS>).value_parser(value_parser!(String))) // for expiry
+        .arg(arg!(durability: -d <DURABILITY>).value_parser(value_parser!(String))) // for persistence
+        .arg(arg!(segment_size: -l <LOGSEGMENTBYTES>).value_parser(value_parser!(String))) // for persistence
+        .arg(arg!(snapshot_interval: -p <SNAPSHOTSECS>).value_parser(value_parser!(String))) // for persistence
+        .arg(arg!(full_every: -f <DELTASPERFULLSNAPSHOT>).value_parser(value_parser!(String))) // for persistence
+        .arg(arg!(storage: -g <STORAGE>).value_parser(value_parser!(String))) // for storage
+        .arg(arg!(store_file: -o <STOREFILE>).value_parser(value_parser!(String))) // for storage
         .get_matches();
 
     // save parameters pertaining to antientropy
@@ -88,31 +108,141 @@ fn main() {
         None => "/tmp/secko_snapshot".to_string() // default snapshot location
     };
 
-    // create the lock-free hashmap (effectively a ctrie afaik in that it’s implemented much like a HAMT with lock-free capabilities)
-    // custom implementation allowing for serialization so that we can make snapshots
-    // wrapped in an arc as its reference will be shared across threads
-    let map: Arc<LockFreeMap<String>>;
+    // when writes are acknowledged: memory, write or fsync
+    let durability: Durability = match matches.get_one::<String>("durability") {
+        Some(c) => c.trim().parse::<Durability>().unwrap_or_else(|e| panic!("Messed up parsing argument {}: {}", c, e)),
+        None => Durability::Memory // acknowledge straight away, as we always have
+    };
+
+    // how big a commit log segment gets before the next is started
+    let segment_bytes: u64 = match matches.get_one::<String>("segment_size") {
+        Some(c) => c.trim().parse::<u64>().unwrap_or_else(|_| panic!("Messed up parsing argument {}", c)),
+        None => commit_log::SEGMENT_BYTES
+    };
 
-    // from that, construct a default replica map, for antientropy purposes
-    let replica_map: Arc<LockFreeMap<Mutex<Vec<Key>>>> = Arc::new(LockFreeMap::new());
+    let snapshot_interval: f64 = match matches.get_one::<String>("snapshot_interval") {
+        Some(c) => c.trim().parse::<f64>().unwrap_or_else(|_| panic!("Messed up parsing argument {}", c)),
+        None => 5.0
+    };
 
-    // either deserialize, if a backed up file exists...
-    if metadata(&snapshot_filename).is_ok() {
-        map = match File::open(&snapshot_filename) {
-            Ok(file) => {
-                match deserialize_from(&mut BufReader::new(file)) {
-                    Ok(snap) => Arc::new(snap),
-                    Err(e) => {
-                        println!("Backup file deserialization failed with error: {}", e);
-                        return; // fail, as this is unexpected behavior
-                    }
-                }
-            },
+    // snapshots after the first of a run only write what changed since the last, until this many of those have piled up on top of a
+    // full one. 0 for every snapshot to be full
+    let full_every: usize = match matches.get_one::<String>("full_every") {
+        Some(c) => c.trim().parse::<usize>().unwrap_or_else(|_| panic!("Messed up parsing argument {}", c)),
+        None => 10
+    };
+
+    // where entries are kept: memory, or disk for values to go in a file with only an index of them in memory
+    let backend: Backend = match matches.get_one::<String>("storage") {
+        Some(c) => c.trim().parse::<Backend>().unwrap_or_else(|e| panic!("Messed up parsing argument {}: {}", c, e)),
+        None => Backend::Memory
+    };
+
+    // the disk backend's file. picked up again at the next start
+    let store_filename: String = match matches.get_one::<String>("store_file") {
+        Some(c) => c.trim().to_string(),
+        None => format!("{}.store", snapshot_filename)
+    };
+
+    // save (or set defaults for) parameters pertaining to connections. applies to both the client and antientropy listeners
+    let max_frame_size: u64 = match matches.get_one::<String>("max_frame") {
+        Some(c) => c.trim().parse::<u64>().unwrap_or_else(|_| panic!("Messed up parsing argument {}", c)),
+        None => DEFAULT_MAX_FRAME_SIZE
+    };
+
+    let read_timeout: f64 = match matches.get_one::<String>("read_timeout") {
+        Some(c) => c.trim().parse::<f64>().unwrap_or_else(|_| panic!("Messed up parsing argument {}", c)),
+        None => 30.0 // a frame that has started arriving should be done within this
+    };
+
+    let write_timeout: f64 = match matches.get_one::<String>("write_timeout") {
+        Some(c) => c.trim().parse::<f64>().unwrap_or_else(|_| panic!("Messed up parsing argument {}", c)),
+        None => 30.0
+    };
+
+    let idle_timeout: f64 = match matches.get_one::<String>("idle_timeout") {
+        Some(c) => c.trim().parse::<f64>().unwrap_or_else(|_| panic!("Messed up parsing argument {}", c)),
+        None => 300.0 // clients may sit on an open connection between requests
+    };
+
+    let max_backlog: usize = match matches.get_one::<String>("max_backlog") {
+        Some(c) => c.trim().parse::<usize>().unwrap_or_else(|_| panic!("Messed up parsing argument {}", c)),
+        None => 64 // client connections waiting on a free worker before we start turning new ones away
+    };
+
+    // for values pushed without a ttl of their own. by default they never expire
+    let default_ttl: Option<Duration> = matches.get_one::<String>("default_ttl").map(|c| {
+        Duration::from_secs_f64(c.trim().parse::<f64>().unwrap_or_else(|_| panic!("Messed up parsing argument {}", c)))
+    });
+
+    // client connections are long lived and may idle, antientropy connections carry a single message each
+    let client_frame_config = FrameConfig {
+        max_frame_size,
+        read_timeout: Some(Duration::from_secs_f64(read_timeout)),
+        write_timeout: Some(Duration::from_secs_f64(write_timeout)),
+        idle_timeout: Some(Duration::from_secs_f64(idle_timeout)),
+    };
+    let ai_frame_config = FrameConfig { idle_timeout: Some(Duration::from_secs_f64(read_timeout)), ..client_frame_config };
+
+    // create the store. in memory that's the lock-free hashmap (effectively a ctrie afaik in that it’s implemented much like a HAMT
+    // with lock-free capabilities), on disk a file of values with the same map as an index of it
+    // wrapped in an arc as its reference will be shared across threads
+    let map: Arc<dyn Storage> = match backend {
+        Backend::Memory => Arc::new(MemoryStorage::new()),
+        Backend::Disk => match DiskStorage::open(&store_filename) {
+            Ok(disk) => Arc::new(disk),
             Err(e) => {
-                println!("Backup file failed to open with error: {}", e);
-                return; // fail, as this is unexpected behavior
+                println!("Store file failed to open with error: {}", e);
+                return;
             }
-        };
+        }
+    };
+
+    // named keys, which hold CRDTs rather than fixed values. snapshotted together with the map
+    let named: Arc<LockFreeMap<Key, Named>>;
+
+    let my_replica_id: ReplicaId = socketaddr_to_u64(&myip);
+
+    // where replay of the commit log starts, if a snapshot says. older versions kept that in the log's header instead
+    let snapshot_position: Option<u64>;
+
+    // which snapshots there are, for the snapshotter to carry on from
+    let snapshot_manifest: SnapshotManifest;
+
+    // the replica lists as of the snapshot. None if it's from before the manifest, which didn't have them
+    let snapshot_lists: Option<HashMap<ReplicaId, Vec<Key>>>;
+
+    // either load the newest good snapshot, if there's a manifest...
+    let loaded = match snapshot::load(&snapshot_filename, &*map) {
+        Ok(l) => l,
+        Err(e) => {
+            println!("Snapshot recovery failed with error: {}", e);
+            return; // fail, as this is unexpected behavior
+        }
+    };
+    if let Some(loaded) = loaded {
+        if loaded.deltas > 0 {
+            println!("Layered {} snapshot deltas on the last full snapshot.", loaded.deltas);
+        }
+        for reason in loaded.skipped.iter() {
+            println!("Fell back to an older snapshot: {}", reason);
+        }
+        named = Arc::new(loaded.named);
+        snapshot_position = Some(loaded.position);
+        snapshot_manifest = loaded.manifest;
+        snapshot_lists = Some(loaded.lists);
+    }
+    // or deserialize, if a backed up file from an older version exists...
+    else if metadata(&snapshot_filename).is_ok() {
+        snapshot_position = None;
+        snapshot_manifest = SnapshotManifest::default();
+        snapshot_lists = None;
+        if let Err(e) = snapshot::load_unmanaged(&snapshot_filename, &*map, my_replica_id) {
+            println!("{}", e);
+            return; // fail, as this is unexpected behavior
+        }
+        println!("Converted a snapshot written by an older version.");
+        named = Arc::new(LockFreeMap::new());
         // show us what exists 
         // println!("Found entries:");
         // for ref_multi in map.iter() {
@@ -121,92 +251,99 @@ fn main() {
     }
     else {
         println!("Creating backup file using provided name. Creating new map from scratch.");
-        map = Arc::new(LockFreeMap::new());
+        snapshot_position = None;
+        snapshot_manifest = SnapshotManifest::default();
+        snapshot_lists = Some(HashMap::new()); // the log, if there is one, has all of ours
+        named = Arc::new(LockFreeMap::new());
     }
 
-    let my_replica_id: ReplicaId = socketaddr_to_u64(&myip);
-
-    // populate replica map with self (get vector of currently held keys and add it)
-    let current_keys: Vec<Key> = map.iter().map(|x| *x.key()).collect::<Vec<_>>();
-    replica_map.insert(my_replica_id, Arc::new(Mutex::new(current_keys)));
-
-    // populate the replica map with neighbors
-    for neighbor in neighbors_addrs.iter() {
-        replica_map.insert(socketaddr_to_u64(&neighbor), Arc::new(Mutex::new(Vec::new())));
-    }
-
-    // open commit file, find number of existing commits
-    let num_commits: usize;
-    // println!("{} {}", commit_log_filename.trim(), metadata(commit_log_filename.trim()).is_ok());
-    if metadata(commit_log_filename).is_ok() {
-        num_commits = BufReader::new(File::open(commit_log_filename).unwrap()).lines().count()-1;
-    } else {
-        num_commits = 0;
-
-        // create file
-        let mut line_adder = OpenOptions::new()
-            .create_new(true)
-            .write(true)
-            .append(true)
-            .open(commit_log_filename)
-            .unwrap();
-
-        // add the line
-        line_adder.write(&"Snapshotted Until Line: 0000000".as_bytes()).unwrap();
-    }
-    // if num_commits < 0 { // create file prior to running
-    //     // add the line
-    //     let mut line_adder = OpenOptions::new()
-    //         .append(true)
-    //         .open(commit_log_filename)
-    //         .unwrap();
-        
-    //     line_adder.write(&"Snapshotted Until Line: 0000000".as_bytes()).unwrap();
-
-    //     num_commits = 0;
-    // }
-    // let num_commits: usize = num_commits as usize; // will now be > 0
+    // open the commit log from where the snapshot leaves off, or start one. a log from an older version is split into segments
+    // first, and a record a crash left half written is cut off
+    let recovered = match commit_log::recover(commit_log_filename, my_replica_id, snapshot_position) {
+        Ok(r) => r,
+        Err(e) => {
+            println!("Commit log recovery failed with error: {}", e);
+            return; // fail, as this is unexpected behavior
+        }
+    };
+    if recovered.converted {
+        println!("Converted a commit log written by an older version.");
+    }
+    if recovered.truncated > 0 {
+        println!("Cut a torn record ({} bytes) off the end of the commit log.", recovered.truncated);
+    }
+    let num_commits: usize = recovered.end as usize;
     println!("starting number of commits is: {}", num_commits);
 
     // using above info, make atomic counter for persister to use
     let counter_p: Arc<RelaxedCounter> = Arc::new(RelaxedCounter::new(num_commits)); // +1 because we want a new commit to start at. but keeping numcommits as the old value, just num lines - 1 very sufficient, as we only care in that case about the number of lines being considered
     let counter_s: Arc<RelaxedCounter> = counter_p.clone();
 
-    // create appender handle, which is used to append to the file as often as possible. This is used by the persister thread, to add commits
-    let file_appender = OpenOptions::new()
-        .append(true)
-        .open(&commit_log_filename)
-        .unwrap();
+    // how many times a key turned up with a value other than the one we hold. should stay at 0 with content addressing
+    let collisions: Arc<RelaxedCounter> = Arc::new(RelaxedCounter::new(0));
 
-    // create snapshotter handle, which is just used to update that number at the top.
-    let commit_log_updater = OpenOptions::new()
-        .write(true)
-        .open(&commit_log_filename)
-        .unwrap();
+    // signalled whenever keys are added to our own list in the replica map, for subscribers waiting on new ones. only ever waited on
+    // with that list's lock
+    let key_added: Arc<Condvar> = Arc::new(Condvar::new());
 
-    // roll through from last commit
-    let mut commit_unroller: BufReader<File> = BufReader::new(File::open(&commit_log_filename).unwrap());
-    
-    // get commit last snapshotted
-    let mut first_line = String::new();
-    commit_unroller.read_line(&mut first_line).unwrap();
-    let last_snapshotted_commit: usize = first_line[24..].trim().parse().unwrap();
-    let lines: Vec<String> = commit_unroller.lines().collect::<Result<_, _>>().unwrap();
-    println!("Snapshots go up until {}", last_snapshotted_commit);
-
-    // roll through the log from there
-    for c in last_snapshotted_commit..num_commits {
-        let (key, value) = lines[c].split_once(' ').unwrap();
-        
-        // add to map
-        let parsed_key = match key.parse::<Key>() {
-            Ok(k) => k,
-            Err(_) => continue
-        }; 
-        map.insert(parsed_key, Arc::new(value.to_owned()));
+    // create appender handle, which is used to append to the log as often as possible. This is used by the persister thread, to add commits
+    let log_appender = match commit_log::Appender::open(commit_log_filename, recovered.end, segment_bytes) {
+        Ok(a) => a,
+        Err(e) => {
+            println!("Commit log failed to open with error: {}", e);
+            return;
+        }
+    };
+
+    // roll through the log from the last commit snapshotted, which is where recover started reading
+    println!("Snapshots go up until {}", recovered.start);
+
+    // our list gets a key on the end for every record replayed, as it did when it was logged
+    let restored: Option<ReplicaMap> = snapshot_lists.map(|lists| restore(lists, my_replica_id, &recovered.records));
+
+    let skipped = match commit_log::replay(recovered.records, &*map, &named, my_replica_id) {
+        Ok(s) => s,
+        Err(e) => {
+            println!("Commit log replay failed with error: {}", e);
+            return; // fail, as this is unexpected behavior
+        }
+    };
+    for skipped in skipped {
+        println!("Skipped logged state of {}", skipped);
     }
 
     println!("unrolled commits");//, now map contains:");
+
+    // the replica map, for antientropy purposes. as it was if the snapshot had it, so positions peers hold in our list still mean the
+    // same thing. otherwise populate it with self (get vector of currently held keys and add it), after the replay, so what came back
+    // from the log is scanned and passed on too
+    let replica_map: Arc<ReplicaMap> = Arc::new(match restored {
+        Some(restored) => restored,
+        None => {
+            let replica_map: ReplicaMap = LockFreeMap::new();
+            let current_keys: Vec<Key> = map.keys().into_iter().chain(named.iter().map(|x| *x.key())).collect::<Vec<_>>();
+            replica_map.insert(my_replica_id, Arc::new(Mutex::new(current_keys)));
+            replica_map
+        }
+    });
+
+    // how long our list was where the log ends, for the snapshotter to know how far along it any later position is
+    let listed = Listed { position: recovered.end, len: replica_map.get(&my_replica_id).unwrap().val().lock().unwrap().len() };
+
+    // populate the replica map with neighbors we don't already have a list for
+    for neighbor in neighbors_addrs.iter() {
+        if replica_map.get(&socketaddr_to_u64(&neighbor)).is_none() {
+            replica_map.insert(socketaddr_to_u64(&neighbor), Arc::new(Mutex::new(Vec::new())));
+        }
+    }
+
+    // timestamps for named keys. kept ahead of every one we already hold, in case the system clock has gone backwards since
+    let clock: Arc<Clock> = Arc::new(Clock::new(my_replica_id));
+    for entry in named.iter() {
+        if let Some(stamp) = entry.val().state.lock().unwrap().stamp() {
+            clock.observe(&stamp);
+        }
+    }
     // for ref_multi in map.iter() {
     //     println!("{} -> {}", ref_multi.key(), ref_multi.val());
     // }
@@ -229,7 +366,7 @@ fn main() {
     let mut can_connect: bool = false;
     while !can_connect {
         for neighbor_addr in neighbors_addrs.iter() {
-            match TcpStream::connect(neighbor_addr) {
+            match TcpStream::connect(neighbor_addr) { // plain connect, neighbor's antientropy listener may not be serving yet
                 Ok(_) =>  {
                     can_connect = true;
                     break;
@@ -246,13 +383,22 @@ fn main() {
 
     // Create mpsc
     let (tx, rx) = mpsc::channel();
-    let map_snapshot_ref: Arc<LockFreeMap<String>> = map.clone();
+    let map_snapshot_ref: Arc<dyn Storage> = map.clone();
+    let map_persister_ref: Arc<dyn Storage> = map.clone();
+    let log_filename: String = commit_log_filename.to_string();
+    let named_snapshot_ref: Arc<LockFreeMap<Key, Named>> = named.clone();
 
     // dedicate one thread to committing ("persisting")
-    let persister_handle = thread::Builder::new().name("p".to_string()).spawn(move || persister(counter_p, file_appender, rx));
+    let persister_handle = thread::Builder::new().name("p".to_string()).spawn(move || persister(counter_p, map_persister_ref, log_appender, rx, durability));
 
     // dedicate one thread to snapshotting
-    let snapshotter_handle = thread::Builder::new().name("p".to_string()).spawn(move || snapshotter(counter_s, commit_log_updater, map_snapshot_ref, snapshot_filename));
+    let replica_map_snapshot_ref = replica_map.clone();
+    let snapshotter_handle = thread::Builder::new().name("p".to_string()).spawn(move || snapshotter(counter_s, map_snapshot_ref, named_snapshot_ref, replica_map_snapshot_ref, my_replica_id, listed, snapshot_filename, snapshot_manifest, log_filename, Duration::from_secs_f64(snapshot_interval), full_every));
+
+    // dedicate one thread to forgetting deleted keys once every replica has the tombstone, and expired values
+    let map_collector_ref: Arc<dyn Storage> = map.clone();
+    let replica_map_collector_ref = replica_map.clone();
+    let collector_handle = thread::Builder::new().name("gc".to_string()).spawn(move || collector(map_collector_ref, replica_map_collector_ref));
 
     // create pools for antientropy digest/update receipts 
     let digest_receipt_pool = ThreadPool::new(8);
@@ -290,7 +436,7 @@ fn main() {
             // println!("My Digest: {:?}, for {}", digest, u64_to_socketaddr(*peer));
 
             // send digest
-            let mut conn = match TcpStream::connect(u64_to_socketaddr(*peer)) {
+            let mut conn = match connect_with(u64_to_socketaddr(*peer), &ai_frame_config) {
                 Ok(c) => c,
                 Err(_) => {
                     println!("Try in a bit...");
@@ -307,31 +453,62 @@ fn main() {
 
     let clh_tx_clone = tx.clone();
     let clh_map_clone = Arc::clone(&map);
+    let clh_collisions = Arc::clone(&collisions);
+    let clh_key_added = Arc::clone(&key_added);
+    let clh_named = Arc::clone(&named);
+    let clh_clock = Arc::clone(&clock);
     let client_listener_handle = thread::Builder::new().name("clh".to_string()).spawn(move || {
         // iterate through each connection, very simply!
         for stream in client_listener.incoming() {
             let stream = stream.unwrap();
+
+            // every worker is busy and enough connections are already waiting, tell this one to come back later.
+            // off this thread, as it still needs a handshake and we don't want a slow client holding up accepts
+            if client_pool.queued() >= max_backlog {
+                thread::spawn(move || reject_overloaded(stream, client_frame_config));
+                continue;
+            }
+
             let map_clone = Arc::clone(&clh_map_clone);
             let replica_map_clone = Arc::clone(&replica_map);
             let tx_clone = clh_tx_clone.clone();
+            let collisions_clone = Arc::clone(&clh_collisions);
+            let key_added_clone = Arc::clone(&clh_key_added);
+            let named_clone = Arc::clone(&clh_named);
+            let clock_clone = Arc::clone(&clh_clock);
 
             // invoke a thread from the pool, run the closure within
             client_pool.execute(move || {
-                handle_request(stream, map_clone, replica_map_clone, my_replica_id, tx_clone);
+                handle_request(stream, map_clone, named_clone, replica_map_clone, my_replica_id, tx_clone, collisions_clone, key_added_clone, clock_clone, default_ttl, durability, client_frame_config);
             });
         }
     });
 
     let alh_tx_clone = tx.clone();
     let alh_map_clone = Arc::clone(&map);
+    let alh_collisions = Arc::clone(&collisions);
+    let alh_key_added = Arc::clone(&key_added);
+    let alh_named = Arc::clone(&named);
     let ai_listener_handle = thread::Builder::new().name("alh".to_string()).spawn(move || {
         // iterate through each connection, very simply!
         for stream in ai_listener.incoming() {
             // println!("here2");
             let mut stream = stream.unwrap();
 
+            // a slow or silent peer should only hold up this loop for so long
+            if let Err(e) = ai_frame_config.apply(&stream) {
+                println!("Antientropy connection setup failed with Error: {}", e);
+                continue;
+            }
+
+            // make sure our versions overlap before anything else
+            if let Err(e) = server_handshake(&mut stream) {
+                println!("Antientropy handshake failed with Error: {}", e);
+                continue;
+            }
+
             // get the value
-            let message = match receive_message(&mut stream) {
+            let message = match receive_message_with(&mut stream, &ai_frame_config) {
                 Ok(msg) => msg,
                 Err(e) => {
                     println!("Digest Message Receipt failed with Error: {}", e);
@@ -343,6 +520,10 @@ fn main() {
             let rep = replica_map_ref_ai.clone();
             let sr = update_rate.clone();
             let tx_clone = alh_tx_clone.clone();
+            let collisions_clone = Arc::clone(&alh_collisions);
+            let key_added_clone = Arc::clone(&alh_key_added);
+            let nc = Arc::clone(&alh_named);
+            let clock_clone = Arc::clone(&clock);
 
             // now act
             match message {
@@ -350,7 +531,7 @@ fn main() {
                 Message::DigestMessage(id, digest) => {
                     // println!("received digest from {}", id);
                     digest_receipt_pool.execute(move || {
-                        handle_digest(stream, mc, rep, id, my_replica_id, digest, sr);
+                        handle_digest(stream, mc, nc, rep, id, my_replica_id, digest, sr, ai_frame_config);
                     });
                 }
                 
@@ -358,13 +539,13 @@ fn main() {
                 Message::UpdateMessage(id, update) => {
                     // println!("received update from {}", id);
                     update_receipt_pool.execute(move || {
-                        handle_update(stream, mc, rep, my_replica_id, id, update, tx_clone.clone());
+                        handle_update(stream, mc, nc, rep, my_replica_id, id, update, tx_clone.clone(), collisions_clone, key_added_clone, clock_clone);
                     });
                 }
 
                 _ => {
                     println!("Unexpected message received.");
-                    match send_message(&mut stream, Message::Error("Invalid Message Sent.".to_string())) {
+                    match send_message(&mut stream, Message::Error{ code: ErrorCode::UnknownMessage, detail: Some("Invalid Message Sent.".to_string()) }) {
                         _ => ()
                     };
                 }
@@ -375,15 +556,110 @@ fn main() {
     digest_forward_handle.unwrap().join().unwrap();
     persister_handle.unwrap().join().unwrap();
     snapshotter_handle.unwrap().join().unwrap();
+    collector_handle.unwrap().join().unwrap();
     client_listener_handle.unwrap().join().unwrap();
     ai_listener_handle.unwrap().join().unwrap();
 }
 
-fn handle_request(mut stream: TcpStream, map: Arc<LockFreeMap<String>>, replica_map: Arc<LockFreeMap<Mutex<Vec<Key>>>>, local_replica_id: ReplicaId, queue: mpsc::Sender<Commit>) {
+// answers a write with resp and hands its commits to the persister, their keys on the end of our list on the way. in memory mode
+// the response goes first, to reduce staleness. otherwise it waits until the persister is done with them and everything queued
+// ahead of them. an error has nothing to wait on. the commits go ahead whether or not the client is still there to hear
+#[allow(clippy::too_many_arguments)]
+fn commit_and_respond(stream: &mut TcpStream, id: Option<RequestId>, resp: Message, commits: Vec<Commit>, replica_map: &ReplicaMap, local_replica_id: ReplicaId, key_added: &Condvar, queue: &mpsc::Sender<Persist>, durability: Durability) -> Result<(), FrameError> {
+    let added = !commits.is_empty();
+    if durability == Durability::Memory || matches!(resp, Message::Error{..}) {
+        let sent = send_message_tagged(stream, id, resp);
+        if added {
+            // the client has its answer already, all that's left is to say so
+            if let Err(e) = enlist(replica_map, local_replica_id, Persist::new(commits), queue) {
+                println!("Couldn't queue a write for the commit log: {}", e);
+            }
+            key_added.notify_all();
+        }
+        return sent;
+    }
+
+    // done is dropped without a word if the persister couldn't log them, or is gone
+    let (done, persisted) = mpsc::channel();
+    let sent = enlist(replica_map, local_replica_id, Persist { commits, done: Some(done) }, queue);
+    if added {
+        key_added.notify_all();
+    }
+    let resp = match sent.map(|()| persisted.recv()) {
+        Ok(Ok(())) => resp,
+        _ => Message::Error{ code: ErrorCode::StorageFailure, detail: Some("Couldn't write the commit log, the write may not survive a restart.".to_string()) },
+    };
+    send_message_tagged(stream, id, resp)
+}
+
+// what a client hears when the store couldn't read or write what it asked for. it's retryable, the disk may come back
+fn storage_failure(e: io::Error) -> Message {
+    Message::Error{ code: ErrorCode::StorageFailure, detail: Some(e.to_string()) }
+}
+
+// the chunks of manifest, stored under key, that no other value we hold is made of. a chunk is also just a value under its own key,
+// so the same bytes pushed on their own go too
+fn unshared_chunks(map: &dyn Storage, key: &Key, manifest: &Manifest) -> io::Result<Vec<Key>> {
+    let mut chunks: HashSet<Key> = manifest.chunks.iter().copied().collect();
+    for held in map.iter() {
+        let (other, entry) = held?;
+        if other == *key {
+            continue;
+        }
+        if let Some(other) = entry.live().and_then(|value| Manifest::stored_as(&other, value)) {
+            for chunk in other.chunks.iter() {
+                chunks.remove(chunk);
+            }
+        }
+    }
+    // in the manifest's order, which is the order they went in
+    Ok(manifest.chunks.iter().filter(|c| chunks.remove(c)).copied().collect())
+}
+
+fn reject_overloaded(mut stream: TcpStream, frame_config: FrameConfig) {
+    if frame_config.apply(&stream).is_err() || server_handshake(&mut stream).is_err() {
+        return;
+    }
+    let _ = send_message(&mut stream, Message::Error{ code: ErrorCode::Overloaded, detail: Some("Too many queued connections, try again later.".to_string()) });
+}
+
+#[allow(clippy::too_many_arguments)]
+fn handle_request(mut stream: TcpStream, map: Arc<dyn Storage>, named: Arc<LockFreeMap<Key, Named>>, replica_map: Arc<LockFreeMap<ReplicaId, Mutex<Vec<Key>>>>, local_replica_id: ReplicaId, queue: mpsc::Sender<Persist>, collisions: Arc<RelaxedCounter>, key_added: Arc<Condvar>, clock: Arc<Clock>, default_ttl: Option<Duration>, durability: Durability, frame_config: FrameConfig) {
+    // bound how long a slow or silent client can hold on to this worker
+    if let Err(e) = frame_config.apply(&stream) {
+        println!("Connection setup failed with Error: {}", e);
+        return;
+    }
+
+    // make sure our versions overlap before anything else
+    if let Err(e) = server_handshake(&mut stream) {
+        println!("Handshake failed with Error: {}", e);
+        return;
+    }
+
+    // for scans on this connection, caught up as they go
+    let mut first_listed = FirstListed::default();
+
     loop {
         // println!("entering loop");
-        let message = match receive_message(&mut stream) {
-            Ok(msg) => msg,
+        // the id (if any) goes back on whatever we answer with, so clients can have many requests in flight on one connection
+        let (id, message) = match receive_message_tagged_with(&mut stream, &frame_config) {
+            Ok(tagged) => tagged,
+            Err(FrameError::Closed) => return, // client hung up between requests
+            Err(FrameError::TooLarge { len, max }) => {
+                // the rest of that frame is still on the wire, so there is no way to pick up at the next one. tell them and hang up
+                let _ = send_message(&mut stream, Message::Error{ code: ErrorCode::FrameTooLarge, detail: Some(format!("Frame of {} bytes exceeds the limit of {}.", len, max)) });
+                return;
+            },
+            Err(FrameError::Decode(e)) => {
+                // the whole frame was read, so the connection is still usable. most likely a request type from a newer client
+                let _ = send_message(&mut stream, Message::Error{ code: ErrorCode::UnknownMessage, detail: Some(e) });
+                continue;
+            },
+            Err(FrameError::UnsupportedVersion { version, min, max }) => {
+                let _ = send_message(&mut stream, Message::Error{ code: ErrorCode::UnsupportedVersion, detail: Some(format!("Frame version {} is outside of {}..={}.", version, min, max)) });
+                continue;
+            },
             Err(e) => {
                 println!("Failed with Error: {}", e);
                 return;
@@ -392,59 +668,199 @@ fn handle_request(mut stream: TcpStream, map: Arc<LockFreeMap<String>>, replica_
         // println!("[{:#?}] Request: {:#?}", thread::current().id(), message);
 
         match message {
-            Message::PushReq(KVPair {key, value}) => {
+            Message::PushReq(KVPair {key, value}, ttl) => {
                 // println!("Pushing key-value pair from client...");
 
-                // make sure the hash is correct
-                let mut hash = DefaultHasher::new();
-                value.to_string().hash(&mut hash);
-                let hashed: Key = hash.finish(); 
-
-                if hashed != key {
+                // make sure the hash is correct, with whichever algorithm the key was made with
+                let matches = match content_matches(&*map, &key, &value) {
+                    Ok(m) => m,
+                    Err(e) => {
+                        if let Err(e) = send_message_tagged(&mut stream, id, storage_failure(e)) {
+                            println!("Couldn't answer the client, dropping the connection: {}", e);
+                            return;
+                        }
+                        continue;
+                    }
+                };
+                if !matches {
                     // write response
-                    let resp = Message::Error("Hash of value doesn't match.".to_string());
-                    send_message(&mut stream, resp).unwrap();
+                    let resp = Message::Error{ code: ErrorCode::HashMismatch, detail: Some("Hash of value doesn't match, or it's a manifest with chunks we don't have.".to_string()) };
+                    if let Err(e) = send_message_tagged(&mut stream, id, resp) {
+                        println!("Couldn't answer the client, dropping the connection: {}", e);
+                        return;
+                    }
                     // thread::sleep(time::Duration::from_secs(5)); // not a problem as it relegates this functionality to persister thread
                 }
                 else {
                     // add to map
-                    let map_val = Arc::new(value);
-                    let queue_val = map_val.clone();
-                    let result = map.insert(key, map_val);
+                    let meta = EntryMeta::new(local_replica_id).expiring(ttl.or(default_ttl));
+                    let map_val = Arc::new(Entry::new(value, meta));
+                    let result = match store(&*map, key, map_val) {
+                        Ok(r) => r,
+                        Err(e) => {
+                            if let Err(e) = send_message_tagged(&mut stream, id, storage_failure(e)) {
+                                println!("Couldn't answer the client, dropping the connection: {}", e);
+                                return;
+                            }
+                            continue;
+                        }
+                    };
 
                     // write response
-                    let resp = Message::PushResp{ success: true };
-                    send_message(&mut stream, resp).unwrap();
-                    
-                    match result {
-                        Some(_) => (), // already in map, don't commit
-                        None => {
-                            // add to commit log
-                            queue.send(Commit{key: hashed, value: queue_val, timestamp: SystemTime::now()}).unwrap(); //new, so send to persister, want to do after response to reduce staleness
-
-                            // update local replica map entry for this node
-                            match replica_map.get(&local_replica_id) {
-                                Some(lookup) => {
-                                    lookup.val().lock().unwrap().push(hashed);
-                                }
-                                None => {
-                                    // didn't find own key in replica map - should be impossible
-                                    println!("Replica map is missing self key...returning...");
-                                    return;
-                                }
-                            };
+                    let resp = match result {
+                        InsertOutcome::Collision(ref held) if held.is_deleted() => {
+                            Message::Error{ code: ErrorCode::KeyDeleted, detail: Some(format!("{} was deleted, and not every replica has dropped it yet.", key)) }
+                        },
+                        InsertOutcome::Collision(_) => {
+                            println!("Key collision on push of {}, {} so far", key, collisions.inc() + 1);
+                            Message::Error{ code: ErrorCode::KeyCollision, detail: Some(format!("A different value is already stored under {}.", key)) }
+                        },
+                        _ => Message::PushResp{ success: true },
+                    };
+
+                    // new, so send to persister. a duplicate is already in map, don't commit, but it still waits on what's ahead of it in
+                    // the queue, which may be the copy it duplicates
+                    let commits = match result {
+                        InsertOutcome::Inserted => vec![Commit::Put{key, meta}],
+                        _ => Vec::new(),
+                    };
+                    if let Err(e) = commit_and_respond(&mut stream, id, resp, commits, &replica_map, local_replica_id, &key_added, &queue, durability) {
+                        println!("Couldn't answer the client, dropping the connection: {}", e);
+                        return;
+                    }
+                }
+            },
+
+            Message::PutReq{ value, ttl } => {
+                // we make the key ourselves, so there's nothing to check
+                let key: Key = hash_value(&value);
+
+                // add to map
+                let meta = EntryMeta::new(local_replica_id).expiring(ttl.or(default_ttl));
+                let map_val = Arc::new(Entry::new(value, meta));
+                let result = match store(&*map, key, map_val) {
+                    Ok(r) => r,
+                    Err(e) => {
+                        if let Err(e) = send_message_tagged(&mut stream, id, storage_failure(e)) {
+                            println!("Couldn't answer the client, dropping the connection: {}", e);
+                            return;
                         }
+                        continue;
                     }
+                };
+
+                // write response
+                let resp = match result {
+                    InsertOutcome::Collision(ref held) if held.is_deleted() => {
+                        Message::Error{ code: ErrorCode::KeyDeleted, detail: Some(format!("{} was deleted, and not every replica has dropped it yet.", key)) }
+                    },
+                    InsertOutcome::Collision(_) => {
+                        println!("Key collision on put of {}, {} so far", key, collisions.inc() + 1);
+                        Message::Error{ code: ErrorCode::KeyCollision, detail: Some(format!("A different value is already stored under {}.", key)) }
+                    },
+                    _ => Message::PutResp{ key },
+                };
+
+                // add to commit log
+                let commits = match result {
+                    InsertOutcome::Inserted => vec![Commit::Put{key, meta}],
+                    _ => Vec::new(),
+                };
+                if let Err(e) = commit_and_respond(&mut stream, id, resp, commits, &replica_map, local_replica_id, &key_added, &queue, durability) {
+                    println!("Couldn't answer the client, dropping the connection: {}", e);
+                    return;
+                }
+            },
+
+            Message::PushBatchReq(pairs, ttl) => {
+                let mut results: Vec<PushResult> = Vec::with_capacity(pairs.len());
+                let mut commits: Vec<Commit> = Vec::new();
+                let mut failed: Option<io::Error> = None;
+
+                for KVPair {key, value} in pairs {
+                    // make sure the hash is correct, per item so one bad value doesn't sink the rest. chunks go earlier in the batch than
+                    // their manifest
+                    match content_matches(&*map, &key, &value) {
+                        Ok(true) => (),
+                        Ok(false) => {
+                            results.push(PushResult::HashMismatch);
+                            continue;
+                        },
+                        Err(e) => {
+                            failed = Some(e);
+                            break;
+                        }
+                    }
+
+                    // add to map
+                    let meta = EntryMeta::new(local_replica_id).expiring(ttl.or(default_ttl));
+                    let map_val = Arc::new(Entry::new(value, meta));
+                    match store(&*map, key, map_val) {
+                        Ok(InsertOutcome::Duplicate) => results.push(PushResult::Duplicate), // already in map, don't commit
+                        Ok(InsertOutcome::Collision(held)) if held.is_deleted() => results.push(PushResult::Deleted),
+                        Ok(InsertOutcome::Collision(_)) => {
+                            println!("Key collision on push of {}, {} so far", key, collisions.inc() + 1);
+                            results.push(PushResult::Collision);
+                        },
+                        Ok(InsertOutcome::Inserted) => {
+                            results.push(PushResult::Accepted);
+                            commits.push(Commit::Put{key, meta});
+                        },
+                        Err(e) => {
+                            failed = Some(e);
+                            break;
+                        }
+                    }
+                }
+
+                // hand the whole batch to the persister at once. whatever was stored before a failure is still committed, the client
+                // only hears of the failure, and pushing the batch again gets it duplicates for those
+                let resp = match failed {
+                    Some(e) => storage_failure(e),
+                    None => Message::PushBatchResp(results),
+                };
+                if let Err(e) = commit_and_respond(&mut stream, id, resp, commits, &replica_map, local_replica_id, &key_added, &queue, durability) {
+                    println!("Couldn't answer the client, dropping the connection: {}", e);
+                    return;
+                }
+            },
+
+            Message::MultiRetrieveReq(keys) => {
+                let results: io::Result<Vec<FoundValue>> = keys.iter().map(|key| match map.get(key)?.as_ref().and_then(|v| v.live()) {
+                    Some(value) => Ok(FoundValue::Success { value: value.clone() }),
+                    None => Ok(FoundValue::Failure),
+                }).collect();
+
+                // return a MultiRetrieveResp
+                let resp = match results {
+                    Ok(results) => Message::MultiRetrieveResp(results),
+                    Err(e) => storage_failure(e),
+                };
+
+                // write response
+                if let Err(e) = send_message_tagged(&mut stream, id, resp) {
+                    println!("Couldn't answer the client, dropping the connection: {}", e);
+                    return;
                 }
             },
 
             Message::RetrieveReq { key } => {
-                let lookup = map.get(&key);
+                let lookup = match map.get(&key) {
+                    Ok(l) => l,
+                    Err(e) => {
+                        if let Err(e) = send_message_tagged(&mut stream, id, storage_failure(e)) {
+                            println!("Couldn't answer the client, dropping the connection: {}", e);
+                            return;
+                        }
+                        continue;
+                    }
+                };
 
-                let resp = match lookup {
-                    Some(v) => {
+                // deleted keys are as good as missing
+                let resp = match lookup.as_ref().and_then(|v| v.live()) {
+                    Some(value) => {
                         // expensive copy needed because cannot serialize otherwise for sending..., even with feature flags: https://serde.rs/feature-flags.html
-                        Message::RetrieveResp{ result: FoundValue::Success { value: v.val().to_string() }}
+                        Message::RetrieveResp{ result: FoundValue::Success { value: value.clone() }}
                     }
                     None => {
                         Message::RetrieveResp{ result: FoundValue::Failure }
@@ -452,35 +868,232 @@ fn handle_request(mut stream: TcpStream, map: Arc<LockFreeMap<String>>, replica_
                 };
 
                 // write response
-                send_message(&mut stream, resp).unwrap();
+                if let Err(e) = send_message_tagged(&mut stream, id, resp) {
+                    println!("Couldn't answer the client, dropping the connection: {}", e);
+                    return;
+                }
 
             },
 
-            Message::DumpReq => { // can fail at a certain size on client side
+            Message::StatReq { key } => {
+                // just the metadata. nothing for deleted or expired keys
+                let resp = match map.get(&key) {
+                    Ok(found) => Message::StatResp { meta: found.filter(|v| v.live().is_some()).map(|v| v.meta) },
+                    Err(e) => storage_failure(e),
+                };
+
+                // write response
+                if let Err(e) = send_message_tagged(&mut stream, id, resp) {
+                    println!("Couldn't answer the client, dropping the connection: {}", e);
+                    return;
+                }
+            },
+
+            Message::DumpReq => { // can fail at a certain size on client side, prefer ScanReq
                 // make vector
                 let mut dumped: Vec<KVPair> = Vec::new();
 
                 // iterate through store
-                for pair in map.iter() {
-                    dumped.push(KVPair { key: *pair.key(), value: pair.val().to_string() })
+                let mut failed: Option<io::Error> = None;
+                for read in map.iter() {
+                    match read {
+                        Ok((key, entry)) => if let Some(value) = entry.live() {
+                            dumped.push(KVPair { key, value: value.clone() })
+                        },
+                        Err(e) => {
+                            failed = Some(e);
+                            break;
+                        }
+                    }
                 }
 
                 // return a DumpResp
-                let resp = Message::DumpResp(dumped);
+                let resp = match failed {
+                    Some(e) => storage_failure(e),
+                    None => Message::DumpResp(dumped),
+                };
 
                 // write response
-                send_message(&mut stream, resp).unwrap();
+                if let Err(e) = send_message_tagged(&mut stream, id, resp) {
+                    println!("Couldn't answer the client, dropping the connection: {}", e);
+                    return;
+                }
             },
 
             Message::DumpLenReq => {
-                // get length
-                let len = map.iter().count();
+                // get length, going by what the store knows without reading values. everything but tombstones and what's expired
+                let len = map.len().saturating_sub(map.tombstones().len() + map.expired().len());
 
                 // return a DumpLenResp
                 let resp = Message::DumpLenResp(len);
 
                 // write response
-                send_message(&mut stream, resp).unwrap();
+                if let Err(e) = send_message_tagged(&mut stream, id, resp) {
+                    println!("Couldn't answer the client, dropping the connection: {}", e);
+                    return;
+                }
+            },
+
+            Message::ScanReq { cursor, limit, keys_only } => {
+                if limit == 0 {
+                    if let Err(e) = send_message_tagged(&mut stream, id, Message::Error{ code: ErrorCode::InvalidRequest, detail: Some("Scan limit must be at least 1.".to_string()) }) {
+                        println!("Couldn't answer the client, dropping the connection: {}", e);
+                        return;
+                    }
+                    continue;
+                }
+                let limit = limit.min(MAX_SCAN_LIMIT);
+
+                // our own list holds every key in the store, in the order we got it, and only ever grows. so a position in it stays valid between requests
+                // a key is on it again for every delete, re-push and change of name, so only its first place counts. named keys
+                // aren't in the store at all. copy the page of keys out so we don't hold the lock while reading values
+                let (keys, end, len): (Vec<Key>, usize, usize) = {
+                    let guard = replica_map.get(&local_replica_id).unwrap();
+                    let local_keys = guard.val().lock().unwrap();
+                    let start = cursor.min(local_keys.len());
+                    let end = (start + limit).min(local_keys.len());
+                    (first_listed.firsts(&local_keys, start, end), end, local_keys.len())
+                };
+
+                let mut items: Vec<ScanItem> = Vec::with_capacity(keys.len());
+                let mut failed: Option<io::Error> = None;
+                // deleted keys stay in the list but are left out of the page, as are expired ones
+                for key in keys.iter().filter(|key| key.algorithm() != HashAlgorithm::Name) {
+                    match map.get(key) {
+                        Ok(found) => if let Some(value) = found.as_ref().and_then(|entry| entry.live()) {
+                            items.push(ScanItem { key: *key, value: if keys_only { None } else { Some(value.clone()) } });
+                        },
+                        Err(e) => {
+                            failed = Some(e);
+                            break;
+                        }
+                    }
+                }
+
+                // None once the caller has seen everything we had at the time of this request
+                let next_cursor = if end < len { Some(end) } else { None };
+
+                // return a ScanResp
+                let resp = match failed {
+                    Some(e) => storage_failure(e),
+                    None => Message::ScanResp { items, next_cursor },
+                };
+
+                // write response
+                if let Err(e) = send_message_tagged(&mut stream, id, resp) {
+                    println!("Couldn't answer the client, dropping the connection: {}", e);
+                    return;
+                }
+            },
+
+            Message::DeleteReq { key } => {
+                // already deleted here, nothing more to do. nothing held is still worth a tombstone, other replicas may have it
+                let held = match map.get(&key) {
+                    Ok(Some(v)) if v.is_deleted() => {
+                        if let Err(e) = send_message_tagged(&mut stream, id, Message::DeleteResp{ found: false }) {
+                            println!("Couldn't answer the client, dropping the connection: {}", e);
+                            return;
+                        }
+                        continue;
+                    },
+                    Ok(held) => held,
+                    Err(e) => {
+                        if let Err(e) = send_message_tagged(&mut stream, id, storage_failure(e)) {
+                            println!("Couldn't answer the client, dropping the connection: {}", e);
+                            return;
+                        }
+                        continue;
+                    }
+                };
+
+                // a large value's chunks go with its manifest, or they'd be held everywhere for good
+                let mut keys = vec![key];
+                if let Some(manifest) = held.as_ref().and_then(|v| v.live()).and_then(|value| Manifest::stored_as(&key, value)) {
+                    match unshared_chunks(&*map, &key, &manifest) {
+                        Ok(chunks) => keys.extend(chunks),
+                        Err(e) => {
+                            if let Err(e) = send_message_tagged(&mut stream, id, storage_failure(e)) {
+                                println!("Couldn't answer the client, dropping the connection: {}", e);
+                                return;
+                            }
+                            continue;
+                        }
+                    }
+                }
+
+                // replace whatever is there, the value goes with it. the ones that did go still need passing on if the rest didn't
+                let meta = EntryMeta::new(local_replica_id);
+                let mut commits: Vec<Commit> = Vec::new();
+                let mut resp = Message::DeleteResp{ found: held.is_some() };
+                for key in keys {
+                    if let Err(e) = map.insert(key, Arc::new(Entry::tombstone(meta, HashSet::from([local_replica_id])))) {
+                        resp = storage_failure(e);
+                        break;
+                    }
+                    commits.push(Commit::Delete{key, meta});
+                }
+
+                // add to commit log. the key goes on the end of our list again on the way, which is how the delete gets to other
+                // replicas (and into the next snapshot)
+                if let Err(e) = commit_and_respond(&mut stream, id, resp, commits, &replica_map, local_replica_id, &key_added, &queue, durability) {
+                    println!("Couldn't answer the client, dropping the connection: {}", e);
+                    return;
+                }
+            },
+
+            Message::UpdateNamedReq { name, op } => {
+                let stamp = clock.now();
+
+                // made with op already applied if it's new, otherwise applied to what's there
+                let (entry, created) = named_entry(&named, &name, || Crdt::from_op(&op, local_replica_id, stamp));
+                let applied = match created {
+                    true => Ok(()),
+                    false => entry.state.lock().unwrap().apply(&op, local_replica_id, stamp),
+                };
+
+                if let Err(e) = applied {
+                    if let Err(e) = send_message_tagged(&mut stream, id, Message::Error{ code: ErrorCode::InvalidRequest, detail: Some(e) }) {
+                        println!("Couldn't answer the client, dropping the connection: {}", e);
+                        return;
+                    }
+                    continue;
+                }
+
+                // add to commit log. the name goes on the end of our list on the way when it changes, which is how other replicas
+                // (and the next snapshot) hear of it. unless it's still there waiting to be logged, which does for this change too
+                if let Err(e) = commit_and_respond(&mut stream, id, Message::UpdateNamedResp, vec![Commit::Named{named: entry}], &replica_map, local_replica_id, &key_added, &queue, durability) {
+                    println!("Couldn't answer the client, dropping the connection: {}", e);
+                    return;
+                }
+            },
+
+            Message::ReadNamedReq { name } => {
+                let value = named.get(&name_key(&name)).map(|entry| entry.val().state.lock().unwrap().value());
+
+                // write response
+                if let Err(e) = send_message_tagged(&mut stream, id, Message::ReadNamedResp { value }) {
+                    println!("Couldn't answer the client, dropping the connection: {}", e);
+                    return;
+                }
+            },
+
+            Message::SubscribeReq { from_index, with_values } => {
+                let local_keys = match replica_map.get(&local_replica_id) {
+                    Some(lookup) => lookup.val().clone(),
+                    None => {
+                        // didn't find own key in replica map - should be impossible
+                        println!("Replica map is missing self key...returning...");
+                        return;
+                    }
+                };
+
+                // the connection is a one way feed from here on, for as long as the subscriber stays. give it its own thread rather
+                // than tying up a worker
+                let map = map.clone();
+                let named = named.clone();
+                let key_added = key_added.clone();
+                thread::spawn(move || subscribe(stream, id, map, named, local_keys, key_added, from_index, with_values));
+                return;
             },
 
             Message::ClusterReq => {
@@ -491,7 +1104,10 @@ fn handle_request(mut stream: TcpStream, map: Arc<LockFreeMap<String>>, replica_
                 let resp = Message::ClusterResp(nodes);
 
                 // write response
-                send_message(&mut stream, resp).unwrap();
+                if let Err(e) = send_message_tagged(&mut stream, id, resp) {
+                    println!("Couldn't answer the client, dropping the connection: {}", e);
+                    return;
+                }
             },
 
             Message::ConnectionClosed => {
@@ -499,17 +1115,25 @@ fn handle_request(mut stream: TcpStream, map: Arc<LockFreeMap<String>>, replica_
             },
 
             _ => {
+                // valid message, just not one clients get to send (antientropy traffic on the wrong port, or a response)
                 println!("unrecognized message type");
-                return;
+                if let Err(e) = send_message_tagged(&mut stream, id, Message::Error{ code: ErrorCode::UnknownMessage, detail: None }) {
+                    println!("Couldn't answer the client, dropping the connection: {}", e);
+                    return;
+                }
             }
         }
     }
 }
 
 // handles antientropy digests
-fn handle_digest(mut _stream: TcpStream, map: Arc<LockFreeMap<String>>, replica_map: Arc<LockFreeMap<Mutex<Vec<Key>>>>, sender: ReplicaId, local_replica_id: ReplicaId, mut digest: Vec<DigestPair>, sending_rate: Arc<RwLock<f64>>) {
+#[allow(clippy::too_many_arguments)]
+fn handle_digest(mut _stream: TcpStream, map: Arc<dyn Storage>, named: Arc<LockFreeMap<Key, Named>>, replica_map: Arc<LockFreeMap<ReplicaId, Mutex<Vec<Key>>>>, sender: ReplicaId, local_replica_id: ReplicaId, mut digest: Vec<DigestPair>, sending_rate: Arc<RwLock<f64>>, frame_config: FrameConfig) {
     let mut keys: HashSet<Key> = HashSet::new();
+    // what's under them, read once. off disk that's the expensive part, and it's needed for the size as well as to send
+    let mut fetched: HashMap<Key, Arc<Entry>> = HashMap::new();
     let mut host_keys: HashMap<ReplicaId, Vec<(Key, usize)>> = HashMap::new();
+    let mut value_bytes: usize = 0;
 
     // shuffle the order of replicas in the digest
     digest.shuffle(&mut thread_rng());
@@ -525,7 +1149,7 @@ fn handle_digest(mut _stream: TcpStream, map: Arc<LockFreeMap<String>>, replica_
             }
             else if len > pair.keys {
                 // message cannot be too big
-                if keys.len() >= 250 {
+                if keys.len() >= 250 || value_bytes >= UPDATE_VALUE_BYTES {
                     break;
                 }
 
@@ -544,10 +1168,23 @@ fn handle_digest(mut _stream: TcpStream, map: Arc<LockFreeMap<String>>, replica_
                 //     }
                 // }
                 for i in pair.keys..len {
-                    if keys.len() >= 250 {
+                    if keys.len() >= 250 || value_bytes >= UPDATE_VALUE_BYTES {
                         break;
                     }
-                    keys.insert(cached[i]);
+                    if keys.insert(cached[i]) {
+                        match map.get(&cached[i]) {
+                            Ok(Some(entry)) => {
+                                value_bytes += entry.value.len();
+                                fetched.insert(cached[i], entry);
+                            },
+                            Ok(None) => (),
+                            Err(e) => {
+                                // the peer would take the key as sent, send nothing this round and let the next one try again
+                                println!("Couldn't answer the digest from {}: {}", u64_to_socketaddr(sender), e);
+                                return;
+                            }
+                        }
+                    }
                     v.push((cached[i], i));
                 }
                 host_keys.insert(pair.replica_id, v);
@@ -559,46 +1196,195 @@ fn handle_digest(mut _stream: TcpStream, map: Arc<LockFreeMap<String>>, replica_
         }
     }
 
-    // go through set and make kv pairs
-    let kvpairs: Vec<KVPair> = keys.iter().filter_map(|x| {
-        match map.get(x) {
-            Some(val) => Some(KVPair{key: *x, value: val.val().to_string()}),
-            None => {
-                panic!("Messed up because of key {} while responding to digest from {}. Digest was {:?}, keys were {:?}.", *x, sender, digest, keys);
+    // go through set and make kv pairs, or tombstones for deleted keys
+    let mut kvpairs: Vec<(KVPair, EntryMeta)> = Vec::new();
+    let mut tombstones: Vec<Tombstone> = Vec::new();
+    // a key that isn't in the map at all was deleted, and every replica we know of has heard, so there's nothing to pass on. or it's
+    // a name, which goes as its whole state
+    let mut named_states: Vec<(String, Crdt)> = Vec::new();
+    for x in keys.iter() {
+        if let Some(entry) = named.get(x) {
+            named_states.push((entry.val().name.clone(), entry.val().state.lock().unwrap().clone()));
+        }
+
+        if let Some(val) = fetched.remove(x) {
+            match &val.deleted {
+                Some(acked) => tombstones.push(Tombstone{key: *x, meta: val.meta, acked: acked.lock().unwrap().iter().copied().collect()}),
+                None if val.is_expired() => (), // the peer drops it at the same time we do, so don't bring it back
+                None => kvpairs.push((KVPair{key: *x, value: val.value.clone()}, val.meta)),
             }
         }
-    }).collect(); //unwrap safe here as the key is necessarily in the map if we identified it earlier - we only go through keys we know we have and we never delete
+    }
 
     // construct struct
-    let resp_struct: UpdateMessage = UpdateMessage { sending_rate: *sending_rate.read().unwrap() as f64, replica_keys: host_keys, key_values: kvpairs };
+    let resp_struct: UpdateMessage = UpdateMessage { sending_rate: *sending_rate.read().unwrap() as f64, replica_keys: host_keys, key_values: kvpairs, tombstones, named: named_states };
 
     // send response
-    match send_message(&mut TcpStream::connect(u64_to_socketaddr(sender)).unwrap(), Message::UpdateMessage(local_replica_id, resp_struct)){
+    let mut conn = match connect_with(u64_to_socketaddr(sender), &frame_config) {
+        Ok(c) => c,
+        Err(e) => {
+            println!("Failed connecting to {} with {}", sender, e);
+            return;
+        }
+    };
+    match send_message(&mut conn, Message::UpdateMessage(local_replica_id, resp_struct)){
         Ok(()) => return,
         Err(s) => println!("Failed sending with {}", s)
     };
 }
 
+// feeds our own list of keys to a subscriber, from from_index on. the list only ever grows, so an index always means the same key
+// (until a restart rebuilds it). deletes add the key again. a subscriber that disconnects while nothing is arriving is only noticed
+// on the next key
+#[allow(clippy::too_many_arguments)]
+fn subscribe(mut stream: TcpStream, id: Option<RequestId>, map: Arc<dyn Storage>, named: Arc<LockFreeMap<Key, Named>>, local_keys: Arc<Mutex<Vec<Key>>>, key_added: Arc<Condvar>, from_index: usize, with_values: bool) {
+    let mut next = from_index;
+
+    // can't resume from past the end of what we have
+    let len = local_keys.lock().unwrap().len();
+    if next > len {
+        let resp = Message::Error{ code: ErrorCode::InvalidRequest, detail: Some(format!("Index {} is past the {} keys held.", next, len)) };
+        let _ = send_message_tagged(&mut stream, id, resp);
+        return;
+    }
+
+    loop {
+        // wait until there's something we haven't sent, then copy a page of it out so we don't hold the lock while writing
+        let keys: Vec<Key> = {
+            let mut guard = local_keys.lock().unwrap();
+            while guard.len() <= next {
+                guard = key_added.wait(guard).unwrap();
+            }
+            let end = (next + MAX_SCAN_LIMIT).min(guard.len());
+            guard[next..end].to_vec()
+        };
+
+        for key in keys {
+            // a key that is gone altogether was deleted and collected since, unless it's a name. those share the list but aren't
+            // what subscribers are after
+            let lookup = match map.get(&key) {
+                Ok(l) => l,
+                Err(e) => {
+                    println!("Subscriber dropped at index {}: {}", next, e);
+                    let _ = send_message_tagged(&mut stream, id, storage_failure(e));
+                    return;
+                }
+            };
+            if lookup.is_none() && named.get(&key).is_some() {
+                next += 1;
+                continue;
+            }
+            let deleted = lookup.as_ref().is_none_or(|v| v.is_deleted());
+            let value = if with_values { lookup.as_ref().and_then(|v| v.live()).cloned() } else { None };
+            let event = Message::SubscribeEvent { index: next, item: ScanItem { key, value }, deleted };
+
+            if let Err(e) = send_message_tagged(&mut stream, id, event) {
+                println!("Subscriber left at index {} with Error: {}", next, e);
+                return;
+            }
+            next += 1;
+        }
+    }
+}
+
 // handles antientropy updates
-fn handle_update(mut _stream: TcpStream, map: Arc<LockFreeMap<String>>, replica_map: Arc<LockFreeMap<Mutex<Vec<u64>>>>, local_replica_id: ReplicaId, _sender: ReplicaId, update: UpdateMessage, queue: mpsc::Sender<Commit>) {
-    // Add key-value pairs first, and in doing so update our replica map’s copy of self too
-    for kvpair in update.key_values.iter() {
-        // add to map
-        let map_val = Arc::new(kvpair.value.to_string());
-        let queue_val = map_val.clone();
-        
-        match map.insert(kvpair.key, map_val) {
-            Some(_) => (),
-            None => {
-                // add to commit log
-                queue.send(Commit{key: kvpair.key, value: queue_val, timestamp: SystemTime::now()}).unwrap(); //new, so send to persister, want to do after response to reduce staleness
+#[allow(clippy::too_many_arguments)]
+fn handle_update(mut _stream: TcpStream, map: Arc<dyn Storage>, named: Arc<LockFreeMap<Key, Named>>, replica_map: Arc<LockFreeMap<ReplicaId, Mutex<Vec<Key>>>>, local_replica_id: ReplicaId, sender: ReplicaId, update: UpdateMessage, queue: mpsc::Sender<Persist>, collisions: Arc<RelaxedCounter>, key_added: Arc<Condvar>, clock: Arc<Clock>) {
+    // Add key-value pairs first, and in doing so update our replica map’s copy of self too. the store failing stops it there, see
+    // below
+    let mut commits: Vec<Commit> = Vec::new();
+    let mut failed: Option<io::Error> = None;
+    for (kvpair, meta) in update.key_values.into_iter() {
+        // expiry is set by the origin, so this has already gone (or is about to) everywhere else too
+        if meta.is_expired() {
+            continue;
+        }
 
-                // add to local replica map's copy of self too
-                replica_map.get(&local_replica_id).unwrap().val().lock().unwrap().push(kvpair.key);
+        // add to map, one hop further from where it was written
+        let meta = meta.relayed();
+        let map_val = Arc::new(Entry::new(kvpair.value, meta));
+        
+        match store(&*map, kvpair.key, map_val) {
+            Ok(InsertOutcome::Duplicate) => (),
+            Ok(InsertOutcome::Collision(held)) if held.is_deleted() => (), // the delete wins, the sender will hear of it in time
+            Ok(InsertOutcome::Collision(_)) => {
+                // keep ours. nothing to send back on this connection, so just make it visible
+                println!("Key collision on update of {} from {}, {} so far", kvpair.key, u64_to_socketaddr(sender), collisions.inc() + 1);
+            },
+            Ok(InsertOutcome::Inserted) => {
+                // add to commit log, and local replica map's copy of self with it
+                commits.push(Commit::Put{key: kvpair.key, meta}); //new, so send to persister
+            },
+            Err(e) => {
+                failed = Some(e);
+                break;
             }
         }
     } 
 
+    // then deletes. the first we hear of one replaces the value and goes on the end of our list to be passed on, after that we only
+    // learn who else has it
+    for tombstone in update.tombstones.into_iter() {
+        if failed.is_some() {
+            break;
+        }
+        match map.get(&tombstone.key) {
+            Ok(Some(held)) => if let Some(acked) = &held.deleted {
+                acked.lock().unwrap().extend(tombstone.acked);
+                continue;
+            },
+            Ok(None) => (),
+            Err(e) => {
+                failed = Some(e);
+                break;
+            }
+        }
+
+        let mut acked: HashSet<ReplicaId> = tombstone.acked.into_iter().collect();
+        acked.insert(local_replica_id);
+        let meta = tombstone.meta.relayed();
+        if let Err(e) = map.insert(tombstone.key, Arc::new(Entry::tombstone(meta, acked))) {
+            failed = Some(e);
+            break;
+        }
+
+        commits.push(Commit::Delete{key: tombstone.key, meta});
+    }
+
+    // and named keys, merged into whatever we have. only a change needs logging and passing on
+    for (name, state) in update.named.into_iter() {
+        if let Some(stamp) = state.stamp() {
+            clock.observe(&stamp);
+        }
+
+        let (entry, created) = named_entry(&named, &name, || state.clone());
+        let merged = match if created { Ok(true) } else { entry.state.lock().unwrap().merge(&state) } {
+            Ok(merged) => merged,
+            Err(e) => {
+                println!("Kept our {} on update from {}: {}", name, u64_to_socketaddr(sender), e);
+                false
+            }
+        };
+
+        if merged {
+            commits.push(Commit::Named{named: entry});
+        }
+    }
+
+    if !commits.is_empty() {
+        if let Err(e) = enlist(&replica_map, local_replica_id, Persist::new(commits), &queue) {
+            println!("Couldn't queue the update from {} for the commit log: {}", u64_to_socketaddr(sender), e);
+        }
+        key_added.notify_all();
+    }
+
+    // we'd take the sender's lists as far as it sent them, and never ask again for what didn't make it in. left as they were, the
+    // next digest brings the rest again
+    if let Some(e) = failed {
+        println!("Couldn't take the update from {} into the store: {}", u64_to_socketaddr(sender), e);
+        return;
+    }
+
     // Update replica map by index. So go through each replica id
     for entry in update.replica_keys.iter() {
         // check if entry exists
@@ -624,33 +1410,437 @@ fn handle_update(mut _stream: TcpStream, map: Arc<LockFreeMap<String>>, replica_
 }
 
 // persists to commit log really taking advantage of the lockfree + add-only semantics
-fn persister(counter: Arc<RelaxedCounter>, mut f: File, queue: mpsc::Receiver<Commit>) {
-    for commit in queue {
-        // println!("just committed {:#?}", commit);
-        // let datetime: DateTime<Utc> = commit.timestamp.into();
-        f.write(&format!("\n{} -> {}", commit.key, commit.value).as_bytes()).unwrap();
-        counter.inc();
+fn persister(counter: Arc<RelaxedCounter>, map: Arc<dyn Storage>, mut log: commit_log::Appender, queue: mpsc::Receiver<Persist>, durability: Durability) {
+    // what couldn't be logged yet, oldest first. every commit is one record and our list already has a key for each, so they're
+    // written before anything newer or the numbering goes wrong
+    let mut behind: Vec<Persist> = Vec::new();
+
+    loop {
+        // group commit: everything that queued up while the last group was being written goes in the next one, in order, with a single
+        // write and (for fsync) a single sync. the busier it gets, the more each sync covers
+        let first = match behind.is_empty() {
+            true => queue.recv().ok(),
+            false => match queue.recv_timeout(PERSIST_RETRY) {
+                Ok(p) => Some(p),
+                Err(mpsc::RecvTimeoutError::Timeout) => None,
+                Err(mpsc::RecvTimeoutError::Disconnected) => return,
+            },
+        };
+        let mut group: Vec<Persist> = std::mem::take(&mut behind);
+        match first {
+            Some(first) => group.push(first),
+            None if group.is_empty() => return, // everyone with a sender has gone
+            None => (),
+        }
+        group.extend(queue.try_iter().take(MAX_GROUP_COMMIT - 1));
+
+        match write_group(&map, &mut log, &group, durability) {
+            Ok(count) => {
+                counter.add(count);
+                for batch in group {
+                    if let Some(done) = batch.done {
+                        let _ = done.send(()); // they may have hung up
+                    }
+                }
+            },
+            Err(e) => {
+                // whoever waits hears StorageFailure when done is dropped. the commits stay, to go in once the disk is back
+                println!("Couldn't write the commit log, trying again in {:?}: {}", PERSIST_RETRY, e);
+                behind = group.into_iter().map(|batch| Persist::new(batch.commits)).collect();
+            },
+        }
+    }
+}
+
+// a group of commits, as one write. puts are read back out of the store, whatever has happened to them since. gives back how
+// many records went in
+fn write_group(map: &Arc<dyn Storage>, log: &mut commit_log::Appender, group: &[Persist], durability: Durability) -> io::Result<usize> {
+    let mut records: Vec<u8> = Vec::new();
+    let mut count: usize = 0;
+    for commit in group.iter().flat_map(|batch| batch.commits.iter()) {
+        records.extend(commit_log::encode(&LogRecord::of(commit, &**map)?));
+        count += 1;
+    }
+
+    // a group of waits only (duplicates) has nothing to write, whatever it waits on went with an earlier group
+    if !records.is_empty() {
+        log.append(&records, count as u64, durability == Durability::Fsync)?;
     }
+    Ok(count)
 }
 
 // persists to a full copy every n seconds or so. really taking advantage of the lockfree + add-only semantics
-fn snapshotter(counter: Arc<RelaxedCounter>, f: File, map: Arc<LockFreeMap<String>>, path: String) {
+// a full one to start with, and again once full_every deltas are on top of it. in between, only what's been put on the end of our
+// own key list since the last, which is every key that changed
+#[allow(clippy::too_many_arguments)]
+fn snapshotter(counter: Arc<RelaxedCounter>, map: Arc<dyn Storage>, named: Arc<LockFreeMap<Key, Named>>, replica_map: Arc<LockFreeMap<ReplicaId, Mutex<Vec<Key>>>>, local_replica_id: ReplicaId, listed: Listed, path: String, mut manifest: SnapshotManifest, log_path: String, interval: Duration, full_every: usize) {
+    // how far along each list the last snapshot went. None until this run has written a full one
+    let mut cursors: Option<Cursors> = None;
+
     loop {
-        thread::sleep(Duration::from_secs(5));
+        thread::sleep(interval);
 
         // save current commit id or nearest one prior to serializing
-        let str_counter: String = counter.get().to_string();
-        
-        // seralize it
-        let mut ser = BufWriter::new(File::create(&path).unwrap());
-        match serialize_into(&mut ser, &(*map)) {
-            Ok(_) => (), //println!("Snapshot successfully written!"),
-            Err(e) => println!("{}", e)
+        let snapshotted: u64 = counter.get() as u64;
+
+        // nothing logged since the last one
+        if manifest.snapshots.first().map(|s| s.position) == Some(snapshotted) {
+            continue;
+        }
+
+        // seralize it. the manifest only moves on to it once it's all on disk, a failed one leaves the last good one in place. our
+        // own list goes only as far as it was at the position, the keys on it after have their commits after it too
+        let delta = cursors.is_some() && manifest.deltas() < full_every;
+        let (tails, next) = list_tails(&replica_map, local_replica_id, listed, snapshotted, cursors.as_ref().filter(|_| delta));
+        let written = match delta {
+            true => {
+                let keys: &[Key] = tails.iter().find(|t| t.replica == local_replica_id).map_or(&[], |t| &t.keys);
+                snapshot::write_delta(&path, &mut manifest, snapshotted, keys, &*map, &named, &tails)
+            },
+            false => snapshot::write_full(&path, &mut manifest, snapshotted, &*map, &named, &tails),
+        };
+        match written {
+            Ok(()) => cursors = Some(next), //println!("Snapshotted: {}", snapshotted),
+            Err(e) => {
+                println!("Snapshot failed with error: {}", e);
+                continue;
+            }
         };
-        drop(ser); // need to do this otherwise it won't work right!
 
-        // update log saying how much has been persisted
-        f.write_at(&str_counter.as_bytes(), (31-str_counter.len()).try_into().unwrap()).unwrap();
-        // println!("Snapshotted: {}", str_counter);
+        // segments before the oldest snapshot still kept aren't needed to recover from any of them
+        let keep_from = manifest.snapshots.last().map_or(0, |s| s.position);
+        if let Err(e) = commit_log::compact(&log_path, keep_from) {
+            println!("Commit log compaction failed with error: {}", e);
+        }
+    }
+}
+
+// drops tombstones once every replica we know of is known to have one. until then they're needed to stop the value coming back
+// from a replica that hasn't heard. a replica that never comes back keeps them around for good. expired values go as soon as
+// they expire, every replica does the same at the same time
+fn collector(map: Arc<dyn Storage>, replica_map: Arc<LockFreeMap<ReplicaId, Mutex<Vec<Key>>>>) {
+    loop {
+        thread::sleep(Duration::from_secs(5));
+
+        let known: Vec<ReplicaId> = replica_map.iter().map(|r| *r.key()).collect();
+        let mut done: Vec<Key> = map.tombstones().into_iter().filter(|(_, tombstone)| match &tombstone.deleted {
+            Some(acked) => {
+                let acked = acked.lock().unwrap();
+                known.iter().all(|r| acked.contains(r))
+            },
+            None => false
+        }).map(|(key, _)| key).collect();
+        done.extend(map.expired());
+
+        // the keys stay in the replica lists, anything that walks those skips what isn't in the map
+        for key in done.iter() {
+            map.remove(key);
+        }
+        if !done.is_empty() {
+            println!("Collected {} tombstones and expired values", done.len());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use secko_messages::{CrdtOp, CrdtValue, chunk, connect, receive_message};
+
+    // one replica's state, shared by however many handlers a test starts on it. the queue's far end is kept so it stays open
+    struct Node {
+        id: ReplicaId,
+        map: Arc<dyn Storage>,
+        named: Arc<LockFreeMap<Key, Named>>,
+        replica_map: Arc<ReplicaMap>,
+        queue: mpsc::Sender<Persist>,
+        _persisted: mpsc::Receiver<Persist>,
+        clock: Arc<Clock>,
+    }
+
+    fn node(id: ReplicaId) -> Node {
+        node_on(id, Arc::new(MemoryStorage::new()))
+    }
+
+    fn node_on(id: ReplicaId, map: Arc<dyn Storage>) -> Node {
+        let replica_map: Arc<ReplicaMap> = Arc::new(LockFreeMap::new());
+        replica_map.insert(id, Arc::new(Mutex::new(Vec::new())));
+        let (queue, persisted) = mpsc::channel();
+        Node { id, map, named: Arc::new(LockFreeMap::new()), replica_map, queue, _persisted: persisted, clock: Arc::new(Clock::new(id)) }
+    }
+
+    // a connection to handle_request on node, in memory mode, and the handler's thread to see how it ended
+    fn serve(node: &Node) -> (TcpStream, thread::JoinHandle<()>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (map, named, replica_map, id, queue, clock) = (node.map.clone(), node.named.clone(), node.replica_map.clone(), node.id, node.queue.clone(), node.clock.clone());
+        let handler = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            handle_request(stream, map, named, replica_map, id, queue, Arc::new(RelaxedCounter::new(0)), Arc::new(Condvar::new()), clock, None, Durability::Memory, FrameConfig::default());
+        });
+        (connect(addr).unwrap(), handler)
     }
-}
\ No newline at end of file
+
+    // what antientropy would pass on from one to the other, just the named keys
+    fn gossip(from: &Node, to: &Node) {
+        let named: Vec<(String, Crdt)> = from.named.iter().map(|entry| (entry.val().name.clone(), entry.val().state.lock().unwrap().clone())).collect();
+        let update = UpdateMessage { sending_rate: 1.0, replica_keys: HashMap::new(), key_values: Vec::new(), tombstones: Vec::new(), named };
+
+        // handle_update never uses its stream
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        handle_update(stream, to.map.clone(), to.named.clone(), to.replica_map.clone(), to.id, from.id, update, to.queue.clone(), Arc::new(RelaxedCounter::new(0)), Arc::new(Condvar::new()), to.clock.clone());
+    }
+
+    fn update_named(client: &mut TcpStream, name: &str, op: CrdtOp) {
+        send_message(client, Message::UpdateNamedReq { name: name.to_string(), op }).unwrap();
+        match receive_message(client).unwrap() {
+            Message::UpdateNamedResp => (),
+            other => panic!("Expected UpdateNamedResp, got {}", other),
+        }
+    }
+
+    fn read_named(client: &mut TcpStream, name: &str) -> Option<CrdtValue> {
+        send_message(client, Message::ReadNamedReq { name: name.to_string() }).unwrap();
+        match receive_message(client).unwrap() {
+            Message::ReadNamedResp { value } => value,
+            other => panic!("Expected ReadNamedResp, got {}", other),
+        }
+    }
+
+    #[test]
+    fn manifest_with_an_inflated_len_is_a_hash_mismatch() {
+        let node = node(1);
+        let (mut client, _) = serve(&node);
+        let map = &*node.map;
+        let chunk = b"a chunk we already hold".to_vec();
+        store(map, hash_value(&chunk), Arc::new(Entry::new(chunk.clone(), EntryMeta::new(1)))).unwrap();
+
+        // nothing like that many bytes behind it, it mustn't be allocated for
+        for len in [u64::MAX, 1 << 50] {
+            let manifest = Manifest { len, chunks: vec![hash_value(&chunk)] }.to_bytes();
+            let pair = || KVPair { key: hash_value(b"the whole value"), value: manifest.clone() };
+
+            send_message(&mut client, Message::PushBatchReq(vec![pair()], None)).unwrap();
+            match receive_message(&mut client).unwrap() {
+                Message::PushBatchResp(results) => assert_eq!(results, vec![PushResult::HashMismatch]),
+                other => panic!("Expected PushBatchResp, got {}", other),
+            }
+
+            send_message(&mut client, Message::PushReq(pair(), None)).unwrap();
+            match receive_message(&mut client).unwrap() {
+                Message::Error { code, .. } => assert_eq!(code, ErrorCode::HashMismatch),
+                other => panic!("Expected HashMismatch, got {}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn deleting_a_chunked_value_deletes_the_chunks_nothing_else_uses() {
+        let node = node(1);
+        let (mut client, _) = serve(&node);
+
+        // the second value starts with the same chunk as the first
+        let first = vec![1u8; CHUNK_SIZE * 2 + 10];
+        let mut second = vec![1u8; CHUNK_SIZE];
+        second.extend(vec![2u8; 10]);
+        let (first_chunks, first_manifest) = chunk::split(&first).unwrap();
+        let (second_chunks, second_manifest) = chunk::split(&second).unwrap();
+        for pairs in [first_chunks.iter().chain([&first_manifest]), second_chunks.iter().chain([&second_manifest])] {
+            let pairs: Vec<KVPair> = pairs.map(|p| KVPair { key: p.key, value: p.value.clone() }).collect();
+            send_message(&mut client, Message::PushBatchReq(pairs, None)).unwrap();
+            assert!(matches!(receive_message(&mut client).unwrap(), Message::PushBatchResp(_)));
+        }
+
+        send_message(&mut client, Message::DeleteReq { key: first_manifest.key }).unwrap();
+        assert!(matches!(receive_message(&mut client).unwrap(), Message::DeleteResp { found: true }));
+
+        let deleted = |key: &Key| node.map.get(key).unwrap().unwrap().is_deleted();
+        assert!(deleted(&first_manifest.key));
+        assert!(!deleted(&first_chunks[0].key));
+        assert!(deleted(&first_chunks[2].key));
+        assert!(second_chunks.iter().chain([&second_manifest]).all(|p| !deleted(&p.key)));
+    }
+
+    #[test]
+    fn scan_hands_each_key_back_once() {
+        let node = node(1);
+        let (mut client, _) = serve(&node);
+        let (first, second) = (b"pushed, deleted, pushed again".to_vec(), b"pushed twice".to_vec());
+        let push = |client: &mut TcpStream, value: &Vec<u8>| {
+            send_message(client, Message::PushReq(KVPair { key: hash_value(value), value: value.clone() }, None)).unwrap();
+            receive_message(client).unwrap();
+        };
+
+        push(&mut client, &first);
+        send_message(&mut client, Message::DeleteReq { key: hash_value(&first) }).unwrap();
+        assert!(matches!(receive_message(&mut client).unwrap(), Message::DeleteResp { found: true }));
+        node.map.remove(&hash_value(&first)); // as if every replica had acked the tombstone
+        push(&mut client, &first);
+        push(&mut client, &second);
+        push(&mut client, &second);
+        update_named(&mut client, "counter", CrdtOp::Increment(1));
+        update_named(&mut client, "counter", CrdtOp::Increment(1));
+
+        // a page at a time, so the repeats land in pages of their own
+        let mut keys: Vec<Key> = Vec::new();
+        let mut cursor = Some(0);
+        while let Some(at) = cursor {
+            send_message(&mut client, Message::ScanReq { cursor: at, limit: 1, keys_only: true }).unwrap();
+            match receive_message(&mut client).unwrap() {
+                Message::ScanResp { items, next_cursor } => {
+                    keys.extend(items.iter().map(|item| item.key));
+                    cursor = next_cursor;
+                },
+                other => panic!("Expected ScanResp, got {}", other),
+            }
+        }
+        assert_eq!(keys, vec![hash_value(&first), hash_value(&second)]);
+    }
+
+    #[test]
+    fn client_hanging_up_mid_request_only_ends_the_connection() {
+        let node = node(1);
+        let (mut client, handler) = serve(&node);
+        let map = &*node.map;
+        let value = vec![7u8; 8 * 1024 * 1024];
+        let key = hash_value(&value);
+        store(map, key, Arc::new(Entry::new(value, EntryMeta::new(1)))).unwrap();
+
+        // gone before the answer, which is too big to fit in the socket's buffers
+        send_message(&mut client, Message::RetrieveReq { key }).unwrap();
+        drop(client);
+        assert!(handler.join().is_ok());
+    }
+
+    #[test]
+    fn orset_add_racing_a_remove_wins() {
+        let (a, b) = (node(1), node(2));
+        let (mut client_a, _) = serve(&a);
+        let (mut client_b, _) = serve(&b);
+        update_named(&mut client_a, "members", CrdtOp::AddOr(b"x".to_vec()));
+        gossip(&a, &b);
+
+        // b takes out the add it has seen while a adds x again, neither hearing of the other's
+        update_named(&mut client_b, "members", CrdtOp::RemoveOr(b"x".to_vec()));
+        update_named(&mut client_a, "members", CrdtOp::AddOr(b"x".to_vec()));
+        assert_eq!(read_named(&mut client_b, "members"), Some(CrdtValue::Set(vec![])));
+
+        gossip(&a, &b);
+        gossip(&b, &a);
+        for client in [&mut client_a, &mut client_b] {
+            assert_eq!(read_named(client, "members"), Some(CrdtValue::Set(vec![b"x".to_vec()])));
+        }
+
+        // a remove that has seen every add does take it out, everywhere
+        update_named(&mut client_b, "members", CrdtOp::RemoveOr(b"x".to_vec()));
+        gossip(&b, &a);
+        for client in [&mut client_a, &mut client_b] {
+            assert_eq!(read_named(client, "members"), Some(CrdtValue::Set(vec![])));
+        }
+    }
+
+    #[test]
+    fn concurrent_mv_writes_come_back_as_siblings() {
+        let (a, b) = (node(1), node(2));
+        let (mut client_a, _) = serve(&a);
+        let (mut client_b, _) = serve(&b);
+        update_named(&mut client_a, "config", CrdtOp::SetMv(b"first".to_vec()));
+        gossip(&a, &b);
+
+        // both write over first without seeing each other
+        update_named(&mut client_a, "config", CrdtOp::SetMv(b"from a".to_vec()));
+        update_named(&mut client_b, "config", CrdtOp::SetMv(b"from b".to_vec()));
+        gossip(&a, &b);
+        gossip(&b, &a);
+        for client in [&mut client_a, &mut client_b] {
+            let Some(CrdtValue::Siblings(mut values)) = read_named(client, "config") else { panic!("Expected siblings") };
+            values.sort();
+            assert_eq!(values, vec![b"from a".to_vec(), b"from b".to_vec()]);
+        }
+
+        // the next write has seen both, and settles it
+        update_named(&mut client_b, "config", CrdtOp::SetMv(b"settled".to_vec()));
+        gossip(&b, &a);
+        for client in [&mut client_a, &mut client_b] {
+            assert_eq!(read_named(client, "config"), Some(CrdtValue::Siblings(vec![b"settled".to_vec()])));
+        }
+    }
+
+    #[test]
+    fn store_that_cant_be_read_answers_storage_failure() {
+        let path = std::env::temp_dir().join(format!("secko-main-unreadable-{}", std::process::id())).to_str().unwrap().to_string();
+        let node = node_on(1, Arc::new(DiskStorage::open(&path).unwrap()));
+        let (mut client, handler) = serve(&node);
+        let value = b"on a disk that's gone bad".to_vec();
+        let key = hash_value(&value);
+        store(&*node.map, key, Arc::new(Entry::new(value.clone(), EntryMeta::new(1)))).unwrap();
+        node.replica_map.get(&1).unwrap().val().lock().unwrap().push(key); // for the scan
+
+        // the value's bytes are gone from under the index
+        std::fs::OpenOptions::new().write(true).open(&path).unwrap().set_len(0).unwrap();
+
+        let requests = [
+            Message::RetrieveReq { key },
+            Message::MultiRetrieveReq(vec![key]),
+            Message::PushReq(KVPair { key, value: value.clone() }, None),
+            Message::PushBatchReq(vec![KVPair { key, value }], None),
+            Message::ScanReq { cursor: 0, limit: 10, keys_only: false },
+            Message::DumpReq,
+            Message::DeleteReq { key },
+        ];
+        for request in requests {
+            let asked = format!("{}", request);
+            send_message(&mut client, request).unwrap();
+            match receive_message(&mut client).unwrap() {
+                Message::Error { code, .. } => assert_eq!(code, ErrorCode::StorageFailure, "for {}", asked),
+                other => panic!("Expected StorageFailure for {}, got {}", asked, other),
+            }
+        }
+
+        // and the worker is still there for whatever comes next
+        send_message(&mut client, Message::DumpLenReq).unwrap();
+        assert!(matches!(receive_message(&mut client).unwrap(), Message::DumpLenResp(1)));
+        drop(client);
+        assert!(handler.join().is_ok());
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn persister_keeps_going_after_a_write_it_couldnt_log() {
+        let dir = std::env::temp_dir().join(format!("secko-main-persister-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let (store_path, log_path) = (dir.join("store").to_str().unwrap().to_string(), dir.join("log").to_str().unwrap().to_string());
+
+        let map: Arc<dyn Storage> = Arc::new(DiskStorage::open(&store_path).unwrap());
+        let (first, second) = (b"first".to_vec(), b"second".to_vec());
+        let (first_key, second_key) = (hash_value(&first), hash_value(&second));
+        store(&*map, first_key, Arc::new(Entry::new(first.clone(), EntryMeta::new(1)))).unwrap();
+        std::fs::OpenOptions::new().write(true).open(&store_path).unwrap().set_len(0).unwrap();
+
+        commit_log::create(&log_path, 0).unwrap();
+        let log = commit_log::Appender::open(&log_path, 0, commit_log::SEGMENT_BYTES).unwrap();
+        let (queue, persisted) = mpsc::channel();
+        let map_p = map.clone();
+        let persister = thread::spawn(move || persister(Arc::new(RelaxedCounter::new(0)), map_p, log, persisted, Durability::Fsync));
+
+        // the put can't be read back to log it, so whoever waits hears it didn't make it
+        let (done, logged) = mpsc::channel();
+        queue.send(Persist { commits: vec![Commit::Put { key: first_key, meta: EntryMeta::new(1) }], done: Some(done) }).unwrap();
+        assert!(logged.recv().is_err());
+
+        // once the disk is back, it goes in ahead of what came after it
+        map.insert(first_key, Arc::new(Entry::new(first, EntryMeta::new(1)))).unwrap();
+        store(&*map, second_key, Arc::new(Entry::new(second, EntryMeta::new(1)))).unwrap();
+        let (done, logged) = mpsc::channel();
+        queue.send(Persist { commits: vec![Commit::Put { key: second_key, meta: EntryMeta::new(1) }], done: Some(done) }).unwrap();
+        assert!(logged.recv().is_ok());
+
+        drop(queue);
+        assert!(persister.join().is_ok());
+        let keys: Vec<Key> = commit_log::check(&log_path).unwrap().records.iter().map(|r| r.key()).collect();
+        assert_eq!(keys, vec![first_key, second_key]);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
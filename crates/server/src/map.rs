@@ -1,6 +1,6 @@
 // https://github.com/xacrimon/dashmap/issues/5
 // https://www.youtube.com/watch?v=BI_bHCGRgMY
-use lockfree::map::{Map, Iter, ReadGuard, Removed};
+use lockfree::map::{Map, Iter, ReadGuard, Removed, Insertion, Preview};
 use serde::__private::PhantomData; // https://doc.servo.org/nomicon/phantom-data.html
 use serde::{Serialize, Deserialize};
 use serde::de::{Visitor, MapAccess};
@@ -8,7 +8,7 @@ use delegate::delegate;
 use serde::ser::{Serializer, SerializeMap};
 use std::fmt;
 use serde::{Deserializer};
-use std::hash::{Hasher, BuildHasher};
+use std::hash::{Hash, Hasher, BuildHasher};
 use std::sync::Arc;
 use std::ops::Deref;
 
@@ -44,17 +44,19 @@ impl BuildHasher for TrivialHasherBuilder {
     }
 }
 
-// key is of type u64. so a u64 address. the hasher does nothing as the map looks up using u64 addresses, we just need access to the hash but otherwise this is akin to a set just with us having control over
-//  the addresses.
+// keys are already hashes (content addresses for the store, socket addresses packed into a u64 for the replica map). the hasher does
+// nothing but take the last 8 bytes the key writes, which for a content address is the tail of its digest, we just need access to the
+// hash but otherwise this is akin to a set just with us having control over the addresses.
 // whatever the value is, which we figure out based on the client usage (mrdt stuff) must be serializable!!!
 // passed in a hash to V necessarily implements hash, so that trait requirement is excluded here for simplicity, and clarity as we don't hash here. also needs to be serializable, so we include that trait, fruther down.
 #[derive(Debug)] // if we unwrap an arc holding this, need this so we can call unwrap() or expect()
-pub struct LockFreeMap<V> {
-    inner: Map<u64, Arc<V>, TrivialHasherBuilder>
+pub struct LockFreeMap<K, V> {
+    inner: Map<K, Arc<V>, TrivialHasherBuilder>
 }
 
-impl<'a, V> LockFreeMap<V>
+impl<'a, K, V> LockFreeMap<K, V>
 where
+    K: Hash + Ord + 'a,
     V: 'a
 {
     pub fn new() -> Self {
@@ -64,22 +66,71 @@ where
     delegate! {
         to self.inner {
             // pub fn clear(&mut self);
-            pub fn insert(&self, key: u64, val: Arc<V>) -> Option<Removed<u64, Arc<V>>>;
-            pub fn iter(&self) -> Iter<u64, Arc<V>>;
-            pub fn get<'map>(&'map self, key: &u64) -> Option<ReadGuard<'map, u64, Arc<V>>>;
+            pub fn insert(&self, key: K, val: Arc<V>) -> Option<Removed<K, Arc<V>>>;
+            pub fn iter(&self) -> Iter<K, Arc<V>>;
+            pub fn get<'map>(&'map self, key: &K) -> Option<ReadGuard<'map, K, Arc<V>>>;
+        }
+    }
+
+    // only for tombstones that every replica has seen, the store is otherwise add-only
+    pub fn remove(&self, key: &K) -> Option<Removed<K, Arc<V>>> {
+        self.inner.remove(key)
+    }
+}
+
+// what insert_if_absent found
+#[derive(Debug)]
+pub enum InsertOutcome<V> {
+    Inserted,
+    Duplicate, // the same value was already there
+    Collision(Arc<V>), // a different value was already there under this key, it was left alone. this is what's held
+}
+
+impl<'a, K, V> LockFreeMap<K, V>
+where
+    K: Hash + Ord + 'a,
+    V: PartialEq + 'a
+{
+    // never overwrites. a plain insert would silently replace whatever was there, which for content addressed keys should be
+    // the same value, but if the hash ever lets us down the map and the commit log would quietly disagree
+    pub fn insert_if_absent(&self, key: K, val: Arc<V>) -> InsertOutcome<V> {
+        let mut existing: Option<Arc<V>> = None;
+
+        // the closure may get called again if the map changes underneath us, so it only ever records the latest thing it found
+        let insertion = self.inner.insert_with(key, |_, _, found| {
+            match found {
+                Some((_, held)) => {
+                    existing = Some(held.clone());
+                    Preview::Discard
+                },
+                None => {
+                    existing = None;
+                    Preview::New(val.clone())
+                }
+            }
+        });
+
+        match insertion {
+            Insertion::Created => InsertOutcome::Inserted,
+            Insertion::Updated(_) => unreachable!(), // we never hand back a value when something was found
+            Insertion::Failed(_) => match existing {
+                Some(held) if *held == *val => InsertOutcome::Duplicate,
+                Some(held) => InsertOutcome::Collision(held),
+                None => unreachable!(), // only discard when something was found
+            }
         }
     }
 }
 
 // desirialize dashmap
-pub struct LockFreeMapVisitor<V> {
-    marker: PhantomData<LockFreeMap<V>> // we use this here because we have an unused type parameter - https://doc.servo.org/serde/lib/struct.PhantomData.html 
+pub struct LockFreeMapVisitor<K, V> {
+    marker: PhantomData<LockFreeMap<K, V>> // we use this here because we have an unused type parameter - https://doc.servo.org/serde/lib/struct.PhantomData.html 
 }
 
 // visitors are serdes way of deserializing nested structures.
 // visits each of the fields of something like a struct
 // the visitor is what the deserializer calls when the deserializer finds what it expects per deserialize()
-impl<V> LockFreeMapVisitor<V> {
+impl<K, V> LockFreeMapVisitor<K, V> {
     fn new() -> Self {
         LockFreeMapVisitor {
             marker: PhantomData
@@ -91,11 +142,12 @@ impl<V> LockFreeMapVisitor<V> {
 // we want to pass a reference to that, not copy it. so we want it to live as long as the deserializer does. not relevant when reading from
 // disk though as those buffers that we would have references to while parsing from disk would eventually go away - all our values then are
 // owned and this lifetime is less important
-impl<'de, V> Visitor<'de> for LockFreeMapVisitor<V>
+impl<'de, K, V> Visitor<'de> for LockFreeMapVisitor<K, V>
 where
+    K: Deserialize<'de> + Hash + Ord,
     V: Deserialize<'de>
 {
-    type Value = LockFreeMap<V>;
+    type Value = LockFreeMap<K, V>;
 
     fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result { // if you deserialize and get an unexpected type
         write!(formatter, "a LockFreeMap")
@@ -117,15 +169,16 @@ where
 
 // when implementing deserialize, in general we want to give information to the deserializer about what to expect next. depending on the format it might not
 // be clear - for example binary encoding doesn't encode types like json might, making it harder. so this is to make it clear what to expect next
-impl<'de, V> Deserialize<'de> for LockFreeMap<V>
+impl<'de, K, V> Deserialize<'de> for LockFreeMap<K, V>
 where
+    K: Deserialize<'de> + Hash + Ord,
     V: Deserialize<'de>,
 {
     fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
         where
             D: Deserializer<'de>, // this is a generic for something interfacing with the file format. so a json deserializer, or binary one, etc.
     {
-        deserializer.deserialize_map(LockFreeMapVisitor::<V>::new()) //this is what to expect and what to construct based on that. we expect an encoding of a map
+        deserializer.deserialize_map(LockFreeMapVisitor::<K, V>::new()) //this is what to expect and what to construct based on that. we expect an encoding of a map
     }
 }
 
@@ -138,8 +191,9 @@ where
 /// the `Map` since the iterator creation and the current call to
 /// [`next`](Iterator::next). However, it is not guaranteed to yield all items
 /// present in the `Map` at some point if the `Map` is shared between threads.
-impl<V> Serialize for LockFreeMap<V>
+impl<K, V> Serialize for LockFreeMap<K, V>
 where
+    K: Serialize + Hash + Ord + Clone,
     V: Serialize
 {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
@@ -149,15 +203,15 @@ where
         // need to provide bincode a definite length
         // let length = self.iter().fold(0, |acc, _| acc + 1);
 
-        // grab keys ahead of time so we can get a length
+        // grab entries ahead of time so we can get a length
         // iter() is key component. the iterator may be modified but since we are saving commits conservatively then we just care about the current version - race conditions unimportant; ordering *shouldn't* matter here as we aren't cutting loop off early, just caching must up to date key list
-        let keys: Vec<u64> = self.iter().map(|kv| kv.key().clone()).collect();
+        // the values come along too (just the arcs) as keys can be removed in the meantime
+        let entries: Vec<(K, Arc<V>)> = self.iter().map(|kv| (kv.key().clone(), kv.val().clone())).collect();
 
         // now we serialize; need to provide bincode a definite length for bincode
-        let mut map = serializer.serialize_map(Some(keys.len()))?;
-        for key in keys { 
-            map.serialize_entry(&key, self.get(&key).unwrap().val().deref())?; // turns it into something the serializer can understand, which follows the serde data model. serialize_entry takes care of this.
-            // unwrap is safe for now as key won't get deleted
+        let mut map = serializer.serialize_map(Some(entries.len()))?;
+        for (key, val) in entries { 
+            map.serialize_entry(&key, val.deref())?; // turns it into something the serializer can understand, which follows the serde data model. serialize_entry takes care of this.
         }
         map.end()
     }
@@ -0,0 +1,383 @@
+// the replica map: our own list of keys, every change puts its key on the end, and our copies of every other replica's. antientropy
+// goes by position in them, so they're kept through restarts. snapshots have them as of the commit log position they cover, and
+// every record after that put exactly one key on the end of ours, in the same order, so replaying the log brings ours back as it
+// was. our copies of the others are only as fresh as the snapshot, antientropy fills in the rest
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, mpsc, atomic::Ordering};
+use serde::{Serialize, Deserialize};
+use secko_messages::{Key, ReplicaId};
+
+use crate::{Commit, Persist, map::LockFreeMap, commit_log::LogRecord};
+
+pub type ReplicaMap = LockFreeMap<ReplicaId, Mutex<Vec<Key>>>;
+
+// the end of a replica's list, from from on. a full snapshot has them all from 0
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ListTail {
+    pub replica: ReplicaId,
+    pub from: usize,
+    pub keys: Vec<Key>,
+}
+
+// how long our own list was at a commit log position. every record after it puts one key on
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Listed {
+    pub position: u64,
+    pub len: usize,
+}
+
+impl Listed {
+    pub fn len_at(&self, position: u64) -> usize {
+        self.len + position.saturating_sub(self.position) as usize
+    }
+}
+
+// where the last snapshot left off in each list, and the key just before. a list that's been cleared and grown back past there
+// is unlikely to still have the same key in the same place
+pub type Cursors = HashMap<ReplicaId, (usize, Option<Key>)>;
+
+// puts the keys of what's being persisted on the end of our own list and queues it, both under the list's lock, so the log has
+// them in the same order as the list. a name that's already queued is left out, the record for it takes in this change too, so a
+// name changing faster than the log is written doesn't take a place on the list (and in the log) for every change
+pub fn enlist(replica_map: &ReplicaMap, local_replica_id: ReplicaId, mut persist: Persist, queue: &mpsc::Sender<Persist>) -> Result<(), mpsc::SendError<Persist>> {
+    let guard = replica_map.get(&local_replica_id).unwrap();
+    let mut list = guard.val().lock().unwrap();
+    persist.commits.retain(|c| match c {
+        Commit::Named { named } => !named.queued.swap(true, Ordering::SeqCst),
+        _ => true,
+    });
+    list.extend(persist.commits.iter().map(|c| c.key()));
+    queue.send(persist)
+}
+
+// what a snapshot covering position needs of the lists. each from where cursors has the last snapshot leaving off, all of it
+// without, and ours only as far as it was at position. gives back where this one leaves off
+pub fn list_tails(replica_map: &ReplicaMap, local_replica_id: ReplicaId, listed: Listed, position: u64, cursors: Option<&Cursors>) -> (Vec<ListTail>, Cursors) {
+    let mut tails: Vec<ListTail> = Vec::new();
+    let mut next: Cursors = HashMap::new();
+
+    for replica in replica_map.iter() {
+        let list = replica.val().lock().unwrap();
+        let end = match *replica.key() == local_replica_id {
+            true => listed.len_at(position).min(list.len()),
+            false => list.len(),
+        };
+        let from = match cursors.and_then(|c| c.get(replica.key())) {
+            Some(&(from, last)) if from <= end && from.checked_sub(1).map(|i| list[i]) == last => from,
+            _ => 0, // new since, or cleared
+        };
+
+        tails.push(ListTail { replica: *replica.key(), from, keys: list[from..end].to_vec() });
+        next.insert(*replica.key(), (end, end.checked_sub(1).map(|i| list[i])));
+    }
+    (tails, next)
+}
+
+// puts tails on the end of lists, over whatever was there from where each starts
+pub fn layer(lists: &mut HashMap<ReplicaId, Vec<Key>>, tails: Vec<ListTail>) {
+    for tail in tails {
+        let list = lists.entry(tail.replica).or_default();
+        list.truncate(tail.from);
+        list.extend(tail.keys);
+    }
+}
+
+// the replica map as it was when the log ended: the lists from the snapshot, and a key on the end of ours for every record
+// replayed after it
+pub fn restore(mut lists: HashMap<ReplicaId, Vec<Key>>, local_replica_id: ReplicaId, replayed: &[LogRecord]) -> ReplicaMap {
+    lists.entry(local_replica_id).or_default().extend(replayed.iter().map(|r| r.key()));
+
+    let replica_map: ReplicaMap = LockFreeMap::new();
+    for (replica, keys) in lists {
+        replica_map.insert(replica, Arc::new(Mutex::new(keys)));
+    }
+    replica_map
+}
+
+// where each key first shows up on our list, as far as it's been caught up to. a key goes on again when it's deleted, pushed
+// again or renamed, going by its first place alone is how a scan hands each one back once
+#[derive(Default)]
+pub struct FirstListed {
+    upto: usize,
+    first: HashMap<Key, usize>,
+}
+
+impl FirstListed {
+    // the keys of list[start..end] that are at their first place on it
+    pub fn firsts(&mut self, list: &[Key], start: usize, end: usize) -> Vec<Key> {
+        // ours only ever grows, but if it didn't, start over
+        if list.len() < self.upto {
+            *self = FirstListed::default();
+        }
+        for (i, key) in list.iter().enumerate().take(end).skip(self.upto) {
+            self.first.entry(*key).or_insert(i);
+        }
+        self.upto = self.upto.max(end);
+
+        (start..end).filter(|&i| self.first.get(&list[i]) == Some(&i)).map(|i| list[i]).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+    use std::fs::{create_dir_all, remove_dir_all, OpenOptions};
+    use std::os::unix::fs::FileExt;
+    use std::path::Path;
+    use secko_messages::{EntryMeta, Crdt, CrdtOp, CrdtValue, Clock, hash_value, name_key};
+    use crate::{Commit, Entry, Named, store, named_entry, map::InsertOutcome, commit_log::{self, Appender}, snapshot::{self, SnapshotManifest}, storage::{Storage, MemoryStorage}};
+
+    const LOCAL: ReplicaId = 1;
+    const PEER: ReplicaId = 2;
+
+    // just enough of a server to make changes the way the handlers do, persist them the way the persister does and snapshot them
+    // the way the snapshotter does. everything goes in a fresh directory per test
+    struct Node {
+        dir: String,
+        map: MemoryStorage,
+        named: LockFreeMap<Key, Named>,
+        replica_map: ReplicaMap,
+        log: Appender,
+        position: u64,
+        listed: Listed,
+        manifest: SnapshotManifest,
+        cursors: Option<Cursors>,
+    }
+
+    impl Node {
+        fn new(name: &str) -> Node {
+            let dir = std::env::temp_dir().join(format!("secko-replicas-{}-{}", std::process::id(), name));
+            let _ = remove_dir_all(&dir);
+            create_dir_all(&dir).unwrap();
+            let dir = dir.to_str().unwrap().to_string();
+
+            commit_log::create(&format!("{}/log", dir), 0).unwrap();
+            let log = Appender::open(&format!("{}/log", dir), 0, commit_log::SEGMENT_BYTES).unwrap();
+            let replica_map: ReplicaMap = LockFreeMap::new();
+            replica_map.insert(LOCAL, Arc::new(Mutex::new(Vec::new())));
+            replica_map.insert(PEER, Arc::new(Mutex::new(Vec::new())));
+
+            Node { dir, map: MemoryStorage::new(), named: LockFreeMap::new(), replica_map, log, position: 0, listed: Listed { position: 0, len: 0 }, manifest: SnapshotManifest::default(), cursors: None }
+        }
+
+        fn snapshot_path(&self) -> String {
+            format!("{}/snap", self.dir)
+        }
+
+        // through the queue and into the log
+        fn persist(&mut self, commits: Vec<Commit>) {
+            let (tx, rx) = mpsc::channel();
+            enlist(&self.replica_map, LOCAL, Persist::new(commits), &tx).unwrap();
+
+            let mut records: Vec<u8> = Vec::new();
+            let mut count: u64 = 0;
+            for commit in rx.recv().unwrap().commits.iter() {
+                records.extend(commit_log::encode(&LogRecord::of(commit, &self.map).unwrap()));
+                count += 1;
+            }
+            self.log.append(&records, count, false).unwrap();
+            self.position += count;
+        }
+
+        fn put(&mut self, value: &[u8]) -> Key {
+            let key = hash_value(value);
+            let meta = EntryMeta::new(LOCAL);
+            if let InsertOutcome::Inserted = store(&self.map, key, Arc::new(Entry::new(value.to_vec(), meta))).unwrap() {
+                self.persist(vec![Commit::Put { key, meta }]);
+            }
+            key
+        }
+
+        fn delete(&mut self, key: Key) {
+            let meta = EntryMeta::new(LOCAL);
+            self.map.insert(key, Arc::new(Entry::tombstone(meta, HashSet::from([LOCAL])))).unwrap();
+            self.persist(vec![Commit::Delete { key, meta }]);
+        }
+
+        fn add(&mut self, name: &str, n: i64) {
+            let clock = Clock::new(LOCAL);
+            let op = CrdtOp::Add(n);
+            let (entry, created) = named_entry(&self.named, name, || Crdt::from_op(&op, LOCAL, clock.now()));
+            if !created {
+                entry.state.lock().unwrap().apply(&op, LOCAL, clock.now()).unwrap();
+            }
+            self.persist(vec![Commit::Named { named: entry }]);
+        }
+
+        // what antientropy does with the peer's keys it hears of
+        fn hear(&self, keys: &[Key]) {
+            self.replica_map.get(&PEER).unwrap().val().lock().unwrap().extend_from_slice(keys);
+        }
+
+        fn snapshot(&mut self, full: bool) {
+            let path = self.snapshot_path();
+            let cursors = if full { None } else { self.cursors.as_ref() };
+            let (tails, next) = list_tails(&self.replica_map, LOCAL, self.listed, self.position, cursors);
+            match cursors {
+                Some(_) => {
+                    let keys = tails.iter().find(|t| t.replica == LOCAL).unwrap().keys.clone();
+                    snapshot::write_delta(&path, &mut self.manifest, self.position, &keys, &self.map, &self.named, &tails).unwrap();
+                },
+                None => snapshot::write_full(&path, &mut self.manifest, self.position, &self.map, &self.named, &tails).unwrap(),
+            }
+            self.cursors = Some(next);
+        }
+
+        fn lists(&self) -> HashMap<ReplicaId, Vec<Key>> {
+            self.replica_map.iter().map(|r| (*r.key(), r.val().lock().unwrap().clone())).collect()
+        }
+
+        // starts again from what's on disk, as main does
+        fn restart(&self) -> (HashMap<ReplicaId, Vec<Key>>, MemoryStorage) {
+            let map = MemoryStorage::new();
+            let loaded = snapshot::load(&self.snapshot_path(), &map).unwrap().unwrap();
+            let recovered = commit_log::recover(&format!("{}/log", self.dir), LOCAL, Some(loaded.position)).unwrap();
+            let replica_map = restore(loaded.lists, LOCAL, &recovered.records);
+            let lists = replica_map.iter().map(|r| (*r.key(), r.val().lock().unwrap().clone())).collect();
+            (lists, map)
+        }
+    }
+
+    impl Drop for Node {
+        fn drop(&mut self) {
+            let _ = remove_dir_all(&self.dir);
+        }
+    }
+
+    #[test]
+    fn lists_survive_restart() {
+        let mut node = Node::new("restart");
+        let a = node.put(b"a");
+        node.put(b"b");
+        node.hear(&[hash_value(b"p1"), hash_value(b"p2")]);
+        node.snapshot(true);
+
+        node.put(b"c");
+        node.delete(a);
+        node.add("counter", 1);
+        node.hear(&[hash_value(b"p3")]);
+        node.snapshot(false);
+
+        // only in the log
+        node.put(b"d");
+        node.add("counter", 2);
+        node.put(b"b"); // a duplicate isn't a change
+
+        let (lists, _) = node.restart();
+        assert_eq!(lists, node.lists());
+        assert_eq!(lists[&LOCAL].len(), 7);
+        assert_eq!(lists[&LOCAL][3], a);
+        assert_eq!(lists[&LOCAL][4], name_key("counter"));
+    }
+
+    #[test]
+    fn peer_lists_are_as_of_the_snapshot() {
+        let mut node = Node::new("peer");
+        node.put(b"a");
+        node.hear(&[hash_value(b"p1")]);
+        node.snapshot(true);
+        node.hear(&[hash_value(b"p2")]);
+
+        let (lists, _) = node.restart();
+        assert_eq!(lists[&PEER], vec![hash_value(b"p1")]);
+        assert_eq!(lists[&LOCAL], node.lists()[&LOCAL]);
+    }
+
+    #[test]
+    fn lists_survive_falling_back() {
+        let mut node = Node::new("fallback");
+        node.put(b"a");
+        node.hear(&[hash_value(b"p1")]);
+        node.snapshot(true);
+        node.put(b"b");
+        node.snapshot(false);
+        node.put(b"c");
+        node.hear(&[hash_value(b"p2")]);
+        node.snapshot(false);
+        node.put(b"d");
+
+        // the first delta goes bad, so only the full snapshot is used and the rest comes from the log
+        let first_delta = &node.manifest.snapshots[1].file;
+        let f = OpenOptions::new().write(true).open(Path::new(&node.dir).join(first_delta)).unwrap();
+        f.write_all_at(b"\xff\xff", 2).unwrap();
+
+        let (lists, _) = node.restart();
+        assert_eq!(lists[&LOCAL], node.lists()[&LOCAL]);
+        assert_eq!(lists[&PEER], vec![hash_value(b"p1")]);
+    }
+
+    #[test]
+    fn cleared_list_is_snapshotted_whole() {
+        let mut node = Node::new("cleared");
+        node.put(b"a");
+        node.hear(&[hash_value(b"p1"), hash_value(b"p2")]);
+        node.snapshot(true);
+
+        // the peer lost its store and started over, further than it was
+        node.replica_map.get(&PEER).unwrap().val().lock().unwrap().clear();
+        node.hear(&[hash_value(b"q1"), hash_value(b"q2"), hash_value(b"q3")]);
+        node.put(b"b");
+        node.snapshot(false);
+
+        let (lists, _) = node.restart();
+        assert_eq!(lists, node.lists());
+    }
+
+    #[test]
+    fn store_survives_alongside() {
+        let mut node = Node::new("store");
+        let a = node.put(b"a");
+        node.snapshot(true);
+        node.delete(a);
+        node.put(b"b");
+        node.snapshot(false);
+        node.put(b"c");
+
+        // the log past the snapshot isn't replayed into the store here, only what the snapshots have
+        let (_, map) = node.restart();
+        assert!(map.get(&a).unwrap().unwrap().is_deleted());
+        assert_eq!(map.get(&hash_value(b"b")).unwrap().unwrap().live(), Some(&b"b".to_vec()));
+        assert_eq!(map.keys(), vec![a, hash_value(b"b")]);
+    }
+
+    #[test]
+    fn name_and_value_with_the_same_bytes_both_survive_a_delta() {
+        let mut node = Node::new("same-bytes");
+        node.snapshot(true);
+        let key = node.put(b"foo");
+        node.add("foo", 1);
+        node.snapshot(false);
+
+        let (lists, map) = node.restart();
+        assert_eq!(lists[&LOCAL], vec![key, name_key("foo")]);
+        assert_eq!(map.get(&key).unwrap().unwrap().live(), Some(&b"foo".to_vec()));
+    }
+
+    #[test]
+    fn name_changing_again_before_its_logged_goes_on_once() {
+        let mut node = Node::new("queued-name");
+        let clock = Clock::new(LOCAL);
+        let (entry, _) = named_entry(&node.named, "counter", || Crdt::from_op(&CrdtOp::Add(1), LOCAL, clock.now()));
+        let (tx, rx) = mpsc::channel();
+        enlist(&node.replica_map, LOCAL, Persist::new(vec![Commit::Named { named: entry.clone() }]), &tx).unwrap();
+        for _ in 0..3 {
+            entry.state.lock().unwrap().apply(&CrdtOp::Add(1), LOCAL, clock.now()).unwrap();
+            enlist(&node.replica_map, LOCAL, Persist::new(vec![Commit::Named { named: entry.clone() }]), &tx).unwrap();
+        }
+        assert_eq!(node.lists()[&LOCAL], vec![name_key("counter")]);
+
+        // the one commit logs every change made while it waited
+        let queued: Vec<Persist> = rx.try_iter().collect();
+        let commits: Vec<&Commit> = queued.iter().flat_map(|p| p.commits.iter()).collect();
+        assert_eq!(commits.len(), 1);
+        match LogRecord::of(commits[0], &node.map).unwrap() {
+            LogRecord::Named { state, .. } => assert_eq!(state.value(), CrdtValue::Counter(4)),
+            other => panic!("Expected a named record, got {:?}", other),
+        }
+
+        // logged, so the next change goes on again
+        node.add("counter", 1);
+        assert_eq!(node.lists()[&LOCAL], vec![name_key("counter"), name_key("counter")]);
+    }
+}
@@ -0,0 +1,432 @@
+// snapshots are written whole to a temp file, synced, then renamed into place, so a crash part way through never leaves a torn
+// snapshot where a good one was. which snapshots there are, newest first, is in a manifest next to them, along with the kind,
+// format, checksum and commit log position of each. the manifest is replaced the same way, only once the snapshot it adds is on
+// disk. a full snapshot has the whole store, a delta only what changed since the snapshot before it, going by our own key list
+// (every change puts its key on the end). both have the replica lists too, all of them or what's been added to each. startup
+// takes the newest full snapshot that checks out and layers its deltas on top, up to the first one that doesn't, so a damaged one
+// costs a longer replay rather than the store
+use std::collections::HashSet;
+use std::fmt;
+use std::fs::{File, read, remove_file, rename};
+use std::io::{self, Read, Write, BufReader, BufWriter};
+use std::path::Path;
+use std::sync::Arc;
+use serde::{Serialize, Serializer, Deserialize, Deserializer, de::{self, DeserializeOwned, DeserializeSeed, Visitor, SeqAccess, MapAccess}, ser::{self, SerializeMap}};
+use bincode::{Options, serialize, deserialize, serialize_into, deserialize_from};
+use std::collections::HashMap;
+use secko_messages::{Key, ReplicaId, EntryMeta, name_key, hash_value};
+
+use crate::{Entry, Named, sync_dir_of, map::LockFreeMap, storage::Storage, replicas::{ListTail, layer}, commit_log::{crc32, crc32_update}};
+
+pub const SNAPSHOT_VERSION: u16 = 1; // bump whenever what goes in a snapshot changes
+const MANIFEST_MAGIC: [u8; 8] = *b"SEKOSNAP";
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum SnapshotKind {
+    Full,
+    Delta, // on top of the snapshot after it in the manifest
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SnapshotInfo {
+    pub file: String, // name only, it's in the same directory as the manifest
+    pub kind: SnapshotKind,
+    pub version: u16,
+    pub len: u64,
+    pub crc: u32,
+    pub position: u64, // commit log records it covers, replay starts at this one
+}
+
+// which snapshots there are. the newest full one with the deltas since, and the full one before that to fall back on
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct SnapshotManifest {
+    pub next: u64, // goes on the end of the next snapshot's file name, so none is ever written over
+    pub snapshots: Vec<SnapshotInfo>, // newest first
+}
+
+// a full snapshot holds the store, encoded as a map would be, then the names, then the replica lists. the store goes out oldest
+// first, so one loaded from it keeps its order
+struct StoreOut<'a>(&'a dyn Storage);
+
+impl Serialize for StoreOut<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let entries = self.0.iter_arrived();
+        let mut map = serializer.serialize_map(Some(entries.len()))?;
+        for read in entries {
+            let (key, entry) = read.map_err(ser::Error::custom)?;
+            map.serialize_entry(&key, &*entry)?;
+        }
+        map.end()
+    }
+}
+
+// and is read straight into a store, so it never has to fit in memory whole. what it gives back is the rest
+struct FullIn<'a>(&'a dyn Storage);
+
+type FullRest = (LockFreeMap<Key, Named>, Vec<ListTail>);
+
+impl<'de> DeserializeSeed<'de> for FullIn<'_> {
+    type Value = FullRest;
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<Self::Value, D::Error> {
+        deserializer.deserialize_tuple(3, self)
+    }
+}
+
+impl<'de> Visitor<'de> for FullIn<'_> {
+    type Value = FullRest;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        write!(formatter, "a full snapshot")
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+        if seq.next_element_seed(StoreIn(self.0))?.is_none() {
+            return Err(de::Error::invalid_length(0, &self));
+        }
+        let named = match seq.next_element()? {
+            Some(named) => named,
+            None => return Err(de::Error::invalid_length(1, &self)),
+        };
+        match seq.next_element()? {
+            Some(lists) => Ok((named, lists)),
+            None => Err(de::Error::invalid_length(2, &self)),
+        }
+    }
+}
+
+struct StoreIn<'a>(&'a dyn Storage);
+
+impl<'de> DeserializeSeed<'de> for StoreIn<'_> {
+    type Value = ();
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<(), D::Error> {
+        deserializer.deserialize_map(self)
+    }
+}
+
+impl<'de> Visitor<'de> for StoreIn<'_> {
+    type Value = ();
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        write!(formatter, "a store")
+    }
+
+    fn visit_map<M: MapAccess<'de>>(self, mut access: M) -> Result<(), M::Error> {
+        while let Some((key, entry)) = access.next_entry::<Key, Entry>()? {
+            self.0.insert(key, Arc::new(entry)).map_err(de::Error::custom)?;
+        }
+        Ok(())
+    }
+}
+
+// what a delta holds. None for an entry that's gone since, it expired or every replica has its delete. names never go
+#[derive(Serialize)]
+struct DeltaOut<'a> {
+    entries: Vec<(Key, Option<&'a Entry>)>,
+    named: Vec<&'a Named>,
+    lists: &'a [ListTail],
+}
+
+#[derive(Deserialize)]
+struct Delta {
+    entries: Vec<(Key, Option<Entry>)>,
+    named: Vec<Named>,
+    lists: Vec<ListTail>,
+}
+
+// what load found, besides what it put in the store
+pub struct Loaded {
+    pub named: LockFreeMap<Key, Named>,
+    pub lists: HashMap<ReplicaId, Vec<Key>>,
+    pub position: u64,
+    pub manifest: SnapshotManifest,
+    pub deltas: usize, // layered on the full snapshot
+    pub skipped: Vec<String>, // why each newer snapshot couldn't be used
+}
+
+// snapshots go next to path, the manifest at path.manifest
+pub fn manifest_path(path: &str) -> String {
+    format!("{}.manifest", path)
+}
+
+fn sibling(path: &str, file: &str) -> String {
+    Path::new(path).with_file_name(file).to_string_lossy().into_owned()
+}
+
+impl SnapshotManifest {
+    // Ok(None) if there isn't one yet
+    pub fn load(path: &str) -> Result<Option<SnapshotManifest>, String> {
+        let bytes = match read(manifest_path(path)) {
+            Ok(b) => b,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(format!("Couldn't read the snapshot manifest: {}", e)),
+        };
+        if bytes.len() < 12 || bytes[..8] != MANIFEST_MAGIC {
+            return Err("Not a snapshot manifest.".to_string());
+        }
+        if u32::from_be_bytes(bytes[8..12].try_into().unwrap()) != crc32(&bytes[12..]) {
+            return Err("The snapshot manifest doesn't match its checksum.".to_string());
+        }
+
+        match deserialize(&bytes[12..]) {
+            Ok(manifest) => Ok(Some(manifest)),
+            Err(e) => Err(format!("Couldn't decode the snapshot manifest: {}", e)),
+        }
+    }
+
+    fn store(&self, path: &str) -> io::Result<()> {
+        let body = serialize(self).unwrap();
+        let mut bytes = MANIFEST_MAGIC.to_vec();
+        bytes.extend(crc32(&body).to_be_bytes());
+        bytes.extend(body);
+
+        let target = manifest_path(path);
+        let tmp = format!("{}.tmp", target);
+        let mut f = File::create(&tmp)?;
+        f.write_all(&bytes)?;
+        f.sync_all()?;
+        rename(&tmp, &target)?;
+        sync_dir_of(&target)
+    }
+
+    // deltas since the newest full snapshot
+    pub fn deltas(&self) -> usize {
+        self.snapshots.iter().take_while(|s| s.kind == SnapshotKind::Delta).count()
+    }
+}
+
+// counts and checksums whatever passes through, either way
+struct Tally<T> {
+    inner: T,
+    len: u64,
+    crc: u32,
+}
+
+impl<T> Tally<T> {
+    fn new(inner: T) -> Tally<T> {
+        Tally { inner, len: 0, crc: 0 }
+    }
+}
+
+impl<W: Write> Write for Tally<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.len += n as u64;
+        self.crc = crc32_update(self.crc, &buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<R: Read> Read for Tally<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.len += n as u64;
+        self.crc = crc32_update(self.crc, &buf[..n]);
+        Ok(n)
+    }
+}
+
+// snapshots the whole of map and named, and lists, which should all start from 0, as covering the first position records of the
+// commit log. the manifest keeps it and the full snapshot before it, every older one and all the deltas are deleted
+pub fn write_full(path: &str, manifest: &mut SnapshotManifest, position: u64, map: &dyn Storage, named: &LockFreeMap<Key, Named>, lists: &[ListTail]) -> io::Result<()> {
+    let info = write_file(path, manifest, SnapshotKind::Full, position, &(StoreOut(map), named, lists))?;
+
+    let mut snapshots = vec![info];
+    snapshots.extend(manifest.snapshots.iter().find(|s| s.kind == SnapshotKind::Full).cloned());
+    update(path, manifest, snapshots)
+}
+
+// snapshots only what's under keys, as it is now, and what's been added to the lists, as covering the first position records of
+// the commit log. that has to be every key put on the end of our list since the last snapshot, and there has to be a full one to
+// build on
+pub fn write_delta(path: &str, manifest: &mut SnapshotManifest, position: u64, keys: &[Key], map: &dyn Storage, named: &LockFreeMap<Key, Named>, lists: &[ListTail]) -> io::Result<()> {
+    if !manifest.snapshots.iter().any(|s| s.kind == SnapshotKind::Full) {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "no full snapshot for a delta to build on"));
+    }
+
+    // copied out so nothing is held while it's written. a key goes on the list again every time it changes, it only needs to go
+    // in once
+    let mut seen: HashSet<Key> = HashSet::new();
+    let mut entries: Vec<(Key, Option<Arc<Entry>>)> = Vec::new();
+    let mut names: Vec<Arc<Named>> = Vec::new();
+    for key in keys.iter().filter(|k| seen.insert(**k)) {
+        match named.get(key) {
+            Some(name) => names.push(name.val().clone()),
+            None => entries.push((*key, map.get(key)?)),
+        }
+    }
+
+    let delta = DeltaOut {
+        entries: entries.iter().map(|(key, entry)| (*key, entry.as_deref())).collect(),
+        named: names.iter().map(|name| &**name).collect(),
+        lists,
+    };
+    let info = write_file(path, manifest, SnapshotKind::Delta, position, &delta)?;
+
+    let mut snapshots = vec![info];
+    snapshots.extend(manifest.snapshots.iter().cloned());
+    update(path, manifest, snapshots)
+}
+
+// writes out a snapshot under the manifest's next name, all the way to disk
+fn write_file(path: &str, manifest: &SnapshotManifest, kind: SnapshotKind, position: u64, contents: &impl Serialize) -> io::Result<SnapshotInfo> {
+    let file_name = match Path::new(path).file_name() {
+        Some(name) => format!("{}.{}", name.to_string_lossy(), manifest.next),
+        None => return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("{} isn't a file name", path))),
+    };
+    let target = sibling(path, &file_name);
+    let tmp = format!("{}.tmp", target);
+
+    let written = File::create(&tmp).and_then(|f| {
+        let mut out = BufWriter::new(Tally::new(f));
+        serialize_into(&mut out, contents).map_err(io::Error::other)?;
+        let tally = out.into_inner().map_err(|e| e.into_error())?;
+        tally.inner.sync_all()?;
+        Ok(SnapshotInfo { file: file_name.clone(), kind, version: SNAPSHOT_VERSION, len: tally.len, crc: tally.crc, position })
+    });
+    match written.and_then(|info| rename(&tmp, &target).and_then(|()| sync_dir_of(&target)).map(|()| info)) {
+        Ok(info) => Ok(info),
+        Err(e) => {
+            let _ = remove_file(&tmp);
+            Err(e)
+        }
+    }
+}
+
+// moves the manifest on to snapshots, then deletes whichever it no longer lists. manifest is left as it was if that fails
+fn update(path: &str, manifest: &mut SnapshotManifest, snapshots: Vec<SnapshotInfo>) -> io::Result<()> {
+    let updated = SnapshotManifest { next: manifest.next + 1, snapshots };
+    updated.store(path)?; // the snapshot is left behind, the next one goes under the same name
+
+    // no longer in the manifest, so nothing will read them again
+    for old in manifest.snapshots.iter().filter(|old| !updated.snapshots.iter().any(|s| s.file == old.file)) {
+        let _ = remove_file(sibling(path, &old.file));
+    }
+    *manifest = updated;
+    Ok(())
+}
+
+// the file info describes, if this build can read it
+fn open(path: &str, info: &SnapshotInfo) -> Result<File, String> {
+    if info.version != SNAPSHOT_VERSION {
+        return Err(format!("{} is format version {}, this build only reads {}.", info.file, info.version, SNAPSHOT_VERSION));
+    }
+    File::open(sibling(path, &info.file)).map_err(|e| format!("Couldn't open {}: {}", info.file, e))
+}
+
+// reads what's left of input and checks the whole of it against info
+fn check<R: Read>(mut input: Tally<R>, info: &SnapshotInfo) -> Result<(), String> {
+    if let Err(e) = io::copy(&mut input, &mut io::sink()) {
+        return Err(format!("Couldn't read {}: {}", info.file, e));
+    }
+    if input.len != info.len || input.crc != info.crc {
+        return Err(format!("{} doesn't match its checksum.", info.file));
+    }
+    Ok(())
+}
+
+// the snapshot info describes, if it's all there and matches its checksum
+fn read_snapshot<T: DeserializeOwned>(path: &str, info: &SnapshotInfo) -> Result<T, String> {
+    // checked after decoding, as it streams past. whatever bincode didn't need still counts
+    let mut input = Tally::new(BufReader::new(open(path, info)?));
+    let decoded = deserialize_from(&mut input);
+    check(input, info)?;
+    decoded.map_err(|e| format!("Couldn't decode {}: {}", info.file, e))
+}
+
+// the whole of what info describes is there and matches its checksum
+fn verify(path: &str, info: &SnapshotInfo) -> Result<(), String> {
+    check(Tally::new(BufReader::new(open(path, info)?)), info)
+}
+
+// decodes a full snapshot straight into map, giving back the rest
+fn read_full(path: &str, info: &SnapshotInfo, map: &dyn Storage) -> Result<FullRest, String> {
+    // the same encoding deserialize_from uses
+    let options = bincode::DefaultOptions::new().with_fixint_encoding().allow_trailing_bytes();
+    let mut input = bincode::Deserializer::with_reader(BufReader::new(open(path, info)?), options);
+    FullIn(map).deserialize(&mut input).map_err(|e| format!("Couldn't decode {}: {}", info.file, e))
+}
+
+// puts the newest full snapshot that checks out in map, which should be empty, with as many of its deltas layered on as check out.
+// Ok(None) without a manifest, which is a fresh store or one from an older version
+pub fn load(path: &str, map: &dyn Storage) -> Result<Option<Loaded>, String> {
+    let manifest = match SnapshotManifest::load(path)? {
+        Some(m) => m,
+        None => return Ok(None),
+    };
+
+    let mut skipped: Vec<String> = Vec::new();
+    let mut newer: Vec<&SnapshotInfo> = Vec::new(); // deltas on top of the next full snapshot down, newest first
+    for info in manifest.snapshots.iter() {
+        if info.kind == SnapshotKind::Delta {
+            newer.push(info);
+            continue;
+        }
+
+        // checked all the way through before any of it goes in map, there's no taking it back out to fall back on another
+        if let Err(e) = verify(path, info) {
+            skipped.push(e);
+            newer.clear(); // nothing to layer them on
+            continue;
+        }
+        let (named, tails) = read_full(path, info, map)?;
+        let mut lists: HashMap<ReplicaId, Vec<Key>> = HashMap::new();
+        layer(&mut lists, tails);
+
+        // oldest first, each on top of the last. past a damaged one the log has to make up the rest
+        let mut position = info.position;
+        let mut deltas: usize = 0;
+        for delta_info in newer.iter().rev() {
+            let delta = match read_snapshot::<Delta>(path, delta_info) {
+                Ok(d) => d,
+                Err(e) => {
+                    skipped.push(e);
+                    break;
+                }
+            };
+            layer(&mut lists, delta.lists);
+            for (key, entry) in delta.entries {
+                match entry {
+                    Some(entry) => if let Err(e) = map.insert(key, Arc::new(entry)) {
+                        return Err(format!("Couldn't layer {} on the store: {}", delta_info.file, e));
+                    },
+                    None => map.remove(&key),
+                }
+            }
+            for name in delta.named {
+                named.insert(name_key(&name.name), Arc::new(name));
+            }
+            position = delta_info.position;
+            deltas += 1;
+        }
+
+        let manifest = manifest.clone();
+        return Ok(Some(Loaded { named, lists, position, manifest, deltas, skipped }));
+    }
+    Err(format!("No full snapshot in the manifest is usable. {}", skipped.join(" ")))
+}
+
+// a snapshot from before the manifest, the one file at path. that's a map of text values under u64 keys, with nothing else kept,
+// so the values are rehashed into map and taken as written here, now
+pub fn load_unmanaged(path: &str, map: &dyn Storage, local_replica_id: ReplicaId) -> Result<(), String> {
+    let file = match File::open(path) {
+        Ok(f) => f,
+        Err(e) => return Err(format!("Backup file failed to open with error: {}", e)),
+    };
+    let old: LockFreeMap<u64, String> = match deserialize_from(&mut BufReader::new(file)) {
+        Ok(old) => old,
+        Err(e) => return Err(format!("Backup file deserialization failed with error: {}", e)),
+    };
+    for entry in old.iter() {
+        let value = entry.val().as_bytes().to_vec();
+        if let Err(e) = map.insert(hash_value(&value), Arc::new(Entry::new(value, EntryMeta::new(local_replica_id)))) {
+            return Err(format!("Couldn't put the backup file's values in the store: {}", e));
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,744 @@
+// where the entries of the store live. everything that reads or writes them goes through Storage, so what's behind it is picked
+// at startup: memory, the lock-free map as it always was, or disk, values appended to a file with only an index of where each one
+// is kept in memory, for stores that don't fit in RAM. named keys are small and change in place, they stay in their own map.
+// anything that goes to the disk can fail, that's handed back for the caller to answer with rather than taking the node down
+use std::collections::{HashSet, HashMap};
+use std::fs::{File, OpenOptions, rename, remove_file};
+use std::io::{self, Read, Write, Seek, SeekFrom, BufReader, BufWriter};
+use std::os::unix::fs::FileExt;
+use std::sync::{Arc, Mutex};
+use serde::{Serialize, Deserialize};
+use secko_messages::{Key, EntryMeta, ReplicaId};
+
+use crate::{Entry, sync_dir_of, map::{LockFreeMap, InsertOutcome}, commit_log::{crc32, crc32_update}};
+
+// removed keys left on the arrival list before it's swept
+const SWEEP_SLACK: usize = 1024;
+
+pub const STORE_MAGIC: [u8; 8] = *b"SEKOSTO\0";
+pub const STORE_VERSION: u16 = 1; // bump whenever the header or StoreRecord changes
+const STORE_HEADER_LEN: u64 = 12;
+const RECORD_HEADER_LEN: usize = 12;
+
+// bytes of replaced and removed entries the store file can have before it's compacted at startup, as long as they're also more
+// than what's still in use
+const COMPACT_SLACK: u64 = 1024 * 1024;
+
+pub trait Storage: Send + Sync {
+    // never overwrites, see LockFreeMap::insert_if_absent
+    fn insert_if_absent(&self, key: Key, entry: Arc<Entry>) -> io::Result<InsertOutcome<Entry>>;
+
+    // replaces whatever is there. only for deletes and expired values, the store is otherwise add-only
+    fn insert(&self, key: Key, entry: Arc<Entry>) -> io::Result<()>;
+
+    fn get(&self, key: &Key) -> io::Result<Option<Arc<Entry>>>;
+
+    // only for tombstones that every replica has seen and expired values
+    fn remove(&self, key: &Key);
+
+    // entries held, deleted or not
+    fn len(&self) -> usize;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // every entry, in no particular order. the keys are taken when it's called, so the length is known up front, and one removed
+    // since is still there. each is read as it's reached, so any of them can fail
+    fn iter(&self) -> Box<dyn ExactSizeIterator<Item = io::Result<(Key, Arc<Entry>)>> + '_>;
+
+    // the same, oldest first. a key keeps its place when it's replaced
+    fn iter_arrived(&self) -> Box<dyn ExactSizeIterator<Item = io::Result<(Key, Arc<Entry>)>> + '_>;
+
+    // just the keys, oldest first
+    fn keys(&self) -> Vec<Key>;
+
+    // for the collector, neither needs a value
+    fn tombstones(&self) -> Vec<(Key, Arc<Entry>)>;
+    fn expired(&self) -> Vec<Key>;
+}
+
+// which Storage to use
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Backend {
+    Memory,
+    Disk,
+}
+
+impl std::str::FromStr for Backend {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Backend, String> {
+        match s {
+            "memory" => Ok(Backend::Memory),
+            "disk" => Ok(Backend::Disk),
+            other => Err(format!("Unknown storage {}, expected memory or disk.", other)),
+        }
+    }
+}
+
+// keys in the order they first arrived. one that's removed is only counted, the list is swept once enough have gone. one that comes
+// back after being collected keeps its old place
+struct Arrivals {
+    inner: Mutex<(Vec<Key>, usize)>, // the list, and how many of it are still held
+}
+
+impl Arrivals {
+    fn new() -> Arrivals {
+        Arrivals { inner: Mutex::new((Vec::new(), 0)) }
+    }
+
+    fn push(&self, key: Key) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.0.push(key);
+        inner.1 += 1;
+    }
+
+    fn removed(&self, held: impl Fn(&Key) -> bool) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.1 = inner.1.saturating_sub(1);
+        if inner.0.len() > inner.1 * 2 + SWEEP_SLACK {
+            let mut seen: HashSet<Key> = HashSet::new();
+            inner.0.retain(|k| held(k) && seen.insert(*k));
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.inner.lock().unwrap().1
+    }
+
+    // what's still held, oldest first
+    fn ordered(&self, held: impl Fn(&Key) -> bool) -> Vec<Key> {
+        let keys = self.inner.lock().unwrap().0.clone();
+        let mut seen: HashSet<Key> = HashSet::new();
+        keys.into_iter().filter(|k| held(k) && seen.insert(*k)).collect()
+    }
+}
+
+// everything in the lock-free map
+pub struct MemoryStorage {
+    map: LockFreeMap<Key, Entry>,
+    arrivals: Arrivals,
+}
+
+impl MemoryStorage {
+    pub fn new() -> MemoryStorage {
+        MemoryStorage { map: LockFreeMap::new(), arrivals: Arrivals::new() }
+    }
+}
+
+impl Default for MemoryStorage {
+    fn default() -> MemoryStorage {
+        MemoryStorage::new()
+    }
+}
+
+// nothing here can fail
+impl Storage for MemoryStorage {
+    fn insert_if_absent(&self, key: Key, entry: Arc<Entry>) -> io::Result<InsertOutcome<Entry>> {
+        let outcome = self.map.insert_if_absent(key, entry);
+        if let InsertOutcome::Inserted = outcome {
+            self.arrivals.push(key);
+        }
+        Ok(outcome)
+    }
+
+    fn insert(&self, key: Key, entry: Arc<Entry>) -> io::Result<()> {
+        if self.map.insert(key, entry).is_none() {
+            self.arrivals.push(key);
+        }
+        Ok(())
+    }
+
+    fn get(&self, key: &Key) -> io::Result<Option<Arc<Entry>>> {
+        Ok(self.map.get(key).map(|e| e.val().clone()))
+    }
+
+    fn remove(&self, key: &Key) {
+        if self.map.remove(key).is_some() {
+            self.arrivals.removed(|k| self.map.get(k).is_some());
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.arrivals.len()
+    }
+
+    fn iter(&self) -> Box<dyn ExactSizeIterator<Item = io::Result<(Key, Arc<Entry>)>> + '_> {
+        let entries: Vec<io::Result<(Key, Arc<Entry>)>> = self.map.iter().map(|e| Ok((*e.key(), e.val().clone()))).collect();
+        Box::new(entries.into_iter())
+    }
+
+    fn iter_arrived(&self) -> Box<dyn ExactSizeIterator<Item = io::Result<(Key, Arc<Entry>)>> + '_> {
+        let entries: Vec<io::Result<(Key, Arc<Entry>)>> = self.keys().into_iter().filter_map(|k| self.map.get(&k).map(|e| Ok((k, e.val().clone())))).collect();
+        Box::new(entries.into_iter())
+    }
+
+    fn keys(&self) -> Vec<Key> {
+        self.arrivals.ordered(|k| self.map.get(k).is_some())
+    }
+
+    fn tombstones(&self) -> Vec<(Key, Arc<Entry>)> {
+        self.map.iter().filter(|e| e.val().is_deleted()).map(|e| (*e.key(), e.val().clone())).collect()
+    }
+
+    fn expired(&self) -> Vec<Key> {
+        self.map.iter().filter(|e| !e.val().is_deleted() && e.val().is_expired()).map(|e| *e.key()).collect()
+    }
+}
+
+// where the disk backend has an entry
+#[derive(Debug)]
+enum Slot {
+    Stored { offset: u64, len: usize, meta: EntryMeta }, // the value's bytes in the file
+    Held(Arc<Entry>), // tombstones. they have no value, and their acks change in place
+}
+
+// only compared by insert_if_absent, which is always handed a slot just made. so it's only ever the same as itself
+impl PartialEq for Slot {
+    fn eq(&self, other: &Slot) -> bool {
+        std::ptr::eq(self, other)
+    }
+}
+
+// what goes in the store file for each change to the index, the value's bytes straight after a Stored one
+#[derive(Serialize, Deserialize, Debug)]
+enum StoreRecord {
+    Stored { key: Key, meta: EntryMeta },
+    Held { key: Key, meta: EntryMeta, acked: Vec<ReplicaId> },
+    Removed { key: Key },
+}
+
+// a record as it goes in the file, and where in that its value starts
+fn encode(record: &StoreRecord, value: &[u8]) -> (Vec<u8>, u64) {
+    let head = bincode::serialize(record).unwrap();
+    let crc = crc32_update(crc32(&head), value);
+
+    let mut bytes = Vec::with_capacity(RECORD_HEADER_LEN + head.len() + value.len());
+    bytes.extend_from_slice(&(head.len() as u32).to_be_bytes());
+    bytes.extend_from_slice(&(value.len() as u32).to_be_bytes());
+    bytes.extend_from_slice(&crc.to_be_bytes());
+    bytes.extend_from_slice(&head);
+    bytes.extend_from_slice(value);
+    (bytes, (RECORD_HEADER_LEN + head.len()) as u64)
+}
+
+// the next record, its value and its length in the file. None at the end, or at one that's torn or was never written
+fn read_record(reader: &mut impl Read, remaining: u64) -> Option<(StoreRecord, Vec<u8>, u64)> {
+    let mut header = [0u8; RECORD_HEADER_LEN];
+    reader.read_exact(&mut header).ok()?;
+    let head_len = u32::from_be_bytes(header[0..4].try_into().unwrap()) as usize;
+    let value_len = u32::from_be_bytes(header[4..8].try_into().unwrap()) as usize;
+    let crc = u32::from_be_bytes(header[8..12].try_into().unwrap());
+
+    let len = (RECORD_HEADER_LEN + head_len + value_len) as u64;
+    if len > remaining {
+        return None;
+    }
+    let mut head = vec![0u8; head_len];
+    let mut value = vec![0u8; value_len];
+    reader.read_exact(&mut head).ok()?;
+    reader.read_exact(&mut value).ok()?;
+    if crc32_update(crc32(&head), &value) != crc {
+        return None;
+    }
+    Some((bincode::deserialize(&head).ok()?, value, len))
+}
+
+// values appended to a file as records of what went in the index, the index of where each value is kept in memory. the file is
+// only ever added to while running, so a slot read off the index stays good after the key is replaced or removed. it's opened
+// again at the next start and the index built back up from it, which is when the space taken by replaced and removed entries is
+// given back, if there's enough of it. file layout, all big endian:
+//   header: magic (8 bytes) | format version (u16) | unused (u16)
+//   record: head length (u32) | value length (u32) | crc32 of the head and value (u32) | head, a bincoded StoreRecord | value
+// it isn't what makes the store durable, the commit log and snapshots still do that and are loaded on top of it, so nothing is
+// synced. a crash can leave records half written, or a hole where a write failed, everything from there on is cut off at the next
+// start and the snapshot and log fill it back in. anything it has that the log doesn't, from just before a crash, is kept
+pub struct DiskStorage {
+    path: String,
+    file: File,
+    end: Mutex<u64>, // where the next record goes. reads go straight to the file
+    index: LockFreeMap<Key, Slot>,
+    arrivals: Arrivals,
+}
+
+impl DiskStorage {
+    // picks up whatever a previous run left at path, or starts a new file if there's nothing there this build can read.
+    // compacted first if enough of it is replaced or removed entries
+    pub fn open(path: &str) -> io::Result<DiskStorage> {
+        let (map, live) = DiskStorage::load_file(path)?;
+        let dead = map.end.lock().unwrap().saturating_sub(STORE_HEADER_LEN + live);
+        if dead <= live || dead <= COMPACT_SLACK {
+            return Ok(map);
+        }
+
+        // every entry held written out again in a new file, oldest first, which then takes the old one's place
+        let tmp = format!("{}.compact", path);
+        let _ = remove_file(&tmp);
+        {
+            let mut out = BufWriter::new(File::create(&tmp)?);
+            out.write_all(&store_header())?;
+            for entry in map.iter_arrived() {
+                let (key, entry) = entry?;
+                out.write_all(&encode(&record_of(key, &entry), &entry.value).0)?;
+            }
+            out.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+        }
+        drop(map);
+        rename(&tmp, path)?;
+        sync_dir_of(path)?;
+        DiskStorage::load_file(path).map(|(map, _)| map)
+    }
+
+    // the index as the records in the file at path leave it, and how many bytes of records it's still using
+    fn load_file(path: &str) -> io::Result<(DiskStorage, u64)> {
+        let file = OpenOptions::new().read(true).write(true).create(true).truncate(false).open(path)?;
+        let map = DiskStorage { path: path.to_string(), file, end: Mutex::new(STORE_HEADER_LEN), index: LockFreeMap::new(), arrivals: Arrivals::new() };
+
+        // new, or from a build before it had records. there's nothing in it worth keeping
+        let len = map.file.metadata()?.len();
+        let mut header = [0u8; STORE_HEADER_LEN as usize];
+        if len < STORE_HEADER_LEN || map.file.read_exact_at(&mut header, 0).is_err() || header.to_vec() != store_header() {
+            map.file.set_len(0)?;
+            map.file.write_all_at(&store_header(), 0)?;
+            return Ok((map, 0));
+        }
+
+        let mut sizes: HashMap<Key, u64> = HashMap::new();
+        let mut reader = BufReader::new(&map.file);
+        reader.seek(SeekFrom::Start(STORE_HEADER_LEN))?;
+        let mut at = STORE_HEADER_LEN;
+        while let Some((record, value, size)) = read_record(&mut reader, len - at) {
+            match record {
+                StoreRecord::Stored { key, meta } => {
+                    let slot = Slot::Stored { offset: at + size - value.len() as u64, len: value.len(), meta };
+                    if map.index.insert(key, Arc::new(slot)).is_none() {
+                        map.arrivals.push(key);
+                    }
+                    sizes.insert(key, size);
+                },
+                StoreRecord::Held { key, meta, acked } => {
+                    let entry = Arc::new(Entry::tombstone(meta, acked.into_iter().collect()));
+                    if map.index.insert(key, Arc::new(Slot::Held(entry))).is_none() {
+                        map.arrivals.push(key);
+                    }
+                    sizes.insert(key, size);
+                },
+                StoreRecord::Removed { key } => {
+                    map.remove_from_index(&key);
+                    sizes.remove(&key);
+                },
+            }
+            at += size;
+        }
+        drop(reader);
+
+        // whatever's after the last good record goes, so nothing written from here on follows something unreadable
+        map.file.set_len(at)?;
+        *map.end.lock().unwrap() = at;
+        Ok((map, sizes.values().sum()))
+    }
+
+    // puts record and value on the end of the file, handing back where the value went. a failed write leaves a hole nothing
+    // points at, which is only wasted space until the next start cuts it off
+    fn append(&self, record: &StoreRecord, value: &[u8]) -> io::Result<u64> {
+        let (bytes, value_at) = encode(record, value);
+
+        // only the space is taken under the lock, the write itself can go alongside others
+        let offset = {
+            let mut end = self.end.lock().unwrap();
+            *end += bytes.len() as u64;
+            *end - bytes.len() as u64
+        };
+        if let Err(e) = self.file.write_all_at(&bytes, offset) {
+            return Err(io::Error::new(e.kind(), format!("Couldn't write to the store file {}: {}", self.path, e)));
+        }
+        Ok(offset + value_at)
+    }
+
+    // writes entry out and gives back where it is
+    fn slot(&self, key: Key, entry: &Arc<Entry>) -> io::Result<Slot> {
+        let offset = self.append(&record_of(key, entry), &entry.value)?;
+        if entry.is_deleted() {
+            return Ok(Slot::Held(entry.clone()));
+        }
+        Ok(Slot::Stored { offset, len: entry.value.len(), meta: entry.meta })
+    }
+
+    fn load(&self, slot: &Slot) -> io::Result<Arc<Entry>> {
+        match slot {
+            Slot::Held(entry) => Ok(entry.clone()),
+            Slot::Stored { offset, len, meta } => {
+                let mut value = vec![0; *len];
+                if let Err(e) = self.file.read_exact_at(&mut value, *offset) {
+                    return Err(io::Error::new(e.kind(), format!("Couldn't read from the store file {}: {}", self.path, e)));
+                }
+                Ok(Arc::new(Entry::new(value, *meta)))
+            }
+        }
+    }
+
+    // keys and their slots as they are now, loaded one at a time as they're iterated
+    fn loading(&self, slots: Vec<(Key, Arc<Slot>)>) -> Box<dyn ExactSizeIterator<Item = io::Result<(Key, Arc<Entry>)>> + '_> {
+        Box::new(slots.into_iter().map(move |(key, slot)| self.load(&slot).map(|entry| (key, entry))))
+    }
+
+    fn remove_from_index(&self, key: &Key) -> bool {
+        if self.index.remove(key).is_none() {
+            return false;
+        }
+        self.arrivals.removed(|k| self.index.get(k).is_some());
+        true
+    }
+}
+
+fn store_header() -> Vec<u8> {
+    let mut bytes = STORE_MAGIC.to_vec();
+    bytes.extend_from_slice(&STORE_VERSION.to_be_bytes());
+    bytes.extend_from_slice(&0u16.to_be_bytes());
+    bytes
+}
+
+fn record_of(key: Key, entry: &Entry) -> StoreRecord {
+    match &entry.deleted {
+        Some(acked) => StoreRecord::Held { key, meta: entry.meta, acked: acked.lock().unwrap().iter().copied().collect() },
+        None => StoreRecord::Stored { key, meta: entry.meta },
+    }
+}
+
+impl Storage for DiskStorage {
+    fn insert_if_absent(&self, key: Key, entry: Arc<Entry>) -> io::Result<InsertOutcome<Entry>> {
+        // antientropy brings back plenty we already have, so look before writing the value out
+        if let Some(held) = self.get(&key)? {
+            return Ok(if *held == *entry { InsertOutcome::Duplicate } else { InsertOutcome::Collision(held) });
+        }
+
+        // someone else can still get there first, which leaves what we wrote unused
+        match self.index.insert_if_absent(key, Arc::new(self.slot(key, &entry)?)) {
+            InsertOutcome::Inserted => {
+                self.arrivals.push(key);
+                Ok(InsertOutcome::Inserted)
+            },
+            InsertOutcome::Duplicate => unreachable!(), // a fresh slot is never the same as one held
+            InsertOutcome::Collision(held) => {
+                let held = self.load(&held)?;
+                Ok(if *held == *entry { InsertOutcome::Duplicate } else { InsertOutcome::Collision(held) })
+            }
+        }
+    }
+
+    fn insert(&self, key: Key, entry: Arc<Entry>) -> io::Result<()> {
+        // the snapshot and log going back in at startup are mostly what the file already has, that needn't be written twice.
+        // a tombstone's acks aren't worth a record of their own, they come back with antientropy
+        if let Some(held) = self.index.get(&key) {
+            let same = match held.val().as_ref() {
+                Slot::Stored { meta, len, .. } => *meta == entry.meta && *len == entry.value.len() && *self.load(held.val())? == *entry,
+                Slot::Held(tombstone) => tombstone.meta == entry.meta && entry.is_deleted(),
+            };
+            if same {
+                if entry.is_deleted() {
+                    self.index.insert(key, Arc::new(Slot::Held(entry)));
+                }
+                return Ok(());
+            }
+        }
+
+        if self.index.insert(key, Arc::new(self.slot(key, &entry)?)).is_none() {
+            self.arrivals.push(key);
+        }
+        Ok(())
+    }
+
+    fn get(&self, key: &Key) -> io::Result<Option<Arc<Entry>>> {
+        match self.index.get(key) {
+            Some(slot) => self.load(slot.val()).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    fn remove(&self, key: &Key) {
+        // if this doesn't make it to the file the key is back after a restart. it's only ever a collected tombstone or an
+        // expired value, which are collected again
+        if self.remove_from_index(key) {
+            let _ = self.append(&StoreRecord::Removed { key: *key }, &[]);
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.arrivals.len()
+    }
+
+    fn iter(&self) -> Box<dyn ExactSizeIterator<Item = io::Result<(Key, Arc<Entry>)>> + '_> {
+        self.loading(self.index.iter().map(|s| (*s.key(), s.val().clone())).collect())
+    }
+
+    fn iter_arrived(&self) -> Box<dyn ExactSizeIterator<Item = io::Result<(Key, Arc<Entry>)>> + '_> {
+        let slots: Vec<(Key, Arc<Slot>)> = self.keys().into_iter().filter_map(|k| self.index.get(&k).map(|s| (k, s.val().clone()))).collect();
+        self.loading(slots)
+    }
+
+    fn keys(&self) -> Vec<Key> {
+        self.arrivals.ordered(|k| self.index.get(k).is_some())
+    }
+
+    fn tombstones(&self) -> Vec<(Key, Arc<Entry>)> {
+        self.index.iter().filter_map(|s| match s.val().as_ref() {
+            Slot::Held(entry) => Some((*s.key(), entry.clone())),
+            Slot::Stored { .. } => None,
+        }).collect()
+    }
+
+    fn expired(&self) -> Vec<Key> {
+        self.index.iter().filter(|s| match s.val().as_ref() {
+            Slot::Stored { meta, .. } => meta.is_expired(),
+            Slot::Held(_) => false,
+        }).map(|s| *s.key()).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+    use secko_messages::hash_value;
+    use crate::{Commit, test_util::TempDir, commit_log::{self, Appender, LogRecord}, snapshot::{self, SnapshotManifest}};
+
+    fn entry(value: &[u8]) -> Arc<Entry> {
+        Arc::new(Entry::new(value.to_vec(), EntryMeta::new(1)))
+    }
+
+    fn tombstone() -> Arc<Entry> {
+        Arc::new(Entry::tombstone(EntryMeta::new(1), HashSet::from([1])))
+    }
+
+    fn put(map: &DiskStorage, value: &[u8]) -> Key {
+        let key = hash_value(value);
+        assert!(matches!(map.insert_if_absent(key, entry(value)).unwrap(), InsertOutcome::Inserted));
+        key
+    }
+
+    fn value(map: &DiskStorage, key: &Key) -> Option<Vec<u8>> {
+        map.get(key).unwrap().map(|e| e.value.clone())
+    }
+
+    fn held(map: &DiskStorage) -> Vec<(Key, Arc<Entry>)> {
+        map.iter_arrived().collect::<io::Result<_>>().unwrap()
+    }
+
+    #[test]
+    fn insert_if_absent_never_overwrites() {
+        let dir = TempDir::new("storage-insert-if-absent");
+        let map = DiskStorage::open(&dir.path("store")).unwrap();
+        let key = put(&map, b"first");
+
+        assert!(matches!(map.insert_if_absent(key, entry(b"first")).unwrap(), InsertOutcome::Duplicate));
+        match map.insert_if_absent(key, entry(b"something else")).unwrap() {
+            InsertOutcome::Collision(held) => assert_eq!(held.value, b"first"),
+            _ => panic!("Expected a collision"),
+        }
+        assert_eq!(value(&map, &key), Some(b"first".to_vec()));
+        assert_eq!(map.len(), 1);
+
+        // a tombstone isn't the value it replaced
+        map.insert(key, tombstone()).unwrap();
+        assert!(matches!(map.insert_if_absent(key, entry(b"first")).unwrap(), InsertOutcome::Collision(held) if held.is_deleted()));
+        assert!(matches!(map.insert_if_absent(key, tombstone()).unwrap(), InsertOutcome::Duplicate));
+    }
+
+    #[test]
+    fn insert_replaces_and_keeps_the_place() {
+        let dir = TempDir::new("storage-insert");
+        let map = DiskStorage::open(&dir.path("store")).unwrap();
+        let a = put(&map, b"a");
+        let b = put(&map, b"b");
+
+        map.insert(a, entry(b"a again, longer than it was")).unwrap();
+        assert_eq!(value(&map, &a), Some(b"a again, longer than it was".to_vec()));
+        assert_eq!(value(&map, &b), Some(b"b".to_vec()));
+
+        map.insert(b, tombstone()).unwrap();
+        assert!(map.get(&b).unwrap().unwrap().is_deleted());
+        assert_eq!(map.tombstones().iter().map(|(k, _)| *k).collect::<Vec<_>>(), vec![b]);
+
+        // a new key goes on the end, replaced ones stay where they were
+        let c = hash_value(b"c");
+        map.insert(c, entry(b"c")).unwrap();
+        assert_eq!(map.keys(), vec![a, b, c]);
+        assert_eq!(map.len(), 3);
+    }
+
+    #[test]
+    fn remove_forgets_the_key() {
+        let dir = TempDir::new("storage-remove");
+        let map = DiskStorage::open(&dir.path("store")).unwrap();
+        let a = put(&map, b"a");
+        let b = put(&map, b"b");
+
+        map.remove(&a);
+        assert!(map.get(&a).unwrap().is_none());
+        assert_eq!(map.len(), 1);
+        assert_eq!(map.keys(), vec![b]);
+        assert_eq!(held(&map).iter().map(|(k, _)| *k).collect::<Vec<_>>(), vec![b]);
+
+        // removing it again, or something never held, changes nothing
+        map.remove(&a);
+        map.remove(&hash_value(b"never"));
+        assert_eq!(map.len(), 1);
+
+        // and it can come back, at its old place until the list is swept
+        put(&map, b"a");
+        assert_eq!(map.keys(), vec![a, b]);
+    }
+
+    #[test]
+    fn iter_arrived_keeps_order_through_a_sweep() {
+        let dir = TempDir::new("storage-sweep");
+        let map = DiskStorage::open(&dir.path("store")).unwrap();
+        let values: Vec<Vec<u8>> = (0..SWEEP_SLACK * 2).map(|i| format!("value {}", i).into_bytes()).collect();
+        let keys: Vec<Key> = values.iter().map(|v| put(&map, v)).collect();
+
+        // the first half and most of the rest is enough gone for a sweep
+        let mut kept: Vec<Key> = Vec::new();
+        for (i, key) in keys.iter().enumerate() {
+            if i >= SWEEP_SLACK && i % 4 == 3 {
+                kept.push(*key);
+            } else {
+                map.remove(key);
+            }
+        }
+        let listed = map.arrivals.inner.lock().unwrap().0.len();
+        assert!(listed < keys.len(), "never swept, {} still listed", listed);
+
+        assert_eq!(map.len(), kept.len());
+        assert_eq!(map.keys(), kept);
+        let arrived = held(&map);
+        assert_eq!(arrived.iter().map(|(k, _)| *k).collect::<Vec<_>>(), kept);
+        for (key, entry) in arrived {
+            assert_eq!(hash_value(&entry.value), key);
+        }
+
+        // one swept away comes back on the end
+        put(&map, &values[0]);
+        assert_eq!(map.keys().last(), Some(&keys[0]));
+    }
+
+    #[test]
+    fn restart_picks_up_the_store_file_and_the_snapshot_and_log_go_on_top() {
+        let dir = TempDir::new("storage-restart");
+        let (store_path, snapshot_path, log_path) = (dir.path("store"), dir.path("snapshot"), dir.path("log"));
+        let map = DiskStorage::open(&store_path).unwrap();
+        let named = LockFreeMap::new();
+
+        let a = put(&map, b"a");
+        let b = put(&map, b"b");
+        map.insert(a, tombstone()).unwrap();
+        let mut manifest = SnapshotManifest::default();
+        snapshot::write_full(&snapshot_path, &mut manifest, 0, &map, &named, &[]).unwrap();
+
+        // after the snapshot, only in the log
+        commit_log::create(&log_path, 0).unwrap();
+        let mut log = Appender::open(&log_path, 0, commit_log::SEGMENT_BYTES).unwrap();
+        let c = put(&map, b"c");
+        map.insert(b, tombstone()).unwrap();
+        let commits = [Commit::Put { key: c, meta: map.get(&c).unwrap().unwrap().meta }, Commit::Delete { key: b, meta: map.get(&b).unwrap().unwrap().meta }];
+        let records: Vec<u8> = commits.iter().flat_map(|commit| commit_log::encode(&LogRecord::of(commit, &map).unwrap())).collect();
+        log.append(&records, commits.len() as u64, true).unwrap();
+        let before = held(&map);
+        drop(map);
+
+        // the file has it all already
+        let map = DiskStorage::open(&store_path).unwrap();
+        let same = |map: &DiskStorage| {
+            let after = held(map);
+            assert_eq!(after.len(), before.len());
+            for ((key_before, entry_before), (key_after, entry_after)) in before.iter().zip(after.iter()) {
+                assert_eq!(key_before, key_after);
+                assert_eq!(entry_before, entry_after);
+                assert_eq!(entry_before.meta, entry_after.meta);
+            }
+            assert!(map.get(&a).unwrap().unwrap().is_deleted());
+            assert!(map.get(&b).unwrap().unwrap().is_deleted());
+        };
+        same(&map);
+
+        // and loading the snapshot and log over it changes nothing. none of it is written again, only b, which goes back to the
+        // snapshot's value and then to the log's delete
+        let len = std::fs::metadata(&store_path).unwrap().len();
+        let loaded = snapshot::load(&snapshot_path, &map).unwrap().unwrap();
+        let recovered = commit_log::recover(&log_path, 1, Some(loaded.position)).unwrap();
+        assert!(commit_log::replay(recovered.records, &map, &loaded.named, 1).unwrap().is_empty());
+        same(&map);
+        let grown = std::fs::metadata(&store_path).unwrap().len() - len;
+        assert!(grown > 0 && grown < 256, "grew by {}", grown);
+    }
+
+    #[test]
+    fn restart_cuts_off_a_torn_record_and_starts_over_a_file_it_cant_read() {
+        let dir = TempDir::new("storage-torn");
+        let store_path = dir.path("store");
+        let map = DiskStorage::open(&store_path).unwrap();
+        let a = put(&map, b"a");
+        put(&map, b"b, which the crash tears");
+        let torn = std::fs::metadata(&store_path).unwrap().len() - 3;
+        map.file.set_len(torn).unwrap();
+        drop(map);
+
+        let map = DiskStorage::open(&store_path).unwrap();
+        assert_eq!(map.keys(), vec![a]);
+        assert!(std::fs::metadata(&store_path).unwrap().len() < torn);
+
+        // written after what was cut off, and still there the next time
+        let c = put(&map, b"c");
+        drop(map);
+        assert_eq!(DiskStorage::open(&store_path).unwrap().keys(), vec![a, c]);
+
+        // no header, so not one of ours, or from before there were records
+        std::fs::write(&store_path, b"just a value").unwrap();
+        let map = DiskStorage::open(&store_path).unwrap();
+        assert!(map.is_empty());
+        assert_eq!(std::fs::metadata(&store_path).unwrap().len(), STORE_HEADER_LEN);
+    }
+
+    #[test]
+    fn file_grows_while_running_and_is_compacted_at_the_next_start() {
+        let dir = TempDir::new("storage-compact");
+        let store_path = dir.path("store");
+        let map = DiskStorage::open(&store_path).unwrap();
+        let values: Vec<Vec<u8>> = (0..2048).map(|i| format!("{:01024}", i).into_bytes()).collect();
+        let keys: Vec<Key> = values.iter().map(|v| put(&map, v)).collect();
+
+        // deleted and collected, nothing is given back until the file is opened again
+        let size = std::fs::metadata(&store_path).unwrap().len();
+        for key in keys.iter().skip(16) {
+            map.insert(*key, tombstone()).unwrap();
+            map.remove(key);
+        }
+        assert!(std::fs::metadata(&store_path).unwrap().len() > size);
+        drop(map);
+
+        let map = DiskStorage::open(&store_path).unwrap();
+        assert!(std::fs::metadata(&store_path).unwrap().len() < size / 64);
+        assert_eq!(map.keys(), keys[..16].to_vec());
+        for (key, value) in keys.iter().zip(values.iter()).take(16) {
+            assert_eq!(map.get(key).unwrap().unwrap().value, *value);
+        }
+        assert!(!Path::new(&format!("{}.compact", store_path)).exists());
+    }
+
+    #[test]
+    fn a_value_that_cant_be_read_is_an_error() {
+        let dir = TempDir::new("storage-unreadable");
+        let map = DiskStorage::open(&dir.path("store")).unwrap();
+        let a = put(&map, b"a value");
+
+        // everything the index points at is gone
+        map.file.set_len(0).unwrap();
+        assert!(map.get(&a).is_err());
+        assert!(map.insert_if_absent(a, entry(b"a value")).is_err());
+        assert!(map.iter_arrived().next().unwrap().is_err());
+
+        // nothing that doesn't read a value minds
+        assert_eq!(map.len(), 1);
+        assert_eq!(map.keys(), vec![a]);
+    }
+}
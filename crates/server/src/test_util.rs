@@ -0,0 +1,25 @@
+// what tests share, here and in the crates built on this one, which get it with the test-util feature
+use std::fs::{create_dir_all, remove_dir_all};
+
+// a fresh directory per test, so they can run side by side. removed again when it's dropped. the tests of one binary share a
+// process, so name has to be different for each of them
+pub struct TempDir(pub String);
+
+impl TempDir {
+    pub fn new(name: &str) -> TempDir {
+        let dir = std::env::temp_dir().join(format!("secko-{}-{}", std::process::id(), name));
+        let _ = remove_dir_all(&dir);
+        create_dir_all(&dir).unwrap();
+        TempDir(dir.to_str().unwrap().to_string())
+    }
+
+    pub fn path(&self, file: &str) -> String {
+        format!("{}/{}", self.0, file)
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = remove_dir_all(&self.0);
+    }
+}
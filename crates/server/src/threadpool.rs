@@ -1,13 +1,14 @@
 // FROM CHAPTER 20 OF RUST TEXTBOOK
 
 use std::{
-    sync::{mpsc, Arc, Mutex},
+    sync::{mpsc, Arc, Mutex, atomic::{AtomicUsize, Ordering}},
     thread,
 };
 
 pub struct ThreadPool {
     _workers: Vec<Worker>,
     sender: mpsc::Sender<Job>,
+    queued: Arc<AtomicUsize>, // jobs handed to execute that no worker has picked up yet
 }
 
 type Job = Box<dyn FnOnce() + Send + 'static>;
@@ -26,14 +27,20 @@ impl ThreadPool {
         let (sender, receiver) = mpsc::channel();
 
         let receiver = Arc::new(Mutex::new(receiver));
+        let queued = Arc::new(AtomicUsize::new(0));
 
         let mut workers = Vec::with_capacity(size);
 
         for id in 0..size {
-            workers.push(Worker::new(id, Arc::clone(&receiver)));
+            workers.push(Worker::new(id, Arc::clone(&receiver), Arc::clone(&queued)));
         }
 
-        ThreadPool { _workers: workers, sender: sender }
+        ThreadPool { _workers: workers, sender: sender, queued }
+    }
+
+    /// Number of jobs waiting for a free worker.
+    pub fn queued(&self) -> usize {
+        self.queued.load(Ordering::Relaxed)
     }
 
     pub fn execute<F>(&self, f: F)
@@ -42,6 +49,7 @@ impl ThreadPool {
     {
         let job = Box::new(f);
 
+        self.queued.fetch_add(1, Ordering::Relaxed);
         self.sender.send(job).unwrap();
     }
 }
@@ -52,9 +60,10 @@ struct Worker {
 }
 
 impl Worker {
-    fn new(id: usize, receiver: Arc<Mutex<mpsc::Receiver<Job>>>) -> Worker {
+    fn new(id: usize, receiver: Arc<Mutex<mpsc::Receiver<Job>>>, queued: Arc<AtomicUsize>) -> Worker {
         let thread = thread::spawn(move || loop {
             let job = receiver.lock().unwrap().recv().unwrap();
+            queued.fetch_sub(1, Ordering::Relaxed);
 
             job();
         });
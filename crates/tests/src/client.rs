@@ -1,9 +1,7 @@
 // needs to be run as an executable - pregenerated workload passed from tests, and simply runs through it by writing and requesting
 // saves statistics 
 
-use std::collections::hash_map::DefaultHasher;
 use std::fs::{File, OpenOptions};
-use std::hash::{Hash, Hasher};
 use std::net::{SocketAddrV4, TcpStream};
 
 use std::time::{SystemTime, Duration};
@@ -12,16 +10,16 @@ use std::{env, thread};
 use std::io::{BufReader, BufWriter};
 use std::sync::Arc;
 use bincode::deserialize_from;
-use secko_messages::{Message, KVPair, FoundValue, send_message, receive_message};
-use secko_tests::{KeyParam, Workload, DataPoint, async_send_message, async_receive_message, Param};
+use secko_messages::{Message, KVPair, Key, hash_value, FoundValue, PushResult, send_message, receive_message, connect};
+use secko_tests::{KeyParam, Workload, async_send_message, async_receive_message, Param};
 
 use rand_distr::{Distribution, Beta};
 
 fn main() {
     
     let args: Vec<String> = env::args().collect();
-    if args.len() != 5 {
-        println!("Insufficient arguments. Please provide \"<id> <workload-location> <server-ip:port> <test-type>\".");
+    if args.len() != 5 && args.len() != 6 {
+        println!("Insufficient arguments. Please provide \"<id> <workload-location> <server-ip:port> <test-type> [batch-size]\".");
         return;
     }
 
@@ -62,6 +60,18 @@ fn main() {
 
     let test_type = &args[4];
 
+    // optional, anything above 1 sends PushBatchReq/MultiRetrieveReq instead of one request per value
+    let batch_size: usize = match args.get(5) {
+        Some(b) => match b.trim().parse::<usize>() {
+            Ok(v) if v > 0 => v,
+            _ => {
+                println!("Please pass a valid positive value for the batch size (parameter 5).");
+                return
+            }
+        },
+        None => 1
+    };
+
     println!("arguments given: id: {}, workload_file: {}, server_address: {}, test_type: {}", id, &args[2].trim(), server_address, test_type);
     println!("send_rate: {}", workload[0].params.client_send_rate);
 
@@ -73,7 +83,7 @@ fn main() {
 
     // make connection to the server
     {
-        let mut conn = match TcpStream::connect(server_address) {
+        let mut conn = match connect(server_address) {
             Ok(c) => c,
             Err(_) => {
                 panic!("Failed to establish connection to server, line 104");
@@ -84,22 +94,25 @@ fn main() {
             // we have a given set of parameters for this workload - a send rate, value size, and number of values
             let send_rate = w.params.client_send_rate;            
 
+            if batch_size > 1 {
+                push_batched(&mut conn, &w, batch_size, &mut keys_sent);
+                continue;
+            }
+
             for (i, data) in w.data.iter().enumerate() {   
                 // if i%10 == 0 { 
                     println!("{}", i);
                 // }
 
                 // create data
-                let to_send = DataPoint{value: data.to_string(), timestamp: SystemTime::now()}; // includes net latency both ways and hashing time on client side as thats all overhead
-                let as_str = to_send.to_string();
+                let sent_at = SystemTime::now(); // includes net latency both ways and hashing time on client side as thats all overhead
+                let value = data.clone().into_bytes(); // sent as is, the server keeps track of when it got it (see StatReq)
 
                 // make key for it
-                let mut hash = DefaultHasher::new();
-                as_str.to_string().hash(&mut hash);
-                let hashed: u64 = hash.finish(); 
+                let hashed: Key = hash_value(&value); 
 
                 // create message
-                let req = Message::PushReq ( KVPair {key: hashed, value: as_str} );
+                let req = Message::PushReq ( KVPair {key: hashed, value}, None );
 
                 // send it
                 match send_message(&mut conn, req) {
@@ -119,7 +132,7 @@ fn main() {
                 };
 
                 match result {
-                    Message::Error(e) => println!("Push {} failed with error: {}", hashed, e),
+                    Message::Error{ code, detail } => println!("Push {} failed with error: {} {:?}", hashed, code, detail),
                     Message::PushResp{success: true} => {
                         // println!("Key pushed successfully.");
 
@@ -127,7 +140,7 @@ fn main() {
                         let return_time = SystemTime::now();
 
                         // add this datapoint to our vec
-                        keys_sent.push(KeyParam { key: hashed, duration: return_time.duration_since(to_send.timestamp).expect("fail"), params: w.params.clone(), timestamp: to_send.timestamp })
+                        keys_sent.push(KeyParam { key: hashed, duration: return_time.duration_since(sent_at).expect("fail"), params: w.params.clone(), timestamp: sent_at })
 
                     },
                     _ => println!("Execution should not have reached this point."),
@@ -149,7 +162,7 @@ fn main() {
     {
         // create thread for GETs
         let beta = Beta::new(5.0, 1.0).expect("177 err"); // biases towards end
-        let mut conn = match TcpStream::connect(server_address) {
+        let mut conn = match connect(server_address) {
             Ok(c) => c,
             Err(_) => {
                 panic!("Failed to establish connection to server, line 181");
@@ -158,60 +171,64 @@ fn main() {
 
         let len = keys_sent.len();
 
+        if batch_size > 1 {
+            get_batched(&mut conn, &keys_sent, batch_size, &mut keys_requested);
+        }
+        else {
+            for i in 0..len {
+                println!("get {}!", i);
+            // loop {
 
-        for i in 0..len {
-            println!("get {}!", i);
-        // loop {
+                // pick random element close to end of keys_sent
+                // let mut a = rand::thread_rng();
 
-            // pick random element close to end of keys_sent
-            // let mut a = rand::thread_rng();
+                let index = len - 3;// (beta.sample(&mut a) * (len as f64)) as usize;
+                let kp = &keys_sent[index];
 
-            let index = len - 3;// (beta.sample(&mut a) * (len as f64)) as usize;
-            let kp = &keys_sent[index];
+                // start timer
+                let start = SystemTime::now();
 
-            // start timer
-            let start = SystemTime::now();
+                // send request to retrieve
+                let data = Message::RetrieveReq { key: kp.key };
+                match send_message(&mut conn, data) {
+                    Ok(res) => res,
+                    Err(_) => {
+                        panic!("Failed to send message to server, line 199");
+                    }
+                };
 
-            // send request to retrieve
-            let data = Message::RetrieveReq { key: kp.key };
-            match send_message(&mut conn, data) {
-                Ok(res) => res,
-                Err(_) => {
-                    panic!("Failed to send message to server, line 199");
-                }
-            };
+                // receive the result (also as a keyparam), except this time num_values is the length of the vector
+                let result: Message =  match receive_message(&mut conn) {
+                    Ok(res) => res,
+                    Err(_) => {
+                        panic!("Failed to receive message from server, line 207");
+                    }
+                };
 
-            // receive the result (also as a keyparam), except this time num_values is the length of the vector
-            let result: Message =  match receive_message(&mut conn) {
-                Ok(res) => res,
-                Err(_) => {
-                    panic!("Failed to receive message from server, line 207");
+                match result {
+                    Message::RetrieveResp{ result: FoundValue::Success {..}} => {
+                        // end timer
+                        let end = SystemTime::now();
+
+                        // log/store
+                        keys_requested.push(KeyParam {
+                            key: kp.key,
+                            duration: end.duration_since(start).expect("duration error 219"),
+                            params: Param {
+                                ai_send_rate: kp.params.ai_send_rate,
+                                client_send_rate: kp.params.client_send_rate,
+                                value_size: kp.params.value_size,
+                                num_values: len as u64
+                            },
+                            timestamp: start
+                        });
+                    },
+                    _ => ()
                 }
-            };
 
-            match result {
-                Message::RetrieveResp{ result: FoundValue::Success {..}} => {
-                    // end timer
-                    let end = SystemTime::now();
-
-                    // log/store
-                    keys_requested.push(KeyParam {
-                        key: kp.key,
-                        duration: end.duration_since(start).expect("duration error 219"),
-                        params: Param {
-                            ai_send_rate: kp.params.ai_send_rate,
-                            client_send_rate: kp.params.client_send_rate,
-                            value_size: kp.params.value_size,
-                            num_values: len as u64
-                        },
-                        timestamp: start
-                    });
-                },
-                _ => ()
+                // wait before sending next one
+                thread::sleep(Duration::from_millis(((1.0/kp.params.client_send_rate)*1000.0) as u64));
             }
-
-            // wait before sending next one
-            thread::sleep(Duration::from_millis(((1.0/kp.params.client_send_rate)*1000.0) as u64));
         }
     }
 
@@ -246,4 +263,112 @@ fn main() {
     // print timer result for the dump so we know to subtract that. or somehow return that value?
     let dump_duration: Duration = SystemTime::now().duration_since(dump_start).expect("duration 264");
     println!("Dump duration - {} ms", dump_duration.as_millis());
-} 
\ No newline at end of file
+}
+
+// same as the push loop in main, but batch_size values per request. every value in a batch is logged with the batch's round trip
+fn push_batched(conn: &mut TcpStream, w: &Workload, batch_size: usize, keys_sent: &mut Vec<KeyParam>) {
+    let send_rate = w.params.client_send_rate;
+
+    for (i, chunk) in w.data.chunks(batch_size).enumerate() {
+        println!("batch {}", i);
+
+        // create data, all timed from when the batch goes out
+        let timestamp = SystemTime::now();
+        let pairs: Vec<KVPair> = chunk.iter().map(|data| {
+            let value = data.clone().into_bytes();
+
+            // make key for it
+            KVPair {key: hash_value(&value), value}
+        }).collect();
+        let keys: Vec<Key> = pairs.iter().map(|p| p.key).collect();
+
+        // send it
+        match send_message(conn, Message::PushBatchReq(pairs, None)) {
+            Ok(_) => (),
+            Err(_) => {
+                panic!("Failed to send batch message");
+            }
+        };
+
+        // wait on a response
+        let result: Message = match receive_message(conn) {
+            Ok(res) => res,
+            Err(_) => {
+                panic!("Failed to receive batch response from server");
+            }
+        };
+
+        match result {
+            Message::Error{ code, detail } => println!("Batch push failed with error: {} {:?}", code, detail),
+            Message::PushBatchResp(results) => {
+                let return_time = SystemTime::now();
+
+                for (key, result) in keys.iter().zip(results) {
+                    match result {
+                        PushResult::HashMismatch => println!("Push {} failed with a hash mismatch", key),
+                        PushResult::Collision => println!("Push {} failed with a key collision", key),
+                        PushResult::Deleted => println!("Push {} failed as the key was deleted", key),
+                        _ => keys_sent.push(KeyParam { key: *key, duration: return_time.duration_since(timestamp).expect("fail"), params: w.params.clone(), timestamp })
+                    }
+                }
+            },
+            _ => println!("Execution should not have reached this point."),
+        }
+
+        // wait before sending next one, keeping the same per-value rate
+        thread::sleep(Duration::from_millis(((chunk.len() as f64/send_rate)*1000.0) as u64));
+    }
+}
+
+// requests every sent key once, batch_size keys per request
+fn get_batched(conn: &mut TcpStream, keys_sent: &[KeyParam], batch_size: usize, keys_requested: &mut Vec<KeyParam>) {
+    let len = keys_sent.len();
+
+    for (i, chunk) in keys_sent.chunks(batch_size).enumerate() {
+        println!("get batch {}!", i);
+
+        // start timer
+        let start = SystemTime::now();
+
+        // send request to retrieve
+        let data = Message::MultiRetrieveReq(chunk.iter().map(|kp| kp.key).collect());
+        match send_message(conn, data) {
+            Ok(res) => res,
+            Err(_) => {
+                panic!("Failed to send batch message to server");
+            }
+        };
+
+        let result: Message = match receive_message(conn) {
+            Ok(res) => res,
+            Err(_) => {
+                panic!("Failed to receive batch response from server");
+            }
+        };
+
+        if let Message::MultiRetrieveResp(results) = result {
+            // end timer
+            let end = SystemTime::now();
+
+            // log/store the ones that were found
+            for (kp, found) in chunk.iter().zip(results) {
+                if let FoundValue::Success {..} = found {
+                    keys_requested.push(KeyParam {
+                        key: kp.key,
+                        duration: end.duration_since(start).expect("duration error"),
+                        params: Param {
+                            ai_send_rate: kp.params.ai_send_rate,
+                            client_send_rate: kp.params.client_send_rate,
+                            value_size: kp.params.value_size,
+                            num_values: len as u64
+                        },
+                        timestamp: start
+                    });
+                }
+            }
+        }
+
+        // wait before sending next one
+        thread::sleep(Duration::from_millis(((chunk.len() as f64/chunk[0].params.client_send_rate)*1000.0) as u64));
+    }
+}
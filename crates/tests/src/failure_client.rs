@@ -1,9 +1,7 @@
 // needs to be run as an executable - pregenerated workload passed from tests, and simply runs through it by writing and requesting
 // saves statistics 
 
-use std::collections::hash_map::DefaultHasher;
 use std::fs::{File};
-use std::hash::{Hash, Hasher};
 use std::net::{SocketAddrV4, TcpStream};
 
 use std::time::{SystemTime, Duration};
@@ -11,14 +9,14 @@ use std::{env, thread};
 
 use std::io::{BufReader};
 use bincode::deserialize_from;
-use secko_messages::{Message, KVPair, send_message, receive_message};
-use secko_tests::{KeyParam, Workload, DataPoint};
+use secko_messages::{Message, KVPair, Key, hash_value, PushResult, send_message, receive_message, connect};
+use secko_tests::{KeyParam, Workload};
 
 fn main() {
     
     let args: Vec<String> = env::args().collect();
-    if args.len() != 4 {
-        println!("Insufficient arguments. Please provide \"<id> <workload-location> <server-ip:port>\".");
+    if args.len() != 4 && args.len() != 5 {
+        println!("Insufficient arguments. Please provide \"<id> <workload-location> <server-ip:port> [batch-size]\".");
         return;
     }
 
@@ -55,6 +53,18 @@ fn main() {
         }
     };
 
+    // optional, anything above 1 sends PushBatchReq instead of one request per value
+    let batch_size: usize = match args.get(4) {
+        Some(b) => match b.trim().parse::<usize>() {
+            Ok(v) if v > 0 => v,
+            _ => {
+                println!("Please pass a valid positive value for the batch size (parameter 4).");
+                return
+            }
+        },
+        None => 1
+    };
+
     // create a vector that will store key->parameter mappings (timestamp, send rate, value size)
     let mut keys_sent: Vec<KeyParam> = Vec::new();
 
@@ -63,13 +73,18 @@ fn main() {
         let send_rate = w.params.client_send_rate;
 
         // make connection to the server
-        let mut conn = match TcpStream::connect(server_address) {
+        let mut conn = match connect(server_address) {
             Ok(c) => c,
             Err(_) => {
                 panic!("Failed to establish connection to server, line 104");
             }
         };
 
+        if batch_size > 1 {
+            push_batched(&mut conn, w, batch_size, &mut keys_sent);
+            continue;
+        }
+
         // let mut ctr = 0;s
 
         for data in w.data {
@@ -77,16 +92,14 @@ fn main() {
             // ctr += 1;
                     
             // create data
-            let to_send = DataPoint{value: data, timestamp: SystemTime::now()}; // includes net latency both ways and hashing time on client side as thats all overhead
-            let as_str = to_send.to_string();
+            let sent_at = SystemTime::now(); // includes net latency both ways and hashing time on client side as thats all overhead
+            let value = data.into_bytes(); // sent as is, the server keeps track of when it got it (see StatReq)
 
             // make key for it
-            let mut hash = DefaultHasher::new();
-            as_str.to_string().hash(&mut hash);
-            let hashed: u64 = hash.finish(); 
+            let hashed: Key = hash_value(&value); 
 
             // create message
-            let req = Message::PushReq ( KVPair {key: hashed, value: as_str} );
+            let req = Message::PushReq ( KVPair {key: hashed, value}, None );
 
             // send it
             match send_message(&mut conn, req) {
@@ -108,7 +121,7 @@ fn main() {
 
             // handle the response
             match resp {
-                Message::Error(e) => println!("Push {} failed with error: {}", hashed, e),
+                Message::Error{ code, detail } => println!("Push {} failed with error: {} {:?}", hashed, code, detail),
                 Message::PushResp{success: true} => {
                     // println!("Key {} pushed successfully.", hashed);
 
@@ -116,7 +129,7 @@ fn main() {
                     let return_time = SystemTime::now();
 
                     // add this datapoint to our vec
-                    keys_sent.push(KeyParam { key: hashed, duration: return_time.duration_since(to_send.timestamp).expect("duration error"), params: w.params.clone(), timestamp: to_send.timestamp })
+                    keys_sent.push(KeyParam { key: hashed, duration: return_time.duration_since(sent_at).expect("duration error"), params: w.params.clone(), timestamp: sent_at })
 
                 },
                 _ => println!("Execution should not have reached this point."),
@@ -128,4 +141,59 @@ fn main() {
     }
 
     println!("{} - Failure focused client finished.", id);
-} 
\ No newline at end of file
+}
+
+// same as the push loop in main, but batch_size values per request
+fn push_batched(conn: &mut TcpStream, w: Workload, batch_size: usize, keys_sent: &mut Vec<KeyParam>) {
+    let send_rate = w.params.client_send_rate;
+
+    for chunk in w.data.chunks(batch_size) {
+        // create data, all timed from when the batch goes out
+        let timestamp = SystemTime::now();
+        let pairs: Vec<KVPair> = chunk.iter().map(|data| {
+            let value = data.clone().into_bytes();
+
+            // make key for it
+            KVPair {key: hash_value(&value), value}
+        }).collect();
+        let keys: Vec<Key> = pairs.iter().map(|p| p.key).collect();
+
+        // send it
+        match send_message(conn, Message::PushBatchReq(pairs, None)) {
+            Ok(_) => (),
+            Err(_) => {
+                panic!("Failed to send batch message");
+            }
+        };
+
+        // wait on a response
+        let resp = match receive_message(conn) {
+            Ok(r) => r,
+            Err(e) => {
+                println!("Message receipt error! {}", e);
+                continue;
+            }
+        };
+
+        // handle the response
+        match resp {
+            Message::Error{ code, detail } => println!("Batch push failed with error: {} {:?}", code, detail),
+            Message::PushBatchResp(results) => {
+                let return_time = SystemTime::now();
+
+                for (key, result) in keys.iter().zip(results) {
+                    match result {
+                        PushResult::HashMismatch => println!("Push {} failed with a hash mismatch", key),
+                        PushResult::Collision => println!("Push {} failed with a key collision", key),
+                        PushResult::Deleted => println!("Push {} failed as the key was deleted", key),
+                        _ => keys_sent.push(KeyParam { key: *key, duration: return_time.duration_since(timestamp).expect("duration error"), params: w.params.clone(), timestamp })
+                    }
+                }
+            },
+            _ => println!("Execution should not have reached this point."),
+        }
+
+        // wait before sending next one, keeping the same per-value rate
+        thread::sleep(Duration::from_millis(((chunk.len() as f64/send_rate)*1000.0) as u64));
+    }
+}
@@ -1,7 +1,7 @@
 use std::{fs::{File, OpenOptions}, io::BufWriter, net::TcpStream, process::Command, time::{SystemTime, Duration}, thread};
 
 use bincode::serialize_into;
-use secko_messages::{Message, send_message, receive_message};
+use secko_messages::{Message, send_message, receive_message, connect};
 use secko_tests::{generate_batch, Param, Workload, spawn_server, spawn_client, Client};
 
 use serde::{Serialize, Deserialize};
@@ -251,7 +251,7 @@ pub fn test_failure() {
 
         // send a dump
         let server = "127.0.0.1:9000".to_string();
-        let mut conn = match TcpStream::connect(server) {
+        let mut conn = match connect(server) {
             Ok(c) => c,
             Err(_) => {
                 panic!("Failed to establish connection to server, line 104");
@@ -267,7 +267,7 @@ pub fn test_failure() {
 
         // send another dump
         let server = "127.0.0.1:9001".to_string();
-        let mut conn = match TcpStream::connect(server) {
+        let mut conn = match connect(server) {
             Ok(c) => c,
             Err(_) => {
                 panic!("Failed to establish connection to server, line 104");
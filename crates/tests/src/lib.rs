@@ -1,6 +1,5 @@
-use bincode::{serialize, deserialize};
 use rand::{Rng, distributions::Alphanumeric};
-use secko_messages::Message;
+use secko_messages::{Message, Key, RequestId, FrameHeader, encode_frame_tagged, decode_frame_tagged, HEADER_LEN, FLAG_HANDSHAKE, PROTOCOL_VERSION, MIN_PROTOCOL_VERSION, FrameError, DEFAULT_MAX_FRAME_SIZE};
 use serde::{Serialize, Deserialize};
 use std::{
     fmt::{self},
@@ -12,21 +11,7 @@ use chrono::DateTime; // https://stackoverflow.com/questions/45386585/how-to-for
 
 // to send a bunch of messages and handle responses later, not sequentially
 use tokio::net::TcpStream;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-
-// need struct for datapoint, used in workload struct and is what is stored on the server
-#[derive(Serialize, Deserialize, Debug)]
-pub struct DataPoint {
-    pub value: String,
-    pub timestamp: SystemTime, // time at which it was sent
-}
-
-impl fmt::Display for DataPoint {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        let datetime: DateTime<Utc> = self.timestamp.into();
-        write!(f, "DataPoint (sent at: {})", datetime.format("%d/%m/%Y %T.%f"))
-    }
-}
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 
 // need struct for just parameters
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -46,7 +31,7 @@ impl fmt::Display for Param {
 // need struct for key-parameter mapping, stores results of a datapoint on a client
 #[derive(Serialize, Deserialize, Debug)]
 pub struct KeyParam {
-    pub key: u64,
+    pub key: Key,
     pub duration: Duration, // how long it took to hear back
     pub params: Param, 
     pub timestamp: SystemTime // when it was sent
@@ -78,7 +63,7 @@ pub struct Client {
 // need struct for workload
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Workload {
-    pub data: Vec<String>, // the value is a string. wrapped with a time in DataPoint for when we actually send it.
+    pub data: Vec<String>, // the value is a string, sent as is. the server records when each one arrives
     pub params: Param
 }
 
@@ -166,25 +151,21 @@ pub fn generate_batch(id: u16, parameters: &Param) -> Vec<String> {
     v
 }
 
-// async sending and receiving
-pub async fn async_send_message(stream: &mut TcpStream, msg: Message) -> Result<(), String> {
-    // first, serialize the request
-    let data = match serialize(&msg) {
-        Ok(data) => data,
-        Err(e) => return Err(e.to_string()),
-    };
-
-    // then, get the length of that serialized data
-    let len: [u8; 8] = data.len().to_be_bytes(); // NOTE: works if usize is 64 bytes.
+// async sending and receiving. generic over the stream so they also work on the halves of a split TcpStream,
+// which is what lets one task keep writing requests while another reads the responses
+pub async fn async_send_message<S: AsyncWrite + Unpin>(stream: &mut S, msg: Message) -> Result<(), String> {
+    async_send_request(stream, None, msg).await
+}
 
-    // now, write the message's length...
-    let _: Result<(), String> = match stream.write(&len).await {
-        Ok(_) => Ok(()),
+pub async fn async_send_request<S: AsyncWrite + Unpin>(stream: &mut S, id: Option<RequestId>, msg: Message) -> Result<(), String> {
+    // first, serialize the request into a frame (same layout as secko_messages::send_message_tagged)
+    let frame = match encode_frame_tagged(id, &msg) {
+        Ok(frame) => frame,
         Err(e) => return Err(e.to_string()),
     };
-    
-    // ...followed by the message itself.
-    let _: Result<(), String> = match stream.write(&data).await {
+
+    // now, write the header and message in one go...
+    let _: Result<(), String> = match stream.write_all(&frame).await {
         Ok(_) => Ok(()),
         Err(e) => return Err(e.to_string()),
     };
@@ -199,30 +180,97 @@ pub async fn async_send_message(stream: &mut TcpStream, msg: Message) -> Result<
     
 }
 
-pub async fn async_receive_message(stream: &mut TcpStream) -> Result<Message, String> {
-    // first, get the size
-    // https://users.rust-lang.org/t/reading-length-payload-from-a-tcpstream/51211
-    let mut size_buffer = [0; 8];
-    let _: Result<(), String> = match stream.read(&mut size_buffer).await {
+pub async fn async_receive_message<S: AsyncRead + Unpin>(stream: &mut S) -> Result<Message, String> {
+    async_receive_response(stream).await.map(|(_, msg)| msg)
+}
+
+pub async fn async_receive_response<S: AsyncRead + Unpin>(stream: &mut S) -> Result<(Option<RequestId>, Message), String> {
+    // first, get the header
+    let mut header_buffer = [0; HEADER_LEN];
+    let _: Result<(), String> = match stream.read_exact(&mut header_buffer).await {
         Ok(_) => Ok(()),
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok((None, Message::ConnectionClosed)), // catch EOF
+        Err(e) => return Err(e.to_string()),
+    };
+    let header = match FrameHeader::decode(&header_buffer) {
+        Ok(h) => h,
         Err(e) => return Err(e.to_string()),
     };
-    let bincode_size: usize = usize::from_be_bytes(size_buffer);
 
-    if bincode_size == 0 { // catch EOF
-        return Ok(Message::ConnectionClosed);
+    // refuse to allocate for a length we'd never accept anyway
+    if header.len > DEFAULT_MAX_FRAME_SIZE {
+        return Err(FrameError::TooLarge{len: header.len, max: DEFAULT_MAX_FRAME_SIZE}.to_string());
     }
 
     // now, read in the request
-    let mut request = vec![0; bincode_size];
-    let _: Result<(), String> = match stream.read(&mut request).await {
+    let mut request = vec![0; header.len as usize];
+    let _: Result<(), String> = match stream.read_exact(&mut request).await {
         Ok(_) => Ok(()),
         Err(e) => return Err(e.to_string()),
     };
 
     // and return the deserialized result
-    match deserialize(&request) {
-        Ok(msg) => Ok(msg),
+    match decode_frame_tagged(&header, &request) {
+        Ok(tagged) => Ok(tagged),
         Err(e) => return Err(e.to_string()),
     }
-}
\ No newline at end of file
+}
+
+// sends every request without waiting on any responses, then matches the responses back up by id.
+// results come back in the same order as requests, whatever order the server answered in
+pub async fn async_pipeline(stream: TcpStream, requests: Vec<Message>) -> Result<Vec<Message>, String> {
+    let (mut reader, mut writer) = stream.into_split();
+    let count = requests.len();
+
+    // writing on its own task, so a server that answers while we're still sending can't back up into us
+    let sender = tokio::spawn(async move {
+        for (i, req) in requests.into_iter().enumerate() {
+            async_send_request(&mut writer, Some(i as RequestId), req).await?;
+        }
+        Ok::<_, String>(writer)
+    });
+
+    let mut responses: Vec<Option<Message>> = (0..count).map(|_| None).collect();
+    for _ in 0..count {
+        match async_receive_response(&mut reader).await? {
+            (_, Message::ConnectionClosed) => return Err("Remote closed unexpectedly.".to_string()),
+            (Some(id), msg) if (id as usize) < count && responses[id as usize].is_none() => responses[id as usize] = Some(msg),
+            (id, msg) => return Err(format!("Response with unexpected request id {:?}: {}", id, msg)),
+        }
+    }
+
+    match sender.await {
+        Ok(Ok(_)) => (),
+        Ok(Err(e)) => return Err(e),
+        Err(e) => return Err(e.to_string()),
+    };
+
+    Ok(responses.into_iter().map(|r| r.unwrap()).collect())
+}
+
+// async version of secko_messages::connect, offers our version range and checks what the server picked
+pub async fn async_connect(addr: &str) -> Result<TcpStream, String> {
+    let mut stream = match TcpStream::connect(addr).await {
+        Ok(s) => s,
+        Err(e) => return Err(e.to_string()),
+    };
+
+    let mut offer = Vec::new();
+    offer.extend_from_slice(&FrameHeader { version: PROTOCOL_VERSION, flags: FLAG_HANDSHAKE, len: 4 }.encode());
+    offer.extend_from_slice(&MIN_PROTOCOL_VERSION.to_be_bytes());
+    offer.extend_from_slice(&PROTOCOL_VERSION.to_be_bytes());
+    if let Err(e) = stream.write_all(&offer).await {
+        return Err(e.to_string());
+    }
+
+    let mut answer = [0; HEADER_LEN + 6];
+    if let Err(e) = stream.read_exact(&mut answer).await {
+        return Err(e.to_string());
+    }
+    let chosen = u16::from_be_bytes([answer[HEADER_LEN], answer[HEADER_LEN + 1]]);
+    if chosen < MIN_PROTOCOL_VERSION || chosen > PROTOCOL_VERSION {
+        return Err(format!("server rejected protocol version range {}..={}", MIN_PROTOCOL_VERSION, PROTOCOL_VERSION));
+    }
+
+    Ok(stream)
+}
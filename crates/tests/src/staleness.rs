@@ -1,7 +1,7 @@
 use std::{fs::File, io::BufWriter, net::TcpStream, time::Duration, thread};
 
 use bincode::serialize_into;
-use secko_messages::{Message, receive_message, send_message};
+use secko_messages::{Message, receive_message, send_message, connect};
 use secko_tests::{generate_batch, Param, Workload, spawn_server, spawn_client};
 
 // need function for base staleness
@@ -90,7 +90,7 @@ pub fn test_generic(n: u16, ai_send_rate: f64, client_send_rate: f64) {
     // do until list of servers is empty
     let mut server_conns = Vec::new();
     for id in 0..n {
-        let conn = TcpStream::connect(format!("127.0.0.1:{}", 9000+id)).unwrap(); // declared out here so connection not dropped too quickly and we don't get err - connection reset by peer.
+        let conn = connect(format!("127.0.0.1:{}", 9000+id)).unwrap(); // declared out here so connection not dropped too quickly and we don't get err - connection reset by peer.
         server_conns.push(conn);
     }
 
@@ -226,7 +226,7 @@ pub fn test_client_rate() {
         // do until list of servers is empty
         let mut server_conns = Vec::new();
         for id in 0..n {
-            let conn = TcpStream::connect(format!("127.0.0.1:{}", 9000+id)).unwrap(); // declared out here so connection not dropped too quickly and we don't get err - connection reset by peer.
+            let conn = connect(format!("127.0.0.1:{}", 9000+id)).unwrap(); // declared out here so connection not dropped too quickly and we don't get err - connection reset by peer.
             server_conns.push(conn);
         }
 
@@ -356,7 +356,7 @@ pub fn test_ai_rate() {
         // do until list of servers is empty
         let mut server_conns = Vec::new();
         for id in 0..n {
-            let conn = TcpStream::connect(format!("127.0.0.1:{}", 9000+id)).unwrap(); // declared out here so connection not dropped too quickly and we don't get err - connection reset by peer.
+            let conn = connect(format!("127.0.0.1:{}", 9000+id)).unwrap(); // declared out here so connection not dropped too quickly and we don't get err - connection reset by peer.
             server_conns.push(conn);
         }
 
@@ -464,7 +464,7 @@ pub fn test_elasticity() {
         // wait until full propagation (client will hang for us)
         let mut server_conns = Vec::new();
         for id in 0..ns[i-1] {
-            let conn = TcpStream::connect(format!("127.0.0.1:{}", 9000+id)).unwrap(); // declared out here so connection not dropped too quickly and we don't get err - connection reset by peer.
+            let conn = connect(format!("127.0.0.1:{}", 9000+id)).unwrap(); // declared out here so connection not dropped too quickly and we don't get err - connection reset by peer.
             server_conns.push(conn);
         }
 